@@ -0,0 +1,96 @@
+// Decides who may open a signaling connection. The server hands every
+// upgrade request to its AuthPolicy; deployments with their own scheme (API
+// keys, OAuth introspection) implement the trait instead of using the shared
+// token.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+// The parts of an upgrade request a policy gets to see
+#[derive(Debug, Clone, Default)]
+pub struct AuthRequest {
+    pub query: HashMap<String, String>,
+    pub authorization: Option<String>,
+}
+
+impl AuthRequest {
+    // A credential presented as a `token` query parameter or an
+    // `Authorization: Bearer` header, the query taking precedence
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.query.get("token").map(String::as_str).or_else(|| {
+            self.authorization
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+        })
+    }
+}
+
+// Who a connection belongs to; policies that can't tell users apart leave
+// it anonymous
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Identity {
+    pub user_id: Option<String>,
+    // Set by policies that vouch for the user acting as an operator; a
+    // connection can't claim that role on its own
+    pub operator: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthError(pub String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<Identity, AuthError>> + Send + 'a>>;
+
+pub trait AuthPolicy: Send + Sync {
+    fn authorize<'a>(&'a self, request: &'a AuthRequest) -> AuthFuture<'a>;
+}
+
+// Lets everyone in anonymously
+pub struct AllowAll;
+
+impl AuthPolicy for AllowAll {
+    fn authorize<'a>(&'a self, _request: &'a AuthRequest) -> AuthFuture<'a> {
+        Box::pin(async { Ok(Identity::default()) })
+    }
+}
+
+// Lets in anyone presenting the one shared secret, anonymously
+pub struct SharedToken {
+    token: String,
+}
+
+impl SharedToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        SharedToken {
+            token: token.into(),
+        }
+    }
+}
+
+impl AuthPolicy for SharedToken {
+    fn authorize<'a>(&'a self, request: &'a AuthRequest) -> AuthFuture<'a> {
+        Box::pin(async move {
+            match request.bearer_token() {
+                Some(presented) if tokens_match(presented, &self.token) => Ok(Identity::default()),
+                _ => Err(AuthError("missing or invalid token".to_owned())),
+            }
+        })
+    }
+}
+
+// Compares every byte so the time taken doesn't reveal how much matched
+pub fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,166 @@
+// Plays the audio and video demuxed from one file against a shared clock.
+// Each sample is due at the playback start plus its timestamp in the file,
+// so the tracks stay as far apart as the file has them whatever pace each
+// reader keeps. A track whose writes keep falling behind their due times by
+// more than the other's is reported once the gap passes SYNC_WINDOW.
+use std::time::{Duration, Instant};
+
+// Lip sync starts to show somewhere past this
+pub const SYNC_WINDOW: Duration = Duration::from_millis(80);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Track {
+    Audio,
+    Video,
+}
+
+impl Track {
+    fn index(self) -> usize {
+        match self {
+            Track::Audio => 0,
+            Track::Video => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Timeline {
+    start: Instant,
+    // Where the current pass through the file begins; grows on every loop
+    offset: Duration,
+    // Latest end of any sample seen in the current pass
+    end: Duration,
+}
+
+impl Timeline {
+    pub fn new(start: Instant) -> Self {
+        Timeline {
+            start,
+            offset: Duration::ZERO,
+            end: Duration::ZERO,
+        }
+    }
+
+    // When a sample stamped `pts` in the file should be written
+    pub fn due(&mut self, pts: Duration, duration: Duration) -> Instant {
+        self.end = self.end.max(pts + duration);
+        self.start + self.offset + pts
+    }
+
+    // Starts the next pass where the longer track of this one ended, so a
+    // looped file keeps its audio and video aligned
+    pub fn next_pass(&mut self) {
+        self.offset += self.end;
+        self.end = Duration::ZERO;
+    }
+
+    pub fn is_empty_pass(&self) -> bool {
+        self.end.is_zero()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyncChange {
+    // Audio lags video by a positive skew, leads it by a negative one
+    Lost { audio_behind_ms: i64 },
+    Regained,
+}
+
+#[derive(Debug, Default)]
+pub struct SkewMonitor {
+    // How late each track's latest sample was written
+    lateness: [Option<Duration>; 2],
+    // Audio lateness less video lateness, smoothed so that one slow write,
+    // which the other track soon catches up with, doesn't count as drift
+    skew_ms: f64,
+    out_of_sync: bool,
+}
+
+impl SkewMonitor {
+    // Records how long after its due time a sample went out, returning a
+    // change when the tracks drift apart or come back together. Sync only
+    // counts as regained well inside the window, so a skew hovering at its
+    // edge isn't reported over and over.
+    pub fn record(&mut self, track: Track, lateness: Duration) -> Option<SyncChange> {
+        self.lateness[track.index()] = Some(lateness);
+        let [Some(audio), Some(video)] = self.lateness else {
+            return None;
+        };
+        let skew = audio.as_secs_f64() * 1000.0 - video.as_secs_f64() * 1000.0;
+        self.skew_ms += (skew - self.skew_ms) / 8.0;
+        let window = SYNC_WINDOW.as_secs_f64() * 1000.0;
+        if !self.out_of_sync && self.skew_ms.abs() > window {
+            self.out_of_sync = true;
+            return Some(SyncChange::Lost {
+                audio_behind_ms: self.skew_ms.round() as i64,
+            });
+        }
+        if self.out_of_sync && self.skew_ms.abs() < window / 2.0 {
+            self.out_of_sync = false;
+            return Some(SyncChange::Regained);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(33);
+    const PACKET: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn looped_passes_start_where_the_longer_track_ended() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start);
+        assert!(timeline.is_empty_pass());
+        assert_eq!(timeline.due(Duration::ZERO, FRAME), start);
+        assert_eq!(
+            timeline.due(Duration::from_millis(980), PACKET),
+            start + Duration::from_millis(980)
+        );
+        timeline.due(Duration::from_millis(957), FRAME);
+        timeline.next_pass();
+        assert!(timeline.is_empty_pass());
+        // The audio ran to 1000 ms, past the last frame's 990
+        assert_eq!(
+            timeline.due(Duration::ZERO, PACKET),
+            start + Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn a_single_stall_is_not_drift() {
+        let mut monitor = SkewMonitor::default();
+        let mut changes = Vec::new();
+        for i in 0..100 {
+            let video = if i == 50 {
+                Duration::from_millis(150)
+            } else {
+                Duration::ZERO
+            };
+            changes.extend(monitor.record(Track::Video, video));
+            changes.extend(monitor.record(Track::Audio, Duration::ZERO));
+        }
+        assert!(changes.is_empty(), "{:?}", changes);
+    }
+
+    #[test]
+    fn a_sustained_lag_is_reported_once_and_so_is_the_recovery() {
+        let mut monitor = SkewMonitor::default();
+        let mut changes = Vec::new();
+        for lag in [200, 0] {
+            for _ in 0..100 {
+                changes.extend(monitor.record(Track::Video, Duration::ZERO));
+                changes.extend(monitor.record(Track::Audio, Duration::from_millis(lag)));
+            }
+        }
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(matches!(
+            changes[0],
+            SyncChange::Lost { audio_behind_ms } if audio_behind_ms > SYNC_WINDOW.as_millis() as i64
+        ));
+        assert_eq!(changes[1], SyncChange::Regained);
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use webrtc_streaming::config;
+use webrtc_streaming::transcript::{read_transcript, Direction, TranscriptEntry};
+
+// Drives a recorded transcript's inbound messages back through the signaling
+// server and reports what the server sent in return
+#[tokio::main]
+async fn main() -> Result<()> {
+    let path: PathBuf = config::flag_value("transcript")
+        .ok_or_else(|| anyhow::anyhow!("usage: replay --transcript <file.jsonl> [--url <ws url>] [--speed <factor>] [--drain-ms <ms>] [--check]"))?
+        .into();
+    let url =
+        config::flag_value("url").unwrap_or_else(|| "ws://localhost:3030/signaling".to_owned());
+    // 1.0 keeps the original pacing, 0 sends everything back to back
+    let speed: f64 = config::parse_setting("speed", "RUSTWEBRTC_REPLAY_SPEED", 1.0);
+    let drain = Duration::from_millis(config::parse_setting(
+        "drain-ms",
+        "RUSTWEBRTC_REPLAY_DRAIN_MS",
+        1000,
+    ));
+
+    let entries = read_transcript(&path)?;
+    let expected = expected_responses(&entries);
+    let received = replay(&url, &entries, speed, drain).await?;
+
+    println!(
+        "Replayed {:?}: received {} messages, transcript recorded {}",
+        path,
+        received.len(),
+        expected.len()
+    );
+    if config::has_flag("check") {
+        let matched = matching_responses(&received, &expected);
+        if matched != expected.len() || received.len() != expected.len() {
+            eprintln!(
+                "Responses diverged from the transcript after {} matching messages",
+                matched
+            );
+            std::process::exit(1);
+        }
+        println!("All responses matched the transcript");
+    }
+
+    Ok(())
+}
+
+// What the server sent in the recorded session, minus the messages that
+// name connections
+fn expected_responses(entries: &[TranscriptEntry]) -> Vec<serde_json::Value> {
+    entries
+        .iter()
+        .filter(|entry| entry.direction == Direction::Out)
+        .map(|entry| entry.message.clone())
+        .filter(|message| !names_connections(message))
+        .collect()
+}
+
+// Sends the transcript's inbound messages to `url`, paced by `speed`, and
+// collects the server's responses until `drain` after the last one
+async fn replay(
+    url: &str,
+    entries: &[TranscriptEntry],
+    speed: f64,
+    drain: Duration,
+) -> Result<Vec<serde_json::Value>> {
+    let (ws_stream, _) = connect_async(url).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    let receiver = tokio::spawn(async move {
+        let mut received = Vec::new();
+        while let Some(Ok(msg)) = read.next().await {
+            if let Message::Text(text) = msg {
+                println!("Received: {}", text);
+                match serde_json::from_str::<serde_json::Value>(&text) {
+                    Ok(value) if names_connections(&value) => {}
+                    Ok(value) => received.push(value),
+                    Err(e) => eprintln!("Server sent invalid JSON: {}", e),
+                }
+            }
+        }
+        received
+    });
+
+    let started = tokio::time::Instant::now();
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.direction == Direction::In)
+    {
+        if speed > 0.0 {
+            let due = Duration::from_secs_f64(entry.at_ms as f64 / 1000.0 / speed);
+            tokio::time::sleep_until(started + due).await;
+        }
+        let text = serde_json::to_string(&entry.message)?;
+        println!("Sending: {}", text);
+        write.send(Message::Text(text)).await?;
+    }
+
+    tokio::time::sleep(drain).await;
+    write.close().await?;
+    Ok(receiver.await?)
+}
+
+// How many responses match the transcript before the first difference
+fn matching_responses(received: &[serde_json::Value], expected: &[serde_json::Value]) -> usize {
+    received
+        .iter()
+        .zip(expected.iter())
+        .take_while(|(got, want)| got == want)
+        .count()
+}
+
+// Welcome and peerjoined carry freshly generated connection ids, so they can
+// never match
+fn names_connections(message: &serde_json::Value) -> bool {
+    matches!(
+        message.get("type").and_then(|t| t.as_str()),
+        Some("welcome" | "peerjoined")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::Path;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use webrtc_streaming::transcript::TranscriptWriter;
+
+    // A stand-in signaling server: greets each connection with a fresh id,
+    // answers offers and acknowledges candidates, recording a transcript per
+    // connection the way the real server does
+    async fn recording_server(dir: &Path) -> (String, mpsc::UnboundedReceiver<PathBuf>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (done, transcripts) = mpsc::unbounded_channel();
+        let dir = dir.to_owned();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (dir, done) = (dir.clone(), done.clone());
+                tokio::spawn(async move {
+                    let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let (mut write, mut read) = ws.split();
+                    let id = uuid::Uuid::new_v4().to_string();
+                    let transcript = TranscriptWriter::create(&dir, &id).await.unwrap();
+                    let mut reply = vec![json!({"type": "welcome", "id": id})];
+                    while let Some(Ok(Message::Text(text))) = read.next().await {
+                        let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        transcript.record(Direction::In, &message).await;
+                        match message["type"].as_str() {
+                            Some("offer") => reply.push(
+                                json!({"type": "answer", "sdp": format!("answer to {}", message["sdp"])}),
+                            ),
+                            Some("candidate") => reply.push(
+                                json!({"type": "ack", "candidate": message["candidate"]}),
+                            ),
+                            _ => {}
+                        }
+                        for message in reply.drain(..) {
+                            transcript.record(Direction::Out, &message).await;
+                            write
+                                .send(Message::Text(message.to_string()))
+                                .await
+                                .unwrap();
+                        }
+                    }
+                    let _ = done.send(transcript.path().to_owned());
+                });
+            }
+        });
+        (url, transcripts)
+    }
+
+    #[tokio::test]
+    async fn replaying_a_transcript_reproduces_the_responses() {
+        let dir = std::env::temp_dir().join(format!("replay-{}", uuid::Uuid::new_v4()));
+        let (url, mut transcripts) = recording_server(&dir).await;
+
+        // The original session: an offer and a candidate
+        let (ws, _) = connect_async(url.as_str()).await.unwrap();
+        let (mut write, mut read) = ws.split();
+        for message in [
+            json!({"type": "offer", "sdp": "v=0"}),
+            json!({"type": "candidate", "candidate": "candidate:1 1 udp 1 127.0.0.1 5000 typ host"}),
+        ] {
+            write
+                .send(Message::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+        for _ in 0..3 {
+            read.next().await.unwrap().unwrap();
+        }
+        write.close().await.unwrap();
+        let recorded = read_transcript(&transcripts.recv().await.unwrap()).unwrap();
+
+        let expected = expected_responses(&recorded);
+        assert_eq!(expected.len(), 2);
+        let received = replay(&url, &recorded, 0.0, Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(received, expected);
+        assert_eq!(matching_responses(&received, &expected), expected.len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,86 +3,1475 @@ use base64::Engine;
 use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::sync::Mutex; // Use tokio's async Mutex
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
+use webrtc_streaming::auth::{
+    self, AllowAll, AuthError, AuthPolicy, AuthRequest, Identity, SharedToken,
+};
+use webrtc_streaming::brain::queue::{
+    Queue, QueueMetrics, QueueState, SessionPolicy, MAX_IDLE_TIME, MAX_SESSION_DURATION,
+};
+use webrtc_streaming::features::{Feature, FeatureSet};
+use webrtc_streaming::governor::BandwidthGovernor;
+use webrtc_streaming::oneway::{self, FlowMonitor};
+use webrtc_streaming::ratelimit::{MessageLimiter, Verdict, SUSTAINED_ABUSE};
+use webrtc_streaming::scheduler::PriorityGate;
+use webrtc_streaming::signaling::{self, CandidateInit};
+use webrtc_streaming::storage::{ImageStore, LocalFsStore};
+use webrtc_streaming::transcript::{Direction, TranscriptWriter};
+use webrtc_streaming::webhook::{Webhook, WebhookEvent, WebhookPayload};
+use webrtc_streaming::{codecs, config, sdp, shutdown, stun, tls};
 
-type Peers = Arc<Mutex<HashMap<String, Arc<Mutex<SplitSink<WebSocket, Message>>>>>>;
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_OPUS, MIME_TYPE_VP8},
+        APIBuilder,
+    },
+    interceptor::registry::Registry,
+    peer_connection::configuration::RTCConfiguration,
+    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType},
+    track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:3030";
+const REAPER_INTERVAL: Duration = Duration::from_secs(10);
+const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+const CAPTURE_NAME_ATTEMPTS: usize = 5;
+const DEFAULT_CAPTURE_DIR: &str = "captures";
+// Longest user id or room kept in a capture filename
+const MAX_SOURCE_ID_CHARS: usize = 32;
+// Largest offer or answer, once expanded, the server will read
+const MAX_SDP_BYTES: usize = 64 * 1024;
+// How long clients get to receive `bye` and close before the process exits
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+// An offer left unanswered this long no longer blocks competing offers
+const GLARE_WINDOW: Duration = Duration::from_secs(10);
+// How long users restored from the queue file have to reconnect before they
+// lose their place
+const RESUME_GRACE: Duration = Duration::from_secs(60);
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+// Per-client signaling message rate, set with --max-messages-per-sec; 0 lifts it
+const DEFAULT_MAX_MESSAGES_PER_SEC: u32 = 50;
+// Several stats reports long, so one late report doesn't count as silence
+const DEFAULT_ONE_WAY_AFTER: Duration = Duration::from_secs(15);
+// Features the server can adapt forwarded messages for
+const SERVER_FEATURES: [Feature; 4] = Feature::ALL;
+// Each offer preview builds a whole peer connection, so the server as a
+// whole makes only a few a second
+const OFFER_PREVIEWS_PER_SEC: u32 = 2;
+const OFFER_PREVIEW_BURST: u32 = 5;
+
+static CAPTURE_SEQ: AtomicU64 = AtomicU64::new(0);
+// Set once shutdown starts, so the connections it closes keep their places
+// in the saved queue
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+// One-way media warnings sent, by the direction that went silent
+static ONE_WAY_INBOUND: AtomicU64 = AtomicU64::new(0);
+static ONE_WAY_OUTBOUND: AtomicU64 = AtomicU64::new(0);
+
+type Outbox = mpsc::UnboundedSender<Message>;
+// Lock order: `peers` may be held while taking `rooms`, never the reverse
+type Peers = Arc<Mutex<HashMap<String, Peer>>>;
+type Rooms = Arc<Mutex<RoomMap>>;
+type SharedQueue = Arc<Mutex<Queue>>;
+
+#[derive(Clone)]
+struct ServerState {
+    config: Arc<ServerConfig>,
+    peers: Peers,
+    rooms: Rooms,
+    queue: SharedQueue,
+    gate: Arc<PriorityGate>,
+    webhook: Option<Arc<Webhook>>,
+    // Learned from a STUN server at startup when asked to
+    public_address: Option<SocketAddr>,
+    dispatch: Arc<Dispatch>,
+    governor: Option<Arc<Mutex<BandwidthGovernor>>>,
+    // Decides who may open a signaling connection and who they are
+    auth: Arc<dyn AuthPolicy>,
+    // Where captured images and their sidecars are kept
+    images: Arc<dyn ImageStore>,
+}
+
+// Reads the client's choices from the upgrade query string. Only the
+// operator role needs the auth policy's say-so; claiming it without that is
+// refused rather than quietly downgraded.
+fn connection_options(
+    identity: Identity,
+    query: &HashMap<String, String>,
+    max_priority: u8,
+) -> Result<ConnectionOptions, &'static str> {
+    let role = query
+        .get("role")
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(Role::Publisher);
+    if role == Role::Operator && !identity.operator {
+        return Err("the operator role was not granted");
+    }
+    Ok(ConnectionOptions {
+        priority: query
+            .get("priority")
+            .and_then(|p| p.parse::<u8>().ok())
+            .unwrap_or(0)
+            .min(max_priority),
+        ticker: query.get("mode").map(String::as_str) == Some("ticker"),
+        role,
+        // A user the policy identified can't claim another name
+        label: identity
+            .user_id
+            .clone()
+            .or_else(|| query.get("label").cloned()),
+        user_id: identity.user_id,
+        resume: query.get("resume").cloned(),
+    })
+}
+
+// Per-connection choices made by the client through the upgrade query string
+struct ConnectionOptions {
+    priority: u8,
+    // Plain-text queue position updates instead of JSON
+    ticker: bool,
+    role: Role,
+    label: Option<String>,
+    // Who the auth policy says this is, unlike a label the client can pick
+    user_id: Option<String>,
+    // Token from an earlier Welcome, to reclaim that connection's place
+    resume: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    Publisher,
+    Viewer,
+    Operator,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "publisher" => Ok(Role::Publisher),
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            _ => Err(format!("unknown role {}", s)),
+        }
+    }
+}
+
+impl Role {
+    // Offers add tracks, so viewers may only answer; capture triggers are an
+    // operator action
+    fn may_send(self, message: &SignalingMessage) -> bool {
+        match message {
+            SignalingMessage::Offer { .. } => self != Role::Viewer,
+            SignalingMessage::TriggerImageCapture => self == Role::Operator,
+            _ => true,
+        }
+    }
+
+    // Operators only oversee the session, so they never wait for it
+    fn waits_in_queue(self) -> bool {
+        self != Role::Operator
+    }
+
+    // Capture triggers only make sense for connections with a camera
+    fn receives(self, message: &SignalingMessage) -> bool {
+        match message {
+            SignalingMessage::TriggerImageCapture => self == Role::Publisher,
+            _ => true,
+        }
+    }
+}
+
+struct Peer {
+    outbox: Outbox,
+    priority: u8,
+    role: Role,
+    label: Option<String>,
+    user_id: Option<String>,
+    last_seen: Instant,
+    connected_at: Instant,
+    room: Option<String>,
+    transcript: Option<Arc<TranscriptWriter>>,
+    // Assigned at room join when glare resolution is on: the first member is
+    // impolite, everyone joining after is polite
+    polite: bool,
+    // Last ICE connection state the client reported for its peer connection
+    ice_connected: bool,
+    last_offer: Option<Instant>,
+    // Read-only tap connections observing this peer's signaling
+    taps: Vec<(String, Outbox)>,
+    // What the client said it supports in its hello; everything until then
+    features: FeatureSet,
+}
+
+impl Peer {
+    fn new(
+        outbox: Outbox,
+        options: &ConnectionOptions,
+        transcript: Option<Arc<TranscriptWriter>>,
+    ) -> Self {
+        Peer {
+            outbox,
+            priority: options.priority,
+            role: options.role,
+            label: options.label.clone(),
+            user_id: options.user_id.clone(),
+            last_seen: Instant::now(),
+            connected_at: Instant::now(),
+            room: None,
+            polite: false,
+            transcript,
+            ice_connected: false,
+            last_offer: None,
+            taps: Vec::new(),
+            features: FeatureSet::default(),
+        }
+    }
+
+    // Copies a message this peer sent or received to every attached tap
+    fn mirror(&self, client_id: &str, direction: Direction, message: &SignalingMessage) {
+        if self.taps.is_empty() {
+            return;
+        }
+        let event = TapEvent {
+            connection: client_id,
+            direction,
+            message,
+        };
+        match serde_json::to_string(&event) {
+            Ok(json) => {
+                for (_, tap) in &self.taps {
+                    let _ = tap.send(Message::text(json.clone()));
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize tap event: {}", e),
+        }
+    }
+
+    fn touch(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    fn is_stale(&self, now: Instant, stale_after: Duration) -> bool {
+        now.saturating_duration_since(self.last_seen) > stale_after
+    }
+
+    fn has_outlived(&self, now: Instant, max_lifetime: Duration) -> bool {
+        now.saturating_duration_since(self.connected_at) > max_lifetime
+    }
+}
+
+struct RoomMap {
+    rooms: HashMap<String, HashSet<String>>,
+    max_rooms: usize,
+    // Per room, whose offer is waiting for an answer and since when
+    pending_offers: HashMap<String, (String, Instant)>,
+}
+
+#[derive(Debug, PartialEq)]
+enum JoinError {
+    TooManyRooms,
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::TooManyRooms => write!(f, "maximum number of rooms reached"),
+        }
+    }
+}
+
+impl RoomMap {
+    fn new(max_rooms: usize) -> Self {
+        RoomMap {
+            rooms: HashMap::new(),
+            max_rooms,
+            pending_offers: HashMap::new(),
+        }
+    }
+
+    // Joining an existing room always succeeds; only creating a new one is
+    // capped. Returns whether someone else was already in the room.
+    fn join(&mut self, room: &str, client_id: &str) -> Result<bool, JoinError> {
+        if !self.rooms.contains_key(room) && self.rooms.len() >= self.max_rooms {
+            return Err(JoinError::TooManyRooms);
+        }
+        let members = self.rooms.entry(room.to_owned()).or_default();
+        let occupied = members.iter().any(|id| id != client_id);
+        members.insert(client_id.to_owned());
+        Ok(occupied)
+    }
+
+    fn leave(&mut self, room: &str, client_id: &str) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(client_id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+        if self
+            .pending_offers
+            .get(room)
+            .is_some_and(|(offerer, _)| offerer == client_id)
+        {
+            self.pending_offers.remove(room);
+        }
+    }
+
+    // Perfect negotiation at the relay. While one member's offer awaits an
+    // answer, a polite member's competing offer loses and is refused; an
+    // impolite member's offer wins and the polite side is expected to roll
+    // back its own. Returns whether the offer may be forwarded.
+    fn admit_offer(&mut self, room: &str, client_id: &str, polite: bool, now: Instant) -> bool {
+        let glare = self
+            .pending_offers
+            .get(room)
+            .is_some_and(|(offerer, since)| {
+                offerer != client_id && now.saturating_duration_since(*since) < GLARE_WINDOW
+            });
+        if glare && polite {
+            return false;
+        }
+        self.pending_offers
+            .insert(room.to_owned(), (client_id.to_owned(), now));
+        true
+    }
+
+    fn answered(&mut self, room: &str) {
+        self.pending_offers.remove(room);
+    }
+
+    fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+}
+
+#[derive(Serialize)]
+struct TapEvent<'a> {
+    connection: &'a str,
+    direction: Direction,
+    message: &'a SignalingMessage,
+}
+
+#[derive(Serialize)]
+struct PeerInfo {
+    id: String,
+    priority: u8,
+    role: Role,
+    label: Option<String>,
+    last_seen_secs_ago: u64,
+    stale: bool,
+    features: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct ServerConfig {
+    listen: SocketAddr,
+    // Certificate and key for serving wss://
+    tls: Option<(PathBuf, PathBuf)>,
+    stale_after: Duration,
+    max_rooms: usize,
+    transcript_dir: Option<PathBuf>,
+    // Clients may ask for a send priority up to this value via `?priority=`
+    max_client_priority: u8,
+    send_concurrency: usize,
+    // Every connection joins the queue when it opens and can't negotiate
+    // until it holds the single active session; operators are not queued
+    queue_enabled: bool,
+    ticker_interval: Duration,
+    // Waiting users whose connection goes stale lose their place right away
+    // instead of holding it until the socket finally closes
+    queue_drop_stale: bool,
+    // Ticker clients hear about their place in line only when it moves
+    ticker_on_change: bool,
+    // Backend captures are stored with; "local" is the only one so far
+    storage: String,
+    // Where the local backend saves captures, created on first use
+    capture_dir: PathBuf,
+    // Write a JSON provenance file next to every saved capture
+    capture_sidecar: bool,
+    // Put the sender's user id and room in capture filenames
+    capture_source_names: bool,
+    // Ask every publisher for a capture this often, while a session is
+    // active when the queue is on
+    capture_interval: Option<Duration>,
+    // Taps are refused unless this token is configured and presented
+    tap_token: Option<String>,
+    // Shared secret signaling clients must present; unset leaves the
+    // endpoint open for local development
+    auth_token: Option<Arc<str>>,
+    // Offers closer together than this are refused to stop renegotiation storms
+    min_renegotiation_interval: Duration,
+    // Keep the active session alive only while its peer connection reports connected
+    ice_activity: bool,
+    // Send `prepareturn` to the next user this long before a session ends
+    handoff_lead: Duration,
+    // When the active session ends: after a total duration, after going
+    // idle, when its owner doesn't take it up in time, or once its owner
+    // has been disconnected for the reconnect grace
+    session_policy: SessionPolicy,
+    // The line is saved here on every change and restored on startup
+    queue_file: Option<PathBuf>,
+    // Resolve simultaneous offers within a room with polite/impolite roles
+    // instead of forwarding both
+    resolve_glare: bool,
+    // STUN server asked for our public address at startup, if any
+    public_address_stun: Option<String>,
+    // Renegotiations may not drop below this many active video/audio m-lines
+    min_video_tracks: usize,
+    min_audio_tracks: usize,
+    // Offers with more m-lines than this are refused before reaching a peer
+    max_tracks_per_offer: usize,
+    // Connections older than this are closed however active they are, as a
+    // backstop against stuck sessions; checked on every reaper pass
+    max_lifetime: Option<Duration>,
+    // Connections beyond this many waiting are turned away
+    max_waiting: Option<usize>,
+    // Ceiling on the media all reporting connections send in total, in bps
+    max_total_bitrate: Option<u64>,
+    // Signaling messages one client may send per second, with bursts of
+    // twice that; excess messages are dropped
+    max_messages_per_sec: Option<u32>,
+    // A connection whose reported media stalls in one direction for this long
+    // while the other keeps flowing is warned
+    one_way_after: Option<Duration>,
+}
+
+impl ServerConfig {
+    // Only a malformed listen address is fatal; other bad values fall back
+    // to their defaults
+    fn load() -> Result<Self, String> {
+        let listen = config::setting("listen", "RUSTWEBRTC_LISTEN")
+            .unwrap_or_else(|| DEFAULT_LISTEN.to_owned());
+        let listen = listen.parse().map_err(|_| {
+            format!(
+                "Invalid listen address '{}': expected host:port, e.g. 0.0.0.0:3030 or [::]:3030",
+                listen
+            )
+        })?;
+        let tls = match (
+            config::setting("tls-cert", "RUSTWEBRTC_TLS_CERT"),
+            config::setting("tls-key", "RUSTWEBRTC_TLS_KEY"),
+        ) {
+            (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together".to_owned()),
+        };
+        Ok(ServerConfig {
+            listen,
+            tls,
+            stale_after: Duration::from_secs(config::parse_setting(
+                "stale-after",
+                "RUSTWEBRTC_STALE_AFTER_SECS",
+                60,
+            )),
+            max_rooms: config::parse_setting("max-rooms", "RUSTWEBRTC_MAX_ROOMS", 100),
+            transcript_dir: config::enabled("record-transcripts", "RUSTWEBRTC_RECORD_TRANSCRIPTS")
+                .then(|| {
+                    config::setting("transcript-dir", "RUSTWEBRTC_TRANSCRIPT_DIR")
+                        .unwrap_or_else(|| "transcripts".to_owned())
+                        .into()
+                }),
+            max_client_priority: config::parse_setting(
+                "max-client-priority",
+                "RUSTWEBRTC_MAX_CLIENT_PRIORITY",
+                0,
+            ),
+            send_concurrency: config::parse_setting(
+                "send-concurrency",
+                "RUSTWEBRTC_SEND_CONCURRENCY",
+                64,
+            ),
+            queue_enabled: config::enabled("queue", "RUSTWEBRTC_QUEUE"),
+            ticker_interval: Duration::from_secs(config::parse_setting(
+                "ticker-interval-secs",
+                "RUSTWEBRTC_TICKER_INTERVAL_SECS",
+                5,
+            )),
+            queue_drop_stale: config::enabled("queue-drop-stale", "RUSTWEBRTC_QUEUE_DROP_STALE"),
+            ticker_on_change: config::enabled("ticker-on-change", "RUSTWEBRTC_TICKER_ON_CHANGE"),
+            tap_token: config::setting("tap-token", "RUSTWEBRTC_TAP_TOKEN"),
+            auth_token: config::setting("auth-token", "RUSTWEBRTC_AUTH_TOKEN").map(Into::into),
+            storage: config::setting("storage", "RUSTWEBRTC_STORAGE")
+                .unwrap_or_else(|| "local".to_owned()),
+            capture_dir: config::setting("capture-dir", "RUSTWEBRTC_CAPTURE_DIR")
+                .unwrap_or_else(|| DEFAULT_CAPTURE_DIR.to_owned())
+                .into(),
+            capture_sidecar: config::enabled("capture-sidecar", "RUSTWEBRTC_CAPTURE_SIDECAR"),
+            capture_interval: Some(config::parse_setting(
+                "capture-interval",
+                "RUSTWEBRTC_CAPTURE_INTERVAL",
+                0,
+            ))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+            capture_source_names: config::enabled(
+                "capture-source-names",
+                "RUSTWEBRTC_CAPTURE_SOURCE_NAMES",
+            ),
+            min_renegotiation_interval: Duration::from_millis(config::parse_setting(
+                "min-renegotiation-ms",
+                "RUSTWEBRTC_MIN_RENEGOTIATION_MS",
+                0,
+            )),
+            ice_activity: config::enabled("ice-activity", "RUSTWEBRTC_ICE_ACTIVITY"),
+            handoff_lead: Duration::from_secs(config::parse_setting(
+                "handoff-lead-secs",
+                "RUSTWEBRTC_HANDOFF_LEAD_SECS",
+                0,
+            )),
+            session_policy: SessionPolicy {
+                max_session: Duration::from_secs(config::parse_setting(
+                    "max-session-secs",
+                    "RUSTWEBRTC_MAX_SESSION_SECS",
+                    MAX_SESSION_DURATION.as_secs(),
+                )),
+                max_idle: Duration::from_secs(config::parse_setting(
+                    "max-idle-secs",
+                    "RUSTWEBRTC_MAX_IDLE_SECS",
+                    MAX_IDLE_TIME.as_secs(),
+                )),
+                accept_within: Some(config::parse_setting(
+                    "accept-within-secs",
+                    "RUSTWEBRTC_ACCEPT_WITHIN_SECS",
+                    0,
+                ))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+                reconnect_grace: Duration::from_secs(config::parse_setting(
+                    "reconnect-grace-secs",
+                    "RUSTWEBRTC_RECONNECT_GRACE_SECS",
+                    0,
+                )),
+            },
+            queue_file: config::setting("queue-file", "RUSTWEBRTC_QUEUE_FILE").map(Into::into),
+            resolve_glare: config::enabled("resolve-glare", "RUSTWEBRTC_RESOLVE_GLARE"),
+            public_address_stun: config::enabled(
+                "discover-public-address",
+                "RUSTWEBRTC_DISCOVER_PUBLIC_ADDRESS",
+            )
+            .then(|| {
+                config::setting("stun-server", "RUSTWEBRTC_STUN_SERVER")
+                    .unwrap_or_else(|| stun::DEFAULT_SERVER.to_owned())
+            }),
+            min_video_tracks: config::parse_setting(
+                "min-video-tracks",
+                "RUSTWEBRTC_MIN_VIDEO_TRACKS",
+                0,
+            ),
+            min_audio_tracks: config::parse_setting(
+                "min-audio-tracks",
+                "RUSTWEBRTC_MIN_AUDIO_TRACKS",
+                0,
+            ),
+            max_tracks_per_offer: config::parse_setting(
+                "max-tracks-per-offer",
+                "RUSTWEBRTC_MAX_TRACKS_PER_OFFER",
+                16,
+            ),
+            max_lifetime: Some(config::parse_setting(
+                "max-lifetime-secs",
+                "RUSTWEBRTC_MAX_LIFETIME_SECS",
+                0,
+            ))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+            max_waiting: Some(config::parse_setting(
+                "max-waiting",
+                "RUSTWEBRTC_MAX_WAITING",
+                0,
+            ))
+            .filter(|max| *max > 0),
+            max_total_bitrate: Some(config::parse_setting(
+                "max-total-bitrate",
+                "RUSTWEBRTC_MAX_TOTAL_BITRATE",
+                0,
+            ))
+            .filter(|bps| *bps > 0),
+            max_messages_per_sec: Some(config::parse_setting(
+                "max-messages-per-sec",
+                "RUSTWEBRTC_MAX_MESSAGES_PER_SEC",
+                DEFAULT_MAX_MESSAGES_PER_SEC,
+            ))
+            .filter(|rate| *rate > 0),
+            one_way_after: Some(config::parse_setting(
+                "one-way-after-secs",
+                "RUSTWEBRTC_ONE_WAY_AFTER_SECS",
+                DEFAULT_ONE_WAY_AFTER.as_secs(),
+            ))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        })
+    }
+}
+
+// The shared token when one is configured, otherwise the endpoint is open.
+// Deployments with their own scheme put their AuthPolicy here instead.
+fn load_auth_policy(config: &ServerConfig) -> Arc<dyn AuthPolicy> {
+    match &config.auth_token {
+        Some(token) => Arc::new(SharedToken::new(token.as_ref())),
+        None => Arc::new(AllowAll),
+    }
+}
+
+// Further backends, such as object storage, are added here
+fn load_image_store(config: &ServerConfig) -> Result<Arc<dyn ImageStore>, String> {
+    match config.storage.as_str() {
+        "local" => Ok(Arc::new(LocalFsStore::new(&config.capture_dir))),
+        other => Err(format!(
+            "Unknown storage backend '{}': expected local",
+            other
+        )),
+    }
+}
+
+// Webhooks are off unless a URL is configured; every event fires by default
+fn load_webhook() -> Option<Webhook> {
+    let url = config::setting("webhook-url", "RUSTWEBRTC_WEBHOOK_URL")?;
+    let events: Vec<WebhookEvent> =
+        match config::setting("webhook-events", "RUSTWEBRTC_WEBHOOK_EVENTS") {
+            Some(list) => list
+                .split(',')
+                .filter_map(|event| match event.parse() {
+                    Ok(event) => Some(event),
+                    Err(e) => {
+                        eprintln!("Ignoring webhook event: {}", e);
+                        None
+                    }
+                })
+                .collect(),
+            None => WebhookEvent::ALL.to_vec(),
+        };
+    match Webhook::new(&url, events) {
+        Ok(webhook) => Some(webhook),
+        Err(e) => {
+            eprintln!("Webhooks disabled: {}", e);
+            None
+        }
+    }
+}
+
+// The connection label doubles as the user id external systems see
+fn webhook_payload(
+    event: WebhookEvent,
+    client_id: &str,
+    label: &Option<String>,
+    room: Option<String>,
+) -> WebhookPayload {
+    WebhookPayload {
+        event,
+        connection_id: client_id.to_owned(),
+        user_id: label.clone(),
+        room,
+        at_ms: unix_millis(),
+    }
+}
+
+#[derive(Deserialize)]
+struct OfferPreviewRequest {
+    #[serde(default = "default_true")]
+    video: bool,
+    #[serde(default)]
+    audio: bool,
+    // Empty means the full default codec set
+    #[serde(default)]
+    codecs: Vec<String>,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 enum SignalingMessage {
+    // Offers, answers and candidates must name their recipient's id in
+    // `target`; the server fills in `from` with the sender's so the
+    // recipient can reply. One without a target is dropped.
+    // A compressed SDP is passed through untouched for the recipient to expand
     Offer {
         sdp: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
     },
     Answer {
         sdp: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
     },
     Candidate {
         candidate: String,
+        // Named as in the browser's RTCIceCandidateInit; the snake_case
+        // spelling older clients sent is still accepted
+        #[serde(rename = "sdpMid", alias = "sdp_mid")]
         sdp_mid: Option<String>,
+        #[serde(rename = "sdpMLineIndex", alias = "sdp_mline_index")]
         sdp_mline_index: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+    },
+    Candidates {
+        list: Vec<CandidateInit>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+    },
+    // Sent on connect, and again on joining a room, so the client knows its
+    // own id and whom it can target
+    Welcome {
+        your_id: String,
+        peers: Vec<String>,
+        // Present on connect when the client holds a queue place it can
+        // reclaim by reconnecting with `?resume=<token>`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    // Someone the client can now target arrived in its room or, outside
+    // rooms, connected
+    PeerJoined {
+        id: String,
+    },
+    // The features a client supports, normally its first message; the
+    // server answers with the ones it will honour for the connection
+    Hello {
+        features: Vec<String>,
     },
     Image {
         data: String, // Add image data field
+        // Echoes the trigger that asked for this capture, when there was one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
     },
     TriggerImageCapture,
+    Join {
+        room: String,
+    },
+    JoinRejected {
+        room: String,
+        reason: String,
+    },
+    BitrateRequest {
+        max_bitrate: u64,
+    },
+    // Viewer-requested encoder settings, honoured by a capturing streamer
+    StreamRequest {
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+        #[serde(default)]
+        fps: Option<u32>,
+        #[serde(default)]
+        bitrate: Option<u64>,
+    },
+    IceState {
+        state: String,
+    },
+    // A publisher's running counts of media bytes, for the bandwidth governor
+    // and one-way media detection
+    Stats {
+        bytes_sent: u64,
+        #[serde(default)]
+        bytes_received: u64,
+    },
+    // Media in this direction, from the client's side, has stopped while the
+    // other direction still flows
+    MediaWarning {
+        direction: oneway::Direction,
+    },
+    // Caps what a publisher sends while the server is near its total
+    // ceiling; no bitrate lifts the cap
+    BitrateCap {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bitrate: Option<u64>,
+    },
+    // A queued client's place in line, sent whenever it changes; position 0
+    // with no wait means the client holds the active session
+    QueueUpdate {
+        position: usize,
+        estimated_wait_secs: u64,
+    },
+    // Warns the next user in line that the active session ends soon, so they
+    // can set up their peer connection before being promoted
+    PrepareTurn {
+        starts_in_secs: u64,
+    },
+    // Codecs the answer selected, sent to both sides once it has been forwarded
+    Negotiated {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        video_codec: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio_codec: Option<String>,
+    },
+    // Confirms a join when glare resolution is on, with the client's
+    // perfect-negotiation role in the room
+    Joined {
+        room: String,
+        polite: bool,
+    },
+    // Tells viewers the room's publisher reconnected and they should re-offer
+    PublisherReplaced {
+        room: String,
+    },
+    Error {
+        code: String,
+        detail: String,
+    },
+    // The server is shutting down; clients should close their peer connections
+    Bye,
+}
+
+impl SignalingMessage {
+    // The `type` tag the message is sent with
+    fn kind(&self) -> &'static str {
+        match self {
+            SignalingMessage::Offer { .. } => "offer",
+            SignalingMessage::Answer { .. } => "answer",
+            SignalingMessage::Candidate { .. } => "candidate",
+            SignalingMessage::Candidates { .. } => "candidates",
+            SignalingMessage::Welcome { .. } => "welcome",
+            SignalingMessage::PeerJoined { .. } => "peerjoined",
+            SignalingMessage::Hello { .. } => "hello",
+            SignalingMessage::Image { .. } => "image",
+            SignalingMessage::TriggerImageCapture => "triggerimagecapture",
+            SignalingMessage::Join { .. } => "join",
+            SignalingMessage::JoinRejected { .. } => "joinrejected",
+            SignalingMessage::BitrateRequest { .. } => "bitraterequest",
+            SignalingMessage::StreamRequest { .. } => "streamrequest",
+            SignalingMessage::IceState { .. } => "icestate",
+            SignalingMessage::Stats { .. } => "stats",
+            SignalingMessage::MediaWarning { .. } => "mediawarning",
+            SignalingMessage::BitrateCap { .. } => "bitratecap",
+            SignalingMessage::QueueUpdate { .. } => "queueupdate",
+            SignalingMessage::PrepareTurn { .. } => "prepareturn",
+            SignalingMessage::Negotiated { .. } => "negotiated",
+            SignalingMessage::Joined { .. } => "joined",
+            SignalingMessage::PublisherReplaced { .. } => "publisherreplaced",
+            SignalingMessage::Error { .. } => "error",
+            SignalingMessage::Bye => "bye",
+        }
+    }
+
+    fn target(&self) -> Option<&str> {
+        match self {
+            SignalingMessage::Offer { target, .. }
+            | SignalingMessage::Answer { target, .. }
+            | SignalingMessage::Candidate { target, .. }
+            | SignalingMessage::Candidates { target, .. } => target.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Negotiation is between two peers, so it is never broadcast
+    fn needs_target(&self) -> bool {
+        matches!(
+            self,
+            SignalingMessage::Offer { .. }
+                | SignalingMessage::Answer { .. }
+                | SignalingMessage::Candidate { .. }
+                | SignalingMessage::Candidates { .. }
+        )
+    }
+
+    fn set_sender(&mut self, sender_id: &str) {
+        match self {
+            SignalingMessage::Offer { from, .. }
+            | SignalingMessage::Answer { from, .. }
+            | SignalingMessage::Candidate { from, .. }
+            | SignalingMessage::Candidates { from, .. } => *from = Some(sender_id.to_owned()),
+            _ => {}
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let peers: Peers = Arc::new(Mutex::new(HashMap::new()));
+    let config = match ServerConfig::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let public_address = match &config.public_address_stun {
+        Some(server) => match stun::reflexive_address(server, STUN_TIMEOUT).await {
+            Ok(addr) => {
+                println!("Public address according to {}: {}", server, addr);
+                Some(addr)
+            }
+            Err(e) => {
+                eprintln!("Could not learn the public address from {}: {}", server, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let images = match load_image_store(&config) {
+        Ok(images) => images,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let state = ServerState {
+        gate: PriorityGate::new(config.send_concurrency),
+        peers: Arc::new(Mutex::new(HashMap::new())),
+        rooms: Arc::new(Mutex::new(RoomMap::new(config.max_rooms))),
+        queue: Arc::new(Mutex::new(
+            load_queue(&config)
+                .with_handoff_lead(config.handoff_lead)
+                .with_max_waiting(config.max_waiting),
+        )),
+        webhook: load_webhook().map(Arc::new),
+        public_address,
+        dispatch: Arc::new(Dispatch::standard()),
+        auth: load_auth_policy(&config),
+        images,
+        governor: config
+            .max_total_bitrate
+            .map(|bps| Arc::new(Mutex::new(BandwidthGovernor::new(bps)))),
+        config,
+    };
+
+    tokio::spawn(reap_stale_peers(
+        state.peers.clone(),
+        (state.config.queue_enabled && state.config.queue_drop_stale).then(|| state.queue.clone()),
+        state.config.stale_after,
+        state.config.max_lifetime,
+        state.config.queue_file.clone(),
+    ));
+    if state.config.queue_enabled {
+        tokio::spawn(run_queue(
+            state.queue.clone(),
+            state.peers.clone(),
+            state.webhook.clone(),
+            state.config.ice_activity,
+            state.config.queue_file.clone(),
+        ));
+    }
+
+    if let Some(interval) = state.config.capture_interval {
+        tokio::spawn(run_capture_timer(state.clone(), interval));
+    }
 
     let signaling_route = warp::path("signaling")
+        .and(authorize(state.auth.clone()))
+        .and(warp::ws())
+        .and(with_state(state.clone()))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(
+            |identity: Identity,
+             ws: warp::ws::Ws,
+             state: ServerState,
+             query: HashMap<String, String>|
+             -> Box<dyn warp::Reply> {
+                match connection_options(identity, &query, state.config.max_client_priority) {
+                    Ok(options) => Box::new(
+                        ws.on_upgrade(move |socket| handle_connection(socket, state, options)),
+                    ),
+                    Err(e) => Box::new(warp::reply::with_status(
+                        e,
+                        warp::http::StatusCode::FORBIDDEN,
+                    )),
+                }
+            },
+        );
+
+    let tap_route = warp::path!("tap" / String)
         .and(warp::ws())
-        .and(with_peers(peers.clone()))
-        .map(|ws: warp::ws::Ws, peers| {
-            ws.on_upgrade(move |socket| handle_connection(socket, peers))
+        .and(with_state(state.clone()))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(
+            |target: String,
+             ws: warp::ws::Ws,
+             state: ServerState,
+             query: HashMap<String, String>|
+             -> Box<dyn warp::Reply> {
+                if !tap_authorized(state.config.tap_token.as_deref(), query.get("token")) {
+                    return Box::new(warp::reply::with_status(
+                        "tap token required",
+                        warp::http::StatusCode::UNAUTHORIZED,
+                    ));
+                }
+                Box::new(ws.on_upgrade(move |socket| handle_tap(socket, state, target)))
+            },
+        );
+
+    // Asks a connected client to capture a frame and send it back as an image
+    let capture_route = warp::path!("capture" / String)
+        .and(warp::post())
+        .and(authorize(state.auth.clone()))
+        .and(with_state(state.clone()))
+        .then(
+            |client_id: String, _identity: Identity, state: ServerState| async move {
+                let (status, body) = match request_capture(&client_id, &state.peers).await {
+                    Ok(()) => (
+                        warp::http::StatusCode::ACCEPTED,
+                        serde_json::json!({ "triggered": client_id }),
+                    ),
+                    Err((status, e)) => (status, serde_json::json!({ "error": e })),
+                };
+                warp::reply::with_status(warp::reply::json(&body), status)
+            },
+        );
+
+    let peers_route = warp::path("peers")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .then(|state: ServerState| async move {
+            warp::reply::json(&list_peers(&state.peers, state.config.stale_after).await)
         });
 
-    println!("Signaling server running on ws://127.0.0.1:3030/signaling");
-    warp::serve(signaling_route)
-        .run(([127, 0, 0, 1], 3030))
-        .await;
+    // Readiness/liveness probe on the signaling port
+    let health_route = warp::path("health")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .then(|state: ServerState| async move {
+            let peers = state.peers.lock().await.len();
+            let mut body = serde_json::json!({ "status": "ok", "peers": peers });
+            if let Some(addr) = state.public_address {
+                body["public_address"] = addr.to_string().into();
+            }
+            warp::reply::json(&body)
+        });
+
+    // Prometheus text format; each lock is held only long enough to copy a count
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .then(|state: ServerState| async move {
+            let peers = state.peers.lock().await.len();
+            let queue = state.queue.lock().await.metrics_snapshot();
+            warp::reply::with_header(
+                render_metrics(peers, queue),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        });
+
+    let preview_limiter = Arc::new(Mutex::new(MessageLimiter::new(
+        OFFER_PREVIEWS_PER_SEC,
+        OFFER_PREVIEW_BURST,
+        Instant::now(),
+    )));
+    let offer_preview_route = warp::path("offer-preview")
+        .and(warp::post())
+        .and(authorize(state.auth.clone()))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .then(move |_identity: Identity, request: OfferPreviewRequest| {
+            let limiter = Arc::clone(&preview_limiter);
+            async move { offer_preview(request, &limiter).await }
+        });
+
+    let listen = state.config.listen;
+    let server = warp::serve(
+        signaling_route
+            .or(tap_route)
+            .or(capture_route)
+            .or(peers_route)
+            .or(health_route)
+            .or(metrics_route)
+            .or(offer_preview_route)
+            .recover(unauthorized_reply),
+    );
+    let peers = state.peers.clone();
+    let shutdown = async move {
+        shutdown::signal().await;
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        println!("Shutting down, saying goodbye to connected clients");
+        say_goodbye(&peers).await;
+    };
+    match &state.config.tls {
+        Some((cert, key)) => {
+            let tls_config = match tls::load_config(cert, key) {
+                Ok(tls_config) => tls_config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            };
+            let listener = match tokio::net::TcpListener::bind(listen).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("{}", bind_error_message(listen, &e));
+                    std::process::exit(1);
+                }
+            };
+            let addr = listener.local_addr().unwrap_or(listen);
+            println!("Signaling server running on wss://{}/signaling", addr);
+            server
+                .serve_incoming_with_graceful_shutdown(
+                    tls::incoming(listener, tls_config),
+                    shutdown,
+                )
+                .await;
+        }
+        None => {
+            let (addr, serving) = match server.try_bind_with_graceful_shutdown(listen, shutdown) {
+                Ok(bound) => bound,
+                Err(e) => {
+                    eprintln!("{}", bind_error_message(listen, &e));
+                    std::process::exit(1);
+                }
+            };
+            println!("Signaling server running on ws://{}/signaling", addr);
+            serving.await;
+        }
+    }
+
+    // Connections remove themselves from the peer map once their socket closes
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while !state.peers.lock().await.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    println!("Signaling server stopped");
 }
 
-fn with_peers(
-    peers: Peers,
-) -> impl Filter<Extract = (Peers,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || peers.clone())
+// warp buries the cause a couple of errors deep, so the chain is searched
+// for the io error; the common ones get a message saying what to do about
+// them
+fn bind_error_message(listen: SocketAddr, error: &(dyn std::error::Error + 'static)) -> String {
+    let mut source = Some(error);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return match io.kind() {
+                std::io::ErrorKind::AddrInUse => format!(
+                    "Port {} already in use on {}; stop whatever holds it or pick \
+                     another with --listen",
+                    listen.port(),
+                    listen.ip()
+                ),
+                std::io::ErrorKind::AddrNotAvailable => format!(
+                    "Cannot listen on {}: no local interface has that address",
+                    listen
+                ),
+                std::io::ErrorKind::PermissionDenied => format!(
+                    "Not permitted to listen on {}; ports below 1024 usually need \
+                     elevated privileges",
+                    listen
+                ),
+                _ => format!("Cannot listen on {}: {}", listen, io),
+            };
+        }
+        source = cause.source();
+    }
+    format!("Cannot listen on {}: {}", listen, error)
+}
+
+fn render_metrics(peers: usize, queue: QueueMetrics) -> String {
+    let gauges = [
+        (
+            "rustwebrtc_connected_peers",
+            "Signaling connections currently open",
+            peers,
+        ),
+        (
+            "rustwebrtc_queue_waiting",
+            "Users waiting in line for the active session",
+            queue.waiting,
+        ),
+        (
+            "rustwebrtc_active_session",
+            "Whether a user holds the active session",
+            queue.active_session as usize,
+        ),
+    ];
+    let gauges = gauges.iter().map(|(name, help, value)| {
+        format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n")
+    });
+    let name = "rustwebrtc_one_way_media_warnings_total";
+    let one_way = [
+        ("inbound", ONE_WAY_INBOUND.load(Ordering::Relaxed)),
+        ("outbound", ONE_WAY_OUTBOUND.load(Ordering::Relaxed)),
+    ]
+    .iter()
+    .map(|(direction, count)| format!("{name}{{direction=\"{direction}\"}} {count}\n"))
+    .collect::<String>();
+    gauges
+        .chain(std::iter::once(format!(
+            "# HELP {name} Connections warned that media stopped in one direction\n# TYPE {name} counter\n{one_way}"
+        )))
+        .collect()
+}
+
+// A missing or unreadable file starts an empty line
+fn load_queue(config: &ServerConfig) -> Queue {
+    let fresh = || Queue::with_policy(config.session_policy);
+    let Some(path) = config.queue_file.as_deref().filter(|path| path.exists()) else {
+        return fresh();
+    };
+    match Queue::load_from_path(path, config.session_policy) {
+        Ok(queue) => {
+            println!("Restored the queue from {}", path.display());
+            queue
+        }
+        Err(e) => {
+            eprintln!("Ignoring queue file {}: {}", path.display(), e);
+            fresh()
+        }
+    }
+}
+
+// Best effort: a failed save only costs places in line after a restart
+fn persist_queue(queue: &Queue, path: Option<&Path>) {
+    if let Some(path) = path {
+        if let Err(e) = queue.save_to_path(path) {
+            eprintln!("Failed to save the queue to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Sends every client `bye` followed by a close frame, which also ends its
+// outbox writer
+async fn say_goodbye(peers: &Peers) {
+    let bye = match serde_json::to_string(&SignalingMessage::Bye) {
+        Ok(json) => Message::text(json),
+        Err(e) => {
+            eprintln!("Error serializing bye: {}", e);
+            return;
+        }
+    };
+    for peer in peers.lock().await.values() {
+        let _ = peer.outbox.send(bye.clone());
+        let _ = peer.outbox.send(Message::close());
+    }
+}
+
+fn with_state(
+    state: ServerState,
+) -> impl Filter<Extract = (ServerState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+#[derive(Debug)]
+struct Unauthorized(AuthError);
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Asks the policy about the upgrade request, passing on the identity it
+// returns and rejecting the request otherwise
+fn authorize(
+    policy: Arc<dyn AuthPolicy>,
+) -> impl Filter<Extract = (Identity,), Error = warp::Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>, authorization: Option<String>| {
+                let policy = Arc::clone(&policy);
+                async move {
+                    let request = AuthRequest {
+                        query,
+                        authorization,
+                    };
+                    policy
+                        .authorize(&request)
+                        .await
+                        .map_err(|e| warp::reject::custom(Unauthorized(e)))
+                }
+            },
+        )
 }
 
-async fn handle_connection(ws: WebSocket, peers: Peers) {
+async fn unauthorized_reply(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::WithStatus<String>, warp::Rejection> {
+    if let Some(Unauthorized(e)) = rejection.find::<Unauthorized>() {
+        Ok(warp::reply::with_status(
+            e.to_string(),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn handle_connection(ws: WebSocket, state: ServerState, options: ConnectionOptions) {
+    let ServerState {
+        config,
+        peers,
+        rooms,
+        queue,
+        gate,
+        webhook,
+        ..
+    } = state.clone();
     let (sender, mut receiver) = ws.split();
-    let sender = Arc::new(Mutex::new(sender));
+    let (outbox, outbox_rx) = mpsc::unbounded_channel();
+
+    // Only an id restored from the queue file, or an active session held for
+    // its owner to reconnect, can be resumed; live clients leave the waiting
+    // line when they disconnect
+    let can_resume =
+        config.queue_file.is_some() || !config.session_policy.reconnect_grace.is_zero();
+    let resumed = match options.resume.as_deref() {
+        Some(token) if can_resume => resumed_id(token, &peers, &queue).await,
+        _ => None,
+    };
+    let client_id = match resumed {
+        Some(id) => {
+            println!("Client {} resumed its place in the queue", id);
+            queue.lock().await.set_connected(&id, true);
+            id
+        }
+        None => Uuid::new_v4().to_string(),
+    };
+    tokio::spawn(write_outbox(
+        client_id.clone(),
+        sender,
+        outbox_rx,
+        gate,
+        options.priority,
+    ));
+    let queued = config.queue_enabled && options.role.waits_in_queue();
+    if queued {
+        let mut queue = queue.lock().await;
+        match queue.join_queue(&client_id, options.priority) {
+            Ok(true) => persist_queue(&queue, config.queue_file.as_deref()),
+            Ok(false) => {}
+            Err(e) => {
+                println!("Turned away client {}: {}", client_id, e);
+                send_error(&outbox, "queue_full", "the queue is full, try again later");
+                let _ = outbox.send(Message::close());
+                return;
+            }
+        }
+    }
+    let transcript = match &config.transcript_dir {
+        Some(dir) => match TranscriptWriter::create(dir, &client_id).await {
+            Ok(writer) => {
+                println!("Recording transcript to {:?}", writer.path());
+                Some(Arc::new(writer))
+            }
+            Err(e) => {
+                eprintln!("Failed to create transcript for {}: {}", client_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let ticker = (queued && options.ticker).then(|| {
+        tokio::spawn(run_position_ticker(
+            client_id.clone(),
+            outbox.clone(),
+            queue.clone(),
+            config.ticker_interval,
+            config.ticker_on_change,
+        ))
+    });
+    peers.lock().await.insert(
+        client_id.clone(),
+        Peer::new(outbox, &options, transcript.clone()),
+    );
+    // Ticker clients get their place as plain text instead
+    let queue_updates = (queued && !options.ticker).then(|| {
+        tokio::spawn(run_queue_updates(
+            client_id.clone(),
+            peers.clone(),
+            queue.clone(),
+        ))
+    });
+
+    let resume_token = if queued && can_resume {
+        queue
+            .lock()
+            .await
+            .resume_token(&client_id)
+            .map(str::to_owned)
+    } else {
+        None
+    };
+    greet(&client_id, None, resume_token, &peers).await;
 
-    let client_id = Uuid::new_v4().to_string();
-    peers.lock().await.insert(client_id.clone(), sender.clone());
+    if let Some(webhook) = &webhook {
+        webhook.fire(webhook_payload(
+            WebhookEvent::Connect,
+            &client_id,
+            &options.label,
+            None,
+        ));
+    }
 
     println!("Client {} connected", client_id);
 
+    let connection = Connection {
+        client_id: client_id.clone(),
+        options,
+        flow: config
+            .one_way_after
+            .map(|threshold| Mutex::new(FlowMonitor::new(threshold))),
+        state,
+        queued,
+    };
+    let mut limiter = config
+        .max_messages_per_sec
+        .map(|rate| MessageLimiter::new(rate, rate.saturating_mul(2), Instant::now()));
     while let Some(result) = receiver.next().await {
         match result {
             Ok(msg) => {
+                match limiter
+                    .as_mut()
+                    .map(|limiter| limiter.check(Instant::now()))
+                {
+                    Some(Verdict::Drop) => continue,
+                    Some(Verdict::Disconnect) => {
+                        println!(
+                            "Disconnecting client {}: over the message rate for {:?}",
+                            client_id, SUSTAINED_ABUSE
+                        );
+                        let message = SignalingMessage::Error {
+                            code: "rate_limited".to_owned(),
+                            detail: "too many messages, disconnecting".to_owned(),
+                        };
+                        send_to_peer(&client_id, &message, &peers).await;
+                        break;
+                    }
+                    _ => {}
+                }
+                note_activity(&client_id, &peers, queued.then_some(&queue)).await;
                 if let Ok(text) = msg.to_str() {
                     println!("Received message from {}: {}", client_id, text);
 
                     // Attempt to parse the message
                     let signaling_message: Result<SignalingMessage, _> = serde_json::from_str(text);
-                    match signaling_message {
-                        Ok(SignalingMessage::Image { data }) => {
-                            // Handle image message
-                            println!("Handling image message from client {}", client_id);
-                            handle_image_message(data).await;
+                    if let (Some(transcript), Ok(message)) = (&transcript, &signaling_message) {
+                        transcript.record(Direction::In, message).await;
+                    }
+                    if let Ok(message) = &signaling_message {
+                        if let Some(peer) = peers.lock().await.get(&client_id) {
+                            peer.mirror(&client_id, Direction::In, message);
                         }
+                    }
+                    match signaling_message {
                         Ok(message) => {
-                            // Handle other signaling messages
-                            println!("Parsed signaling message: {:?}", message);
-                            forward_message(&client_id, &message, &peers).await;
+                            let dispatch = &connection.state.dispatch;
+                            dispatch.dispatch(&connection, message).await;
                         }
                         Err(e) => {
                             eprintln!(
@@ -100,42 +1489,896 @@ async fn handle_connection(ws: WebSocket, peers: Peers) {
         }
     }
 
-    peers.lock().await.remove(&client_id);
+    if let Some(dropped) = limiter.map(|limiter| limiter.dropped()).filter(|n| *n > 0) {
+        println!(
+            "Dropped {} messages from client {} over the rate limit",
+            dropped, client_id
+        );
+    }
+    if let Some(ticker) = ticker {
+        ticker.abort();
+    }
+    if let Some(queue_updates) = queue_updates {
+        queue_updates.abort();
+    }
+    if queued && !SHUTTING_DOWN.load(Ordering::SeqCst) {
+        leave_on_disconnect(&client_id, &queue, &peers, webhook.as_ref(), &config).await;
+    }
+    if let Some(governor) = &connection.state.governor {
+        governor.lock().await.remove(&client_id);
+    }
+    let removed = peers.lock().await.remove(&client_id);
+    if let Some(peer) = removed {
+        if let Some(webhook) = &webhook {
+            webhook.fire(webhook_payload(
+                WebhookEvent::Disconnect,
+                &client_id,
+                &peer.label,
+                peer.room.clone(),
+            ));
+        }
+        if let Some(room) = peer.room {
+            rooms.lock().await.leave(&room, &client_id);
+        }
+    }
     println!("Client {} disconnected", client_id);
 }
 
-async fn handle_image_message(data: String) {
-    println!("Received image data of length: {}", data.len());
+// Any message counts as activity, both for stale-connection reaping and, for
+// the active user, for the session's idle and acceptance timers
+async fn note_activity(client_id: &str, peers: &Peers, queue: Option<&SharedQueue>) {
+    if let Some(peer) = peers.lock().await.get_mut(client_id) {
+        peer.touch();
+    }
+    if let Some(queue) = queue {
+        queue.lock().await.update_activity(client_id);
+    }
+}
 
-    let base64_data = data.split(',').nth(1).unwrap_or("");
-    println!("Base64 content length: {}", base64_data.len());
+// The id a `?resume=` token reclaims, as long as no live connection has it
+async fn resumed_id(token: &str, peers: &Peers, queue: &SharedQueue) -> Option<String> {
+    let id = queue.lock().await.resumable_user(token)?.to_owned();
+    if peers.lock().await.contains_key(&id) {
+        return None;
+    }
+    Some(id)
+}
 
-    match general_purpose::STANDARD.decode(base64_data) {
-        Ok(image_bytes) => {
-            println!(
-                "Decoded image data successfully. Bytes length: {}",
-                image_bytes.len()
-            );
+// The connection a message arrived on, as seen by its handler
+struct Connection {
+    client_id: String,
+    options: ConnectionOptions,
+    state: ServerState,
+    queued: bool,
+    // Watches the client's reported media for one-way flow, when enabled
+    flow: Option<Mutex<FlowMonitor>>,
+}
 
-            if let Err(e) = tokio::fs::write("captured_image.png", &image_bytes).await {
-                eprintln!("Failed to save image: {}", e);
-            } else {
-                println!("Image saved as captured_image.png");
-            }
+type HandlerFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+type Handler = for<'a> fn(&'a Connection, SignalingMessage) -> HandlerFuture<'a>;
+
+// Routes each parsed message to the handler registered for its type. Types
+// without one go to the fallback, which applies the role, queue and offer
+// checks and forwards whatever passes.
+struct Dispatch {
+    handlers: HashMap<&'static str, Handler>,
+    fallback: Handler,
+}
+
+impl Dispatch {
+    fn new(fallback: Handler) -> Self {
+        Dispatch {
+            handlers: HashMap::new(),
+            fallback,
         }
-        Err(e) => {
-            eprintln!("Failed to decode Base64 image data: {}", e);
+    }
+
+    // The server's own handlers; the messages they take skip the fallback's
+    // checks
+    fn standard() -> Self {
+        let mut dispatch = Dispatch::new(handle_relayed);
+        dispatch.register("hello", handle_hello);
+        dispatch.register("image", handle_image);
+        dispatch.register("icestate", handle_ice_state);
+        dispatch.register("join", handle_join_message);
+        dispatch.register("stats", handle_stats);
+        dispatch
+    }
+
+    fn register(&mut self, kind: &'static str, handler: Handler) {
+        if self.handlers.insert(kind, handler).is_some() {
+            eprintln!("Replaced the handler for {} messages", kind);
         }
     }
+
+    async fn dispatch(&self, connection: &Connection, message: SignalingMessage) {
+        let handler = self.handlers.get(message.kind()).unwrap_or(&self.fallback);
+        handler(connection, message).await;
+    }
 }
-//To take a picture
-async fn trigger_image_capture(
-    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let message = serde_json::to_string(&SignalingMessage::TriggerImageCapture)?;
-    let mut sender = sender.lock().await;
-    sender.send(Message::text(message)).await?;
-    println!("Sent image capture trigger to client.");
+
+fn handle_image(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let SignalingMessage::Image { data, nonce } = message else {
+            return;
+        };
+        println!(
+            "Handling image message from client {}",
+            connection.client_id
+        );
+        let config = &connection.state.config;
+        let user_id = connection.options.label.clone();
+        let room = match connection
+            .state
+            .peers
+            .lock()
+            .await
+            .get(&connection.client_id)
+        {
+            Some(peer) => peer.room.clone(),
+            None => None,
+        };
+        // Named after the connection unless source names are on and the
+        // sender gave something to name it after
+        let source = config
+            .capture_source_names
+            .then(|| capture_source(user_id.as_deref(), room.as_deref()))
+            .flatten()
+            .unwrap_or_else(|| connection.client_id.clone());
+        let sidecar = config.capture_sidecar.then(|| CaptureMetadata {
+            connection_id: connection.client_id.clone(),
+            user_id,
+            room,
+            captured_at_ms: unix_millis(),
+            width: None,
+            height: None,
+            nonce,
+        });
+        println!("Received image data of length: {}", data.len());
+        let image_bytes = match decode_image_data(&data) {
+            Ok(image_bytes) => image_bytes,
+            Err(e) => {
+                eprintln!("Rejected image from client {}: {}", connection.client_id, e);
+                let message = SignalingMessage::Error {
+                    code: "bad_image".to_owned(),
+                    detail: e,
+                };
+                send_to_peer(&connection.client_id, &message, &connection.state.peers).await;
+                return;
+            }
+        };
+        handle_image_message(
+            image_bytes,
+            connection.state.images.as_ref(),
+            &source,
+            sidecar,
+        )
+        .await;
+    })
+}
+
+// Records what the client supports and confirms the set the server will
+// honour. Unrecognised names are skipped, so newer clients still connect.
+fn handle_hello(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let SignalingMessage::Hello { features } = message else {
+            return;
+        };
+        let (features, unknown) = FeatureSet::parse(&features);
+        let features = features.intersection(SERVER_FEATURES.into_iter().collect());
+        if !unknown.is_empty() {
+            println!(
+                "Client {} advertised unknown features: {}",
+                connection.client_id,
+                unknown.join(", ")
+            );
+        }
+        match connection
+            .state
+            .peers
+            .lock()
+            .await
+            .get_mut(&connection.client_id)
+        {
+            Some(peer) => peer.features = features,
+            None => return,
+        }
+        println!(
+            "Client {} supports: {}",
+            connection.client_id,
+            features.names().join(", ")
+        );
+        let reply = SignalingMessage::Hello {
+            features: features.names(),
+        };
+        send_to_peer(&connection.client_id, &reply, &connection.state.peers).await;
+    })
+}
+
+fn handle_ice_state(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let SignalingMessage::IceState { state } = message else {
+            return;
+        };
+        if let Some(peer) = connection
+            .state
+            .peers
+            .lock()
+            .await
+            .get_mut(&connection.client_id)
+        {
+            peer.ice_connected = matches!(state.as_str(), "connected" | "completed");
+        }
+    })
+}
+
+fn handle_join_message(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let SignalingMessage::Join { room } = message else {
+            return;
+        };
+        let state = &connection.state;
+        handle_join(
+            &connection.client_id,
+            room,
+            &state.peers,
+            &state.rooms,
+            state.config.resolve_glare,
+        )
+        .await;
+    })
+}
+
+// Checks for one-way media, feeds the governor and tells every connection
+// whose cap moved. Stats are consumed here either way, never forwarded.
+fn handle_stats(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let SignalingMessage::Stats {
+            bytes_sent,
+            bytes_received,
+        } = message
+        else {
+            return;
+        };
+        if let Some(flow) = &connection.flow {
+            let silent = flow
+                .lock()
+                .await
+                .observe(bytes_sent, bytes_received, Instant::now());
+            if let Some(direction) = silent {
+                println!(
+                    "Client {}: {:?} media stopped while the other direction flows",
+                    connection.client_id, direction
+                );
+                match direction {
+                    oneway::Direction::Inbound => &ONE_WAY_INBOUND,
+                    oneway::Direction::Outbound => &ONE_WAY_OUTBOUND,
+                }
+                .fetch_add(1, Ordering::Relaxed);
+                let message = SignalingMessage::MediaWarning { direction };
+                send_to_peer(&connection.client_id, &message, &connection.state.peers).await;
+            }
+        }
+        let Some(governor) = &connection.state.governor else {
+            return;
+        };
+        let changes = {
+            let mut governor = governor.lock().await;
+            governor.record(&connection.client_id, bytes_sent, Instant::now());
+            governor.rebalance()
+        };
+        for (client_id, max_bitrate) in changes {
+            match max_bitrate {
+                Some(bps) => println!("Capping client {} at {} bps", client_id, bps),
+                None => println!("Lifting the bitrate cap on client {}", client_id),
+            }
+            let message = SignalingMessage::BitrateCap { max_bitrate };
+            send_to_peer(&client_id, &message, &connection.state.peers).await;
+        }
+    })
+}
+
+// Everything without a handler of its own: offers and answers pass the role,
+// queue, track and rate checks, then messages are forwarded
+fn handle_relayed(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+    Box::pin(async move {
+        let Connection {
+            client_id,
+            options,
+            state,
+            queued,
+            ..
+        } = connection;
+        let ServerState {
+            config,
+            peers,
+            rooms,
+            queue,
+            ..
+        } = state;
+        // The kind of track a renegotiating offer falls short on, if any
+        let stripped = match &message {
+            SignalingMessage::Offer {
+                sdp, compressed, ..
+            } => strips_tracks(client_id, sdp, *compressed, peers, config).await,
+            _ => None,
+        };
+        match message {
+            message if !options.role.may_send(&message) => {
+                eprintln!(
+                    "Rejected {:?} from client {} with role {:?}",
+                    message, client_id, options.role
+                );
+            }
+            SignalingMessage::Offer { .. } | SignalingMessage::Answer { .. }
+                if *queued && !queue.lock().await.is_active(client_id) =>
+            {
+                let state = queue.lock().await.position_of(client_id);
+                let message = SignalingMessage::Error {
+                    code: "not_active".to_owned(),
+                    detail: match state {
+                        Some(state) => format!(
+                            "waiting in queue at position {}, about {} s",
+                            state.position + 1,
+                            state.estimated_wait_secs
+                        ),
+                        None => "not in the queue".to_owned(),
+                    },
+                };
+                send_to_peer(client_id, &message, peers).await;
+            }
+            SignalingMessage::Offer {
+                ref sdp,
+                compressed,
+                ..
+            } if offer_media_count(sdp, compressed)
+                .is_none_or(|count| count > config.max_tracks_per_offer) =>
+            {
+                eprintln!(
+                    "Rejected offer from client {}: too many media sections",
+                    client_id
+                );
+                let message = SignalingMessage::Error {
+                    code: "too_many_tracks".to_owned(),
+                    detail: format!(
+                        "offers may carry at most {} media sections",
+                        config.max_tracks_per_offer
+                    ),
+                };
+                send_to_peer(client_id, &message, peers).await;
+            }
+            SignalingMessage::Offer { .. } if stripped.is_some() => {
+                if let Some(kind) = stripped {
+                    eprintln!(
+                        "Rejected renegotiation from client {}: too few active {} tracks",
+                        client_id, kind
+                    );
+                }
+                let message = SignalingMessage::Error {
+                    code: "renegotiation_removes_tracks".to_owned(),
+                    detail: format!(
+                        "renegotiations must keep at least {} video and {} audio tracks active",
+                        config.min_video_tracks, config.min_audio_tracks
+                    ),
+                };
+                send_to_peer(client_id, &message, peers).await;
+            }
+            SignalingMessage::Offer { .. }
+                if !accept_offer(client_id, peers, config.min_renegotiation_interval).await =>
+            {
+                eprintln!(
+                    "Rejected offer from client {}: renegotiating too often",
+                    client_id
+                );
+                let message = SignalingMessage::Error {
+                    code: "renegotiation_rate_limited".to_owned(),
+                    detail: format!(
+                        "wait at least {} ms between offers",
+                        config.min_renegotiation_interval.as_millis()
+                    ),
+                };
+                send_to_peer(client_id, &message, peers).await;
+            }
+            message
+                if config.resolve_glare && !pass_glare(client_id, &message, peers, rooms).await =>
+            {
+                eprintln!(
+                    "Dropped offer from polite client {}: another offer in its room is pending",
+                    client_id
+                );
+                let message = SignalingMessage::Error {
+                    code: "glare".to_owned(),
+                    detail: "another offer is pending in this room; answer it instead".to_owned(),
+                };
+                send_to_peer(client_id, &message, peers).await;
+            }
+            mut message => {
+                // Handle other signaling messages
+                println!("Parsed signaling message: {:?}", message);
+                message.set_sender(client_id);
+                forward_message(client_id, &message, peers).await;
+                if let SignalingMessage::Answer { .. } = message {
+                    announce_negotiated(client_id, &message, peers).await;
+                }
+            }
+        }
+    })
+}
+
+// Taps are refused outright unless a token is configured. The comparison
+// takes the same time however much of the token matched.
+fn tap_authorized(expected: Option<&str>, presented: Option<&String>) -> bool {
+    match (expected, presented) {
+        (Some(expected), Some(presented)) => auth::tokens_match(presented, expected),
+        _ => false,
+    }
+}
+
+// A tap only listens: it is attached to the target peer and every message it
+// sends is refused. It never enters the peer map, so nothing is forwarded to it.
+async fn handle_tap(ws: WebSocket, state: ServerState, target: String) {
+    let (sender, mut receiver) = ws.split();
+    let (outbox, outbox_rx) = mpsc::unbounded_channel();
+    let tap_id = Uuid::new_v4().to_string();
+    tokio::spawn(write_outbox(
+        tap_id.clone(),
+        sender,
+        outbox_rx,
+        state.gate.clone(),
+        0,
+    ));
+
+    let attached = match state.peers.lock().await.get_mut(&target) {
+        Some(peer) => {
+            peer.taps.push((tap_id.clone(), outbox.clone()));
+            true
+        }
+        None => false,
+    };
+    if !attached {
+        send_error(
+            &outbox,
+            "unknown_connection",
+            format!("no connection {}", target),
+        );
+        let _ = outbox.send(Message::close());
+        return;
+    }
+    println!("Tap {} attached to client {}", tap_id, target);
+
+    while let Some(result) = receiver.next().await {
+        match result {
+            Ok(msg) if msg.is_text() => {
+                send_error(&outbox, "read_only", "tap connections cannot send messages");
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    if let Some(peer) = state.peers.lock().await.get_mut(&target) {
+        peer.taps.retain(|(id, _)| *id != tap_id);
+    }
+    println!("Tap {} detached from client {}", tap_id, target);
+}
+
+fn send_error(outbox: &Outbox, code: &str, detail: impl Into<String>) {
+    let message = SignalingMessage::Error {
+        code: code.to_owned(),
+        detail: detail.into(),
+    };
+    match serde_json::to_string(&message) {
+        Ok(json) => {
+            let _ = outbox.send(Message::text(json));
+        }
+        Err(e) => eprintln!("Failed to serialize message: {}", e),
+    }
+}
+
+async fn handle_join(
+    client_id: &str,
+    room: String,
+    peers: &Peers,
+    rooms: &Rooms,
+    resolve_glare: bool,
+) {
+    // `rooms` is released before `peers` is taken, keeping the lock order
+    let joined = rooms.lock().await.join(&room, client_id);
+    let polite = match joined {
+        Ok(occupied) => occupied,
+        Err(e) => {
+            eprintln!("Client {} could not join room {}: {}", client_id, room, e);
+            let message = SignalingMessage::JoinRejected {
+                room,
+                reason: e.to_string(),
+            };
+            send_to_peer(client_id, &message, peers).await;
+            return;
+        }
+    };
+
+    let (previous, replaced, viewers) = {
+        let mut peers = peers.lock().await;
+        let Some(peer) = peers.get_mut(client_id) else {
+            return;
+        };
+        let previous = peer.room.replace(room.clone());
+        peer.polite = polite;
+        let role = peer.role;
+        let user_id = peer.user_id.clone();
+
+        // A reconnecting publisher takes over from the stale connection still
+        // holding the room, so viewers never see two publishers. Only the
+        // same authenticated user's connection is stale; a label is the
+        // client's own claim and would let anyone evict a publisher.
+        let mut replaced = Vec::new();
+        let mut viewers = Vec::new();
+        for (id, other) in peers.iter_mut() {
+            if id == client_id || other.room.as_deref() != Some(room.as_str()) {
+                continue;
+            }
+            match other.role {
+                Role::Publisher
+                    if role == Role::Publisher && user_id.is_some() && other.user_id == user_id =>
+                {
+                    other.room = None;
+                    let _ = other.outbox.send(Message::close());
+                    replaced.push(id.clone());
+                }
+                Role::Viewer => viewers.push(id.clone()),
+                _ => {}
+            }
+        }
+        (previous, replaced, viewers)
+    };
+    let room_count = {
+        let mut rooms = rooms.lock().await;
+        if let Some(previous) = previous.filter(|previous| *previous != room) {
+            rooms.leave(&previous, client_id);
+        }
+        for id in &replaced {
+            rooms.leave(&room, id);
+        }
+        rooms.room_count()
+    };
+    greet(client_id, Some(&room), None, peers).await;
+    for id in &replaced {
+        println!(
+            "Client {} replaced publisher {} in room {}",
+            client_id, id, room
+        );
+    }
+    if !replaced.is_empty() {
+        let message = SignalingMessage::PublisherReplaced { room: room.clone() };
+        for viewer in &viewers {
+            send_to_peer(viewer, &message, peers).await;
+        }
+    }
+    println!(
+        "Client {} joined room {} ({} rooms in use)",
+        client_id, room, room_count
+    );
+    if resolve_glare {
+        let message = SignalingMessage::Joined { room, polite };
+        send_to_peer(client_id, &message, peers).await;
+    }
+}
+
+// Tells a client arriving in a room, or outside rooms on connecting, who it
+// can target there, and tells them about it
+async fn greet(client_id: &str, room: Option<&str>, resume_token: Option<String>, peers: &Peers) {
+    let others: Vec<String> = peers
+        .lock()
+        .await
+        .iter()
+        .filter(|(id, peer)| *id != client_id && peer.room.as_deref() == room)
+        .map(|(id, _)| id.clone())
+        .collect();
+    let joined = SignalingMessage::PeerJoined {
+        id: client_id.to_owned(),
+    };
+    for other in &others {
+        send_to_peer(other, &joined, peers).await;
+    }
+    let welcome = SignalingMessage::Welcome {
+        your_id: client_id.to_owned(),
+        peers: others,
+        resume_token,
+    };
+    send_to_peer(client_id, &welcome, peers).await;
+}
+
+// Applies the room's glare rules to an offer or answer about to be forwarded;
+// false means a polite client's offer lost and must not be forwarded
+async fn pass_glare(
+    client_id: &str,
+    message: &SignalingMessage,
+    peers: &Peers,
+    rooms: &Rooms,
+) -> bool {
+    let Some((room, polite)) = peers
+        .lock()
+        .await
+        .get(client_id)
+        .and_then(|peer| Some((peer.room.clone()?, peer.polite)))
+    else {
+        return true;
+    };
+    let mut rooms = rooms.lock().await;
+    match message {
+        SignalingMessage::Offer { .. } => {
+            rooms.admit_offer(&room, client_id, polite, Instant::now())
+        }
+        SignalingMessage::Answer { .. } => {
+            rooms.answered(&room);
+            true
+        }
+        _ => true,
+    }
+}
+
+// Records the offer time, refusing offers that follow the previous accepted
+// one within `min_interval`
+async fn accept_offer(client_id: &str, peers: &Peers, min_interval: Duration) -> bool {
+    let mut peers = peers.lock().await;
+    let Some(peer) = peers.get_mut(client_id) else {
+        return false;
+    };
+    let now = Instant::now();
+    if peer
+        .last_offer
+        .is_some_and(|last| now.duration_since(last) < min_interval)
+    {
+        return false;
+    }
+    peer.last_offer = Some(now);
+    true
+}
+
+// Image types a capture may arrive as; anything else is refused
+const CAPTURE_MIME_TYPES: [&str; 3] = ["image/png", "image/jpeg", "image/webp"];
+
+// Accepts a base64 data URL of an allowed image type, or bare base64 from
+// clients that send no header at all
+fn decode_image_data(data: &str) -> Result<Vec<u8>, String> {
+    let base64_data = match data.strip_prefix("data:") {
+        Some(url) => {
+            let (header, payload) = url
+                .split_once(',')
+                .ok_or_else(|| "data URL has no ',' before its payload".to_owned())?;
+            let mut params = header.split(';');
+            let mime = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            if !CAPTURE_MIME_TYPES.contains(&mime.as_str()) {
+                return Err(format!(
+                    "unsupported image type '{}', expected one of {}",
+                    mime,
+                    CAPTURE_MIME_TYPES.join(", ")
+                ));
+            }
+            if !params.any(|param| param.trim().eq_ignore_ascii_case("base64")) {
+                return Err("data URL is not base64 encoded".to_owned());
+            }
+            payload
+        }
+        None => data,
+    };
+    let image_bytes = general_purpose::STANDARD
+        .decode(base64_data.trim())
+        .map_err(|e| format!("invalid base64 image data: {}", e))?;
+    if image_bytes.is_empty() {
+        return Err("image data is empty".to_owned());
+    }
+    Ok(image_bytes)
+}
+
+// Returns where the store put the capture, if it took it
+async fn handle_image_message(
+    image_bytes: Vec<u8>,
+    store: &dyn ImageStore,
+    source: &str,
+    sidecar: Option<CaptureMetadata>,
+) -> Option<String> {
+    println!(
+        "Decoded image data successfully. Bytes length: {}",
+        image_bytes.len()
+    );
+
+    match save_capture(&image_bytes, store, source).await {
+        Ok((name, location)) => {
+            println!("Image saved as {}", location);
+            if let Some(mut metadata) = sidecar {
+                if let Some((width, height)) = png_dimensions(&image_bytes) {
+                    metadata.width = Some(width);
+                    metadata.height = Some(height);
+                }
+                match write_sidecar(store, &name, &metadata).await {
+                    Ok(location) => println!("Capture metadata saved as {}", location),
+                    Err(e) => eprintln!("Failed to save capture metadata: {}", e),
+                }
+            }
+            Some(location)
+        }
+        Err(e) => {
+            eprintln!("Failed to save image: {}", e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CaptureMetadata {
+    connection_id: String,
+    user_id: Option<String>,
+    room: Option<String>,
+    captured_at_ms: u64,
+    width: Option<u32>,
+    height: Option<u32>,
+    nonce: Option<String>,
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Browsers send whatever the canvas encoded, so the extension comes from the
+// bytes rather than the data URL's claimed type
+fn image_extension(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "jpg"
+    } else {
+        "bin"
+    }
+}
+
+// Width and height from the IHDR chunk, which a valid PNG always starts with
+fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+// The sidecar shares the capture's unique name, so it can't clash either
+async fn write_sidecar(
+    store: &dyn ImageStore,
+    image_name: &str,
+    metadata: &CaptureMetadata,
+) -> std::io::Result<String> {
+    let name = Path::new(image_name).with_extension("json");
+    let json = serde_json::to_vec_pretty(metadata)?;
+    store.store(&name.to_string_lossy(), &json).await
+}
+
+// Names a capture after whoever sent it, joining the user id and room that
+// are set. Ids are client-chosen, so anything but ASCII letters, digits, `-`
+// and `_` becomes `_` and each part is cut to a sane length.
+fn capture_source(user_id: Option<&str>, room: Option<&str>) -> Option<String> {
+    let sanitize = |id: &str| -> String {
+        id.chars()
+            .take(MAX_SOURCE_ID_CHARS)
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let parts: Vec<String> = [user_id, room]
+        .into_iter()
+        .flatten()
+        .filter(|id| !id.is_empty())
+        .map(sanitize)
+        .collect();
+    (!parts.is_empty()).then(|| parts.join("_"))
+}
+
+// Named captured_<source>_<unix millis>_<seq>. The sequence number keeps
+// names unique within this process when captures land in the same
+// millisecond; stores refuse taken names, so a clash with another writer is
+// retried rather than overwriting an existing capture. Returns the name and
+// where the store put it.
+async fn save_capture(
+    image_bytes: &[u8],
+    store: &dyn ImageStore,
+    source: &str,
+) -> std::io::Result<(String, String)> {
+    let extension = image_extension(image_bytes);
+    for _ in 0..CAPTURE_NAME_ATTEMPTS {
+        let seq = CAPTURE_SEQ.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "captured_{}_{}_{}.{}",
+            source,
+            unix_millis(),
+            seq,
+            extension
+        );
+        match store.store(&name, image_bytes).await {
+            Ok(location) => return Ok((name, location)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "could not find a free capture filename",
+    ))
+}
+
+// Sends the capture trigger to one connected client. Only publishers have a
+// camera to capture from.
+async fn request_capture(
+    client_id: &str,
+    peers: &Peers,
+) -> Result<(), (warp::http::StatusCode, String)> {
+    let peers = peers.lock().await;
+    let Some(peer) = peers.get(client_id) else {
+        return Err((
+            warp::http::StatusCode::NOT_FOUND,
+            format!("no connected client {}", client_id),
+        ));
+    };
+    if !peer.role.receives(&SignalingMessage::TriggerImageCapture) {
+        return Err((
+            warp::http::StatusCode::CONFLICT,
+            format!("client {} is a {:?}, not a publisher", client_id, peer.role),
+        ));
+    }
+    if let Err(e) = trigger_image_capture(&peer.outbox).await {
+        return Err((
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("could not reach client {}: {}", client_id, e),
+        ));
+    }
+    peer.mirror(
+        client_id,
+        Direction::Out,
+        &SignalingMessage::TriggerImageCapture,
+    );
+    Ok(())
+}
+
+// Captures every connected publisher on each tick. With the queue on the
+// ticks only fire while someone holds the active session and is watching.
+async fn run_capture_timer(state: ServerState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if state.config.queue_enabled && state.queue.lock().await.active_user().is_none() {
+            continue;
+        }
+        let targets: Vec<String> = state
+            .peers
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, peer)| peer.role == Role::Publisher)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for client_id in targets {
+            if let Err((_, e)) = request_capture(&client_id, &state.peers).await {
+                eprintln!("Scheduled capture skipped: {}", e);
+            }
+        }
+    }
+}
+
+//To take a picture
+async fn trigger_image_capture(outbox: &Outbox) -> Result<(), Box<dyn std::error::Error>> {
+    let message = serde_json::to_string(&SignalingMessage::TriggerImageCapture)?;
+    outbox.send(Message::text(message))?;
+    println!("Sent image capture trigger to client.");
     Ok(())
 }
 
@@ -149,12 +2392,1532 @@ async fn forward_message(sender_id: &str, message: &SignalingMessage, peers: &Pe
     };
 
     let peers = peers.lock().await; // Await the async Mutex lock
-    for (client_id, client) in peers.iter() {
-        if client_id != sender_id {
-            let mut client = client.lock().await; // Await the async Mutex lock
-            if let Err(e) = client.send(Message::text(serialized_message.clone())).await {
+
+    // Traffic stays inside the sender's room; peers that never joined one
+    // share an implicit lobby, which keeps room-unaware clients working
+    let room = peers.get(sender_id).and_then(|peer| peer.room.as_deref());
+    let sender_features = peers
+        .get(sender_id)
+        .map(|peer| peer.features)
+        .unwrap_or_default();
+    let mut recipients: Vec<(&String, &Peer)> = match message.target() {
+        Some(target) => match peers.get_key_value(target) {
+            Some((id, peer)) if peer.room.as_deref() == room && peer.role.receives(message) => {
+                vec![(id, peer)]
+            }
+            Some(_) => {
+                eprintln!(
+                    "Dropping message from {}: target {} is not reachable from its room",
+                    sender_id, target
+                );
+                return;
+            }
+            None => {
+                eprintln!(
+                    "Dropping message from {}: unknown target {}",
+                    sender_id, target
+                );
+                return;
+            }
+        },
+        None if message.needs_target() => {
+            eprintln!("Dropping {} from {}: no target", message.kind(), sender_id);
+            return;
+        }
+        None => peers
+            .iter()
+            .filter(|(id, peer)| {
+                *id != sender_id && peer.room.as_deref() == room && peer.role.receives(message)
+            })
+            .collect(),
+    };
+    // Higher-priority peers get their copy queued first
+    recipients.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.priority));
+    // Written once the lock is released, so slow transcript IO doesn't hold
+    // up everyone else's signaling
+    let mut transcribed = Vec::new();
+    for (client_id, peer) in recipients {
+        let adapted = match adapt_for(message, sender_features, peer.features) {
+            Delivery::AsSent => None,
+            Delivery::Rewritten(adapted) => Some(adapted),
+            Delivery::Withheld(reason) => {
+                println!(
+                    "Not forwarding {} from {} to {}: {}",
+                    message.kind(),
+                    sender_id,
+                    client_id,
+                    reason
+                );
+                continue;
+            }
+        };
+        let (message, serialized_message) = match &adapted {
+            Some(adapted) => match serde_json::to_string(adapted) {
+                Ok(json) => (adapted, json),
+                Err(e) => {
+                    eprintln!("Failed to serialize message: {}", e);
+                    continue;
+                }
+            },
+            None => (message, serialized_message.clone()),
+        };
+        if let Err(e) = peer.outbox.send(Message::text(serialized_message)) {
+            eprintln!("Error sending message to {}: {}", client_id, e);
+            continue;
+        }
+        peer.mirror(client_id, Direction::Out, message);
+        if let Some(transcript) = &peer.transcript {
+            transcribed.push((Arc::clone(transcript), message.clone()));
+        }
+    }
+    drop(peers);
+    for (transcript, message) in transcribed {
+        transcript.record(Direction::Out, &message).await;
+    }
+}
+
+enum Delivery {
+    AsSent,
+    Rewritten(SignalingMessage),
+    Withheld(&'static str),
+}
+
+// Fits a forwarded message to what its recipient said it supports:
+// trickled candidates are held back from peers that take none, simulcast is
+// stripped for peers without it and compressed SDP is expanded for peers
+// that can't. An offer or answer from a peer that doesn't trickle loses its
+// trickle option, so the other side gathers every candidate into its reply.
+fn adapt_for(message: &SignalingMessage, sender: FeatureSet, recipient: FeatureSet) -> Delivery {
+    let (sdp, compressed, route, is_offer) = match message {
+        SignalingMessage::Candidate { .. } | SignalingMessage::Candidates { .. }
+            if !recipient.contains(Feature::Trickle) =>
+        {
+            return Delivery::Withheld("recipient does not take trickled candidates");
+        }
+        SignalingMessage::Offer {
+            sdp,
+            compressed,
+            target,
+            from,
+        } => (sdp, *compressed, (target, from), true),
+        SignalingMessage::Answer {
+            sdp,
+            compressed,
+            target,
+            from,
+        } => (sdp, *compressed, (target, from), false),
+        _ => return Delivery::AsSent,
+    };
+    let expand = compressed && !recipient.contains(Feature::Compression);
+    let Some(readable) = readable_sdp(sdp, compressed) else {
+        if expand {
+            return Delivery::Withheld("compressed SDP could not be expanded");
+        }
+        return Delivery::AsSent;
+    };
+    let mut adapted = readable.clone();
+    if !sender.contains(Feature::Trickle) {
+        adapted = sdp::strip_trickle(&adapted);
+    }
+    if !recipient.contains(Feature::Simulcast) {
+        adapted = sdp::strip_simulcast(&adapted);
+    }
+    if adapted == readable && !expand {
+        return Delivery::AsSent;
+    }
+    let recompress = compressed && !expand;
+    let sdp = if recompress {
+        signaling::compress_sdp(&adapted)
+    } else {
+        adapted
+    };
+    let (target, from) = (route.0.clone(), route.1.clone());
+    Delivery::Rewritten(if is_offer {
+        SignalingMessage::Offer {
+            sdp,
+            compressed: recompress,
+            target,
+            from,
+        }
+    } else {
+        SignalingMessage::Answer {
+            sdp,
+            compressed: recompress,
+            target,
+            from,
+        }
+    })
+}
+
+// None when a compressed offer can't be expanded within the size limit
+fn offer_media_count(sdp: &str, compressed: bool) -> Option<usize> {
+    Some(sdp::media_count(&readable_sdp(sdp, compressed)?))
+}
+
+fn readable_sdp(sdp: &str, compressed: bool) -> Option<String> {
+    if compressed {
+        signaling::decompress_sdp(sdp, MAX_SDP_BYTES).ok()
+    } else {
+        Some(sdp.to_owned())
+    }
+}
+
+// A renegotiation, i.e. any offer after the peer's first accepted one, must
+// keep the configured minimum of active audio and video m-lines. Returns the
+// kind that fell short.
+async fn strips_tracks(
+    client_id: &str,
+    sdp: &str,
+    compressed: bool,
+    peers: &Peers,
+    config: &ServerConfig,
+) -> Option<&'static str> {
+    if config.min_video_tracks == 0 && config.min_audio_tracks == 0 {
+        return None;
+    }
+    let renegotiation = peers
+        .lock()
+        .await
+        .get(client_id)
+        .is_some_and(|peer| peer.last_offer.is_some());
+    if !renegotiation {
+        return None;
+    }
+    let Some(sdp) = readable_sdp(sdp, compressed) else {
+        return Some("media");
+    };
+    [
+        ("video", config.min_video_tracks),
+        ("audio", config.min_audio_tracks),
+    ]
+    .into_iter()
+    .find(|(kind, min)| sdp::active_media_count(&sdp, kind) < *min)
+    .map(|(kind, _)| kind)
+}
+
+// Tells the answerer and whoever received the answer which codecs it picked
+async fn announce_negotiated(sender_id: &str, answer: &SignalingMessage, peers: &Peers) {
+    // An answer without a target was dropped, so nobody got it
+    let SignalingMessage::Answer {
+        sdp,
+        compressed,
+        target: Some(target),
+        ..
+    } = answer
+    else {
+        return;
+    };
+    let sdp = if *compressed {
+        match signaling::decompress_sdp(sdp, MAX_SDP_BYTES) {
+            Ok(sdp) => sdp,
+            Err(e) => {
+                eprintln!("Cannot read compressed answer from {}: {}", sender_id, e);
+                return;
+            }
+        }
+    } else {
+        sdp.clone()
+    };
+    let negotiated = SignalingMessage::Negotiated {
+        video_codec: sdp::negotiated_codec(&sdp, "video"),
+        audio_codec: sdp::negotiated_codec(&sdp, "audio"),
+    };
+    send_to_peer(sender_id, &negotiated, peers).await;
+    send_to_peer(target, &negotiated, peers).await;
+}
+
+async fn send_to_peer(client_id: &str, message: &SignalingMessage, peers: &Peers) {
+    let serialized_message = match serde_json::to_string(message) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Failed to serialize message: {}", e);
+            return;
+        }
+    };
+
+    let transcript = match peers.lock().await.get(client_id) {
+        Some(peer) => {
+            if let Err(e) = peer.outbox.send(Message::text(serialized_message)) {
                 eprintln!("Error sending message to {}: {}", client_id, e);
+                return;
             }
+            peer.mirror(client_id, Direction::Out, message);
+            peer.transcript.clone()
         }
+        None => return,
+    };
+    if let Some(transcript) = transcript {
+        transcript.record(Direction::Out, message).await;
+    }
+}
+
+// Refuses previews over the server-wide rate before building anything
+async fn offer_preview(
+    request: OfferPreviewRequest,
+    limiter: &Mutex<MessageLimiter>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let reply = |body: serde_json::Value, status| {
+        warp::reply::with_status(warp::reply::json(&body), status)
+    };
+    if limiter.lock().await.check(Instant::now()) != Verdict::Allow {
+        return reply(
+            serde_json::json!({ "error": "too many offer previews, try again shortly" }),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        );
+    }
+    match preview_offer(request).await {
+        Ok(sdp) => reply(
+            serde_json::json!({ "sdp": sdp }),
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => reply(
+            serde_json::json!({ "error": e.to_string() }),
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+    }
+}
+
+// Builds a throwaway peer connection with the requested tracks just to show
+// the offer it would produce. The peer connection futures aren't `Send`, so
+// the work runs on a blocking thread.
+async fn preview_offer(request: OfferPreviewRequest) -> anyhow::Result<String> {
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || handle.block_on(build_preview_offer(request))).await?
+}
+
+async fn build_preview_offer(request: OfferPreviewRequest) -> anyhow::Result<String> {
+    let mut m = MediaEngine::default();
+    codecs::register_codecs(&mut m, &request.codecs)?;
+    let mut registry = Registry::new();
+    registry = register_default_interceptors(registry, &mut m)?;
+    let api = APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .build();
+    let pc = api.new_peer_connection(RTCConfiguration::default()).await?;
+
+    let codec_mime = |kind: RTPCodecType, fallback: &str| {
+        request
+            .codecs
+            .iter()
+            .filter_map(|name| codecs::codec(name))
+            .find(|(_, k)| *k == kind)
+            .map(|(codec, _)| codec.capability.mime_type)
+            .unwrap_or_else(|| fallback.to_owned())
+    };
+    let mut tracks = Vec::new();
+    if request.video {
+        tracks.push((codec_mime(RTPCodecType::Video, MIME_TYPE_VP8), "video"));
+    }
+    if request.audio {
+        tracks.push((codec_mime(RTPCodecType::Audio, MIME_TYPE_OPUS), "audio"));
+    }
+    for (mime_type, id) in tracks {
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type,
+                ..Default::default()
+            },
+            id.to_owned(),
+            "preview".to_owned(),
+        ));
+        pc.add_track(track as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+    }
+
+    let offer = pc.create_offer(None).await;
+    pc.close().await?;
+    Ok(offer?.sdp)
+}
+
+async fn list_peers(peers: &Peers, stale_after: Duration) -> Vec<PeerInfo> {
+    let now = Instant::now();
+    let peers = peers.lock().await;
+    peers
+        .iter()
+        .map(|(id, peer)| PeerInfo {
+            id: id.clone(),
+            priority: peer.priority,
+            role: peer.role,
+            label: peer.label.clone(),
+            last_seen_secs_ago: now.saturating_duration_since(peer.last_seen).as_secs(),
+            stale: peer.is_stale(now, stale_after),
+            features: peer.features.names(),
+        })
+        .collect()
+}
+
+async fn reap_stale_peers(
+    peers: Peers,
+    queue: Option<SharedQueue>,
+    stale_after: Duration,
+    max_lifetime: Option<Duration>,
+    queue_file: Option<PathBuf>,
+) {
+    let mut ticker = tokio::time::interval(REAPER_INTERVAL);
+    loop {
+        ticker.tick().await;
+        reap_pass(
+            &peers,
+            queue.as_ref(),
+            stale_after,
+            max_lifetime,
+            queue_file.as_deref(),
+        )
+        .await;
+    }
+}
+
+// Pings every peer so live clients refresh `last_seen` with their pong, and
+// closes the ones that have stayed silent past the threshold or have been
+// connected longer than the maximum lifetime
+async fn reap_pass(
+    peers: &Peers,
+    queue: Option<&SharedQueue>,
+    stale_after: Duration,
+    max_lifetime: Option<Duration>,
+    queue_file: Option<&Path>,
+) {
+    let now = Instant::now();
+    let mut stale = Vec::new();
+    for (client_id, peer) in peers.lock().await.iter() {
+        let outlived = max_lifetime.filter(|max| peer.has_outlived(now, *max));
+        let message = if let Some(max_lifetime) = outlived {
+            println!(
+                "Client {} exceeded the maximum lifetime, closing connection",
+                client_id
+            );
+            let warning = SignalingMessage::Error {
+                code: "max_lifetime".to_owned(),
+                detail: format!("connections are closed after {} s", max_lifetime.as_secs()),
+            };
+            if let Ok(json) = serde_json::to_string(&warning) {
+                let _ = peer.outbox.send(Message::text(json));
+            }
+            stale.push(client_id.clone());
+            Message::close()
+        } else if peer.is_stale(now, stale_after) {
+            println!("Client {} is stale, closing connection", client_id);
+            stale.push(client_id.clone());
+            Message::close()
+        } else {
+            Message::ping(Vec::new())
+        };
+        if let Err(e) = peer.outbox.send(message) {
+            eprintln!("Error sending to client {}: {}", client_id, e);
+        }
+    }
+    // The peer lock is released first; the queue loop takes them the other way round
+    if let Some(queue) = queue {
+        let mut queue = queue.lock().await;
+        let mut removed = false;
+        for client_id in &stale {
+            if queue.leave_queue(client_id) {
+                println!("Removed stale client {} from the waiting line", client_id);
+                removed = true;
+            }
+        }
+        if removed {
+            persist_queue(&queue, queue_file);
+        }
+    }
+}
+
+async fn run_queue(
+    queue: SharedQueue,
+    peers: Peers,
+    webhook: Option<Arc<Webhook>>,
+    ice_activity: bool,
+    queue_file: Option<PathBuf>,
+) {
+    let mut ticker = tokio::time::interval(QUEUE_INTERVAL);
+    let mut resume_deadline = queue_file.as_ref().map(|_| Instant::now() + RESUME_GRACE);
+    loop {
+        ticker.tick().await;
+        let mut queue = queue.lock().await;
+        // Restored users who never came back give up their place
+        if resume_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            resume_deadline = None;
+            let connected: HashSet<String> = peers.lock().await.keys().cloned().collect();
+            queue.retain_users(|id| connected.contains(id));
+            persist_queue(&queue, queue_file.as_deref());
+        }
+        // A disconnected peer stops refreshing activity, so the idle timeout reaps it
+        if ice_activity {
+            if let Some(user_id) = queue.active_user().map(str::to_owned) {
+                let connected = peers
+                    .lock()
+                    .await
+                    .get(&user_id)
+                    .is_some_and(|peer| peer.ice_connected);
+                if connected {
+                    queue.update_activity(&user_id);
+                }
+            }
+        }
+        if let Some((ended, reason)) = queue.cleanup_stale_sessions() {
+            println!("Session for {} ended: it {}", ended.user_id, reason);
+            persist_queue(&queue, queue_file.as_deref());
+        }
+        if let Some((user_id, remaining)) = queue.due_for_handoff() {
+            println!(
+                "Telling {} to prepare, their turn starts in {:?}",
+                user_id, remaining
+            );
+            let message = SignalingMessage::PrepareTurn {
+                starts_in_secs: remaining.as_secs(),
+            };
+            send_to_peer(&user_id, &message, &peers).await;
+        }
+        if start_next_session(&mut queue, &peers, webhook.as_ref()).await {
+            persist_queue(&queue, queue_file.as_deref());
+        }
+    }
+}
+
+// Promotes the head of the line if the session is free; returns whether
+// anyone was. Takes the peers lock, so callers hold the queue lock only.
+// A waiting client gives up its place as soon as its connection closes, so
+// everyone behind moves up
+async fn leave_on_disconnect(
+    client_id: &str,
+    queue: &SharedQueue,
+    peers: &Peers,
+    webhook: Option<&Arc<Webhook>>,
+    config: &ServerConfig,
+) {
+    let mut queue = queue.lock().await;
+    queue.leave_queue(client_id);
+    // Without a reconnect grace the session ends here and the next user
+    // is promoted at once rather than on the queue loop's next pass
+    queue.set_connected(client_id, false);
+    if let Some((ended, reason)) = queue.cleanup_stale_sessions() {
+        println!("Session for {} ended: it {}", ended.user_id, reason);
+        start_next_session(&mut queue, peers, webhook).await;
+    } else if queue.is_active(client_id) {
+        println!(
+            "Client {} left, holding its active session for {:?}",
+            client_id, config.session_policy.reconnect_grace
+        );
+    }
+    persist_queue(&queue, config.queue_file.as_deref());
+}
+
+async fn start_next_session(
+    queue: &mut Queue,
+    peers: &Peers,
+    webhook: Option<&Arc<Webhook>>,
+) -> bool {
+    let Some(user_id) = queue.process_queue() else {
+        return false;
+    };
+    println!("Client {} now holds the active session", user_id);
+    if let Some(webhook) = webhook {
+        if let Some(peer) = peers.lock().await.get(&user_id) {
+            webhook.fire(webhook_payload(
+                WebhookEvent::SessionStart,
+                &user_id,
+                &peer.label,
+                peer.room.clone(),
+            ));
+        }
+    }
+    true
+}
+
+// Sends a queued client `queueupdate` on every queue change that moves it;
+// aborted when the connection closes
+async fn run_queue_updates(client_id: String, peers: Peers, queue: SharedQueue) {
+    let mut updates = queue.lock().await.subscribe_to_updates();
+    let mut last_sent: Option<QueueState> = None;
+    loop {
+        let state = queue.lock().await.position_of(&client_id);
+        if let Some(state) = state.filter(|state| last_sent.as_ref() != Some(state)) {
+            let message = SignalingMessage::QueueUpdate {
+                position: state.position,
+                estimated_wait_secs: state.estimated_wait_secs,
+            };
+            send_to_peer(&client_id, &message, &peers).await;
+            last_sent = Some(state);
+        }
+        if let Err(tokio::sync::broadcast::error::RecvError::Closed) = updates.recv().await {
+            return;
+        }
+    }
+}
+
+// Streams the client's place in line as bare text, both on a fixed interval
+// and whenever the queue changes. With `on_change`, a line identical to the
+// last one sent to this client is dropped, so joins behind them stay silent.
+async fn run_position_ticker(
+    client_id: String,
+    outbox: Outbox,
+    queue: SharedQueue,
+    interval: Duration,
+    on_change: bool,
+) {
+    let mut last_sent: Option<String> = None;
+    let mut updates = queue.lock().await.subscribe_to_updates();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            update = updates.recv() => {
+                if let Err(tokio::sync::broadcast::error::RecvError::Closed) = update {
+                    return;
+                }
+            }
+        }
+        let text = {
+            let queue = queue.lock().await;
+            if queue.is_active(&client_id) {
+                "active".to_owned()
+            } else {
+                match queue.position(&client_id) {
+                    Some(position) => format!("position {}", position + 1),
+                    None => continue,
+                }
+            }
+        };
+        if on_change && last_sent.as_deref() == Some(text.as_str()) {
+            continue;
+        }
+        if outbox.send(Message::text(text.clone())).is_err() {
+            return;
+        }
+        last_sent = Some(text);
+    }
+}
+
+// Each connection flushes its own queue, taking a permit from the shared gate
+// so that under contention higher-priority connections are written first
+async fn write_outbox(
+    client_id: String,
+    mut sink: SplitSink<WebSocket, Message>,
+    mut outbox: mpsc::UnboundedReceiver<Message>,
+    gate: Arc<PriorityGate>,
+    priority: u8,
+) {
+    while let Some(message) = outbox.recv().await {
+        let closing = message.is_close();
+        let _permit = gate.acquire(priority).await;
+        if let Err(e) = sink.send(message).await {
+            eprintln!("Error sending message to {}: {}", client_id, e);
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc_streaming::brain::queue::Clock;
+
+    // A connected peer, authenticated as `user` when given, and the
+    // receiving end of its outbox
+    fn connect(
+        peers: &mut HashMap<String, Peer>,
+        id: &str,
+        role: Role,
+        user: Option<&str>,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        let (outbox, inbox) = mpsc::unbounded_channel();
+        let options = ConnectionOptions {
+            priority: 0,
+            ticker: false,
+            role,
+            label: user.map(str::to_owned),
+            user_id: user.map(str::to_owned),
+            resume: None,
+        };
+        peers.insert(id.to_owned(), Peer::new(outbox, &options, None));
+        inbox
+    }
+
+    fn received(inbox: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Ok(message) = inbox.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn signaling(messages: &[Message]) -> Vec<SignalingMessage> {
+        messages
+            .iter()
+            .filter_map(|message| serde_json::from_str(message.to_str().ok()?).ok())
+            .collect()
+    }
+
+    // The built-in defaults, independent of the test runner's argv and env
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            listen: DEFAULT_LISTEN.parse().unwrap(),
+            tls: None,
+            stale_after: Duration::from_secs(60),
+            max_rooms: 100,
+            transcript_dir: None,
+            max_client_priority: 0,
+            send_concurrency: 64,
+            queue_enabled: false,
+            ticker_interval: Duration::from_secs(5),
+            queue_drop_stale: false,
+            ticker_on_change: false,
+            storage: "local".to_owned(),
+            capture_dir: DEFAULT_CAPTURE_DIR.into(),
+            capture_sidecar: false,
+            capture_source_names: false,
+            capture_interval: None,
+            tap_token: None,
+            auth_token: None,
+            min_renegotiation_interval: Duration::ZERO,
+            ice_activity: false,
+            handoff_lead: Duration::ZERO,
+            session_policy: SessionPolicy::default(),
+            queue_file: None,
+            resolve_glare: false,
+            public_address_stun: None,
+            min_video_tracks: 0,
+            min_audio_tracks: 0,
+            max_tracks_per_offer: 16,
+            max_lifetime: None,
+            max_waiting: None,
+            max_total_bitrate: None,
+            max_messages_per_sec: Some(DEFAULT_MAX_MESSAGES_PER_SEC),
+            one_way_after: Some(DEFAULT_ONE_WAY_AFTER),
+        }
+    }
+
+    fn shared(max_rooms: usize) -> (Peers, Rooms) {
+        (
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(RoomMap::new(max_rooms))),
+        )
+    }
+
+    #[tokio::test]
+    async fn reconnecting_publisher_replaces_its_stale_connection() {
+        let (peers, rooms) = shared(10);
+        let (mut old, mut viewer) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "old", Role::Publisher, Some("cam")),
+                connect(&mut peers, "viewer", Role::Viewer, None),
+            )
+        };
+        handle_join("old", "lobby".to_owned(), &peers, &rooms, false).await;
+        handle_join("viewer", "lobby".to_owned(), &peers, &rooms, false).await;
+        received(&mut viewer);
+
+        let mut new = connect(
+            &mut *peers.lock().await,
+            "new",
+            Role::Publisher,
+            Some("cam"),
+        );
+        handle_join("new", "lobby".to_owned(), &peers, &rooms, false).await;
+
+        assert!(received(&mut old).iter().any(Message::is_close));
+        assert_eq!(peers.lock().await["old"].room, None);
+        let members = &rooms.lock().await.rooms["lobby"];
+        assert!(!members.contains("old"));
+        assert!(members.contains("new"));
+        // Viewers are told to negotiate again, now with the new publisher
+        assert!(signaling(&received(&mut viewer))
+            .iter()
+            .any(|message| matches!(
+                message,
+                SignalingMessage::PublisherReplaced { room } if room == "lobby"
+            )));
+        assert!(!received(&mut new).iter().any(Message::is_close));
+    }
+
+    #[tokio::test]
+    async fn join_never_holds_rooms_while_waiting_for_peers() {
+        let (peers, rooms) = shared(10);
+        connect(&mut *peers.lock().await, "a", Role::Viewer, None);
+
+        // Holding `peers` as a disconnect does, a join in flight must not be
+        // sitting on `rooms`
+        let held = peers.lock().await;
+        let join = tokio::spawn({
+            let (peers, rooms) = (Arc::clone(&peers), Arc::clone(&rooms));
+            async move { handle_join("a", "lobby".to_owned(), &peers, &rooms, false).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let taken = tokio::time::timeout(Duration::from_secs(1), rooms.lock()).await;
+        assert!(
+            taken.is_ok(),
+            "handle_join held rooms while waiting for peers"
+        );
+        drop(taken);
+        drop(held);
+        join.await.unwrap();
+        assert_eq!(peers.lock().await["a"].room.as_deref(), Some("lobby"));
+    }
+
+    #[tokio::test]
+    async fn other_publishers_are_not_replaced() {
+        let (peers, rooms) = shared(10);
+        let (mut alice, mut anon, mut viewer) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "alice", Role::Publisher, Some("alice")),
+                connect(&mut peers, "anon", Role::Publisher, None),
+                connect(&mut peers, "viewer", Role::Viewer, None),
+            )
+        };
+        for id in ["alice", "anon", "viewer"] {
+            handle_join(id, "lobby".to_owned(), &peers, &rooms, false).await;
+        }
+        {
+            let mut peers = peers.lock().await;
+            connect(&mut peers, "bob", Role::Publisher, Some("bob"));
+            connect(&mut peers, "anon2", Role::Publisher, None);
+        }
+        handle_join("bob", "lobby".to_owned(), &peers, &rooms, false).await;
+        handle_join("anon2", "lobby".to_owned(), &peers, &rooms, false).await;
+
+        assert!(!received(&mut alice).iter().any(Message::is_close));
+        assert!(!received(&mut anon).iter().any(Message::is_close));
+        assert!(!signaling(&received(&mut viewer))
+            .iter()
+            .any(|message| matches!(message, SignalingMessage::PublisherReplaced { .. })));
+        assert_eq!(rooms.lock().await.rooms["lobby"].len(), 5);
+    }
+
+    #[tokio::test]
+    async fn a_matching_label_alone_does_not_replace_a_publisher() {
+        let (peers, rooms) = shared(10);
+        let mut live = connect(
+            &mut *peers.lock().await,
+            "live",
+            Role::Publisher,
+            Some("cam"),
+        );
+        handle_join("live", "lobby".to_owned(), &peers, &rooms, false).await;
+
+        // Same label from the query string, but no authenticated user
+        {
+            let mut peers = peers.lock().await;
+            connect(&mut peers, "intruder", Role::Publisher, None);
+            peers.get_mut("intruder").unwrap().label = Some("cam".to_owned());
+        }
+        handle_join("intruder", "lobby".to_owned(), &peers, &rooms, false).await;
+
+        assert!(!received(&mut live).iter().any(Message::is_close));
+        assert_eq!(peers.lock().await["live"].room.as_deref(), Some("lobby"));
+    }
+
+    fn answer(target: Option<&str>) -> SignalingMessage {
+        SignalingMessage::Answer {
+            sdp: "v=0\r\n".to_owned(),
+            compressed: false,
+            target: target.map(str::to_owned),
+            from: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiation_reaches_only_its_target() {
+        let (peers, _) = shared(10);
+        let (mut a, mut b, mut c) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "a", Role::Publisher, None),
+                connect(&mut peers, "b", Role::Publisher, None),
+                connect(&mut peers, "c", Role::Publisher, None),
+            )
+        };
+        let mut message = answer(Some("b"));
+        message.set_sender("a");
+        forward_message("a", &message, &peers).await;
+
+        let to_b = signaling(&received(&mut b));
+        assert!(matches!(
+            to_b.as_slice(),
+            [SignalingMessage::Answer { target: Some(target), from: Some(from), .. }]
+                if target == "b" && from == "a"
+        ));
+        assert!(received(&mut a).is_empty());
+        assert!(received(&mut c).is_empty());
+    }
+
+    #[tokio::test]
+    async fn negotiation_without_a_known_target_is_dropped() {
+        let (peers, _) = shared(10);
+        let mut inboxes = {
+            let mut peers = peers.lock().await;
+            ["a", "b", "c"].map(|id| connect(&mut peers, id, Role::Publisher, None))
+        };
+        let candidate = SignalingMessage::Candidate {
+            candidate: "candidate:1 1 udp 1 127.0.0.1 9 typ host".to_owned(),
+            sdp_mid: Some("0".to_owned()),
+            sdp_mline_index: Some(0),
+            target: None,
+            from: None,
+        };
+        for message in [answer(None), answer(Some("gone")), candidate] {
+            forward_message("a", &message, &peers).await;
+        }
+        for inbox in &mut inboxes {
+            assert!(received(inbox).is_empty());
+        }
+
+        // Anything else still goes to the whole room
+        let request = SignalingMessage::BitrateRequest {
+            max_bitrate: 500_000,
+        };
+        forward_message("a", &request, &peers).await;
+        assert!(received(&mut inboxes[0]).is_empty());
+        assert_eq!(received(&mut inboxes[1]).len(), 1);
+        assert_eq!(received(&mut inboxes[2]).len(), 1);
+    }
+
+    #[test]
+    fn rooms_past_the_limit_are_refused() {
+        let mut rooms = RoomMap::new(2);
+        assert_eq!(rooms.join("a", "alice").ok(), Some(false));
+        assert_eq!(rooms.join("b", "bob").ok(), Some(false));
+        assert!(matches!(
+            rooms.join("c", "carol"),
+            Err(JoinError::TooManyRooms)
+        ));
+        assert!(!rooms.rooms.contains_key("c"));
+
+        // Joining a room that already exists doesn't count against the limit
+        assert_eq!(rooms.join("a", "carol").ok(), Some(true));
+        rooms.leave("b", "bob");
+        assert_eq!(rooms.join("c", "carol").ok(), Some(false));
+    }
+
+    // Starts a ticker for "b", second in line behind "a"
+    async fn ticker(on_change: bool) -> (SharedQueue, mpsc::UnboundedReceiver<Message>) {
+        let queue = Arc::new(Mutex::new(Queue::new()));
+        for id in ["a", "b"] {
+            queue.lock().await.join_queue(id, 0).unwrap();
+        }
+        let (outbox, inbox) = mpsc::unbounded_channel();
+        // Long enough that only the first tick and queue updates wake it
+        tokio::spawn(run_position_ticker(
+            "b".to_owned(),
+            outbox,
+            Arc::clone(&queue),
+            Duration::from_secs(3600),
+            on_change,
+        ));
+        (queue, inbox)
+    }
+
+    async fn next_text(inbox: &mut mpsc::UnboundedReceiver<Message>) -> String {
+        let message = tokio::time::timeout(Duration::from_secs(5), inbox.recv())
+            .await
+            .expect("no position sent")
+            .unwrap();
+        message.to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn position_ticker_sends_only_changes() {
+        let (queue, mut inbox) = ticker(true).await;
+        assert_eq!(next_text(&mut inbox).await, "position 2");
+
+        // Someone joining behind "b" doesn't move it, so nothing is sent
+        queue.lock().await.join_queue("c", 0).unwrap();
+        queue.lock().await.leave_queue("a");
+        assert_eq!(next_text(&mut inbox).await, "position 1");
+        queue.lock().await.process_queue();
+        assert_eq!(next_text(&mut inbox).await, "active");
+    }
+
+    #[tokio::test]
+    async fn position_ticker_repeats_without_on_change() {
+        let (queue, mut inbox) = ticker(false).await;
+        assert_eq!(next_text(&mut inbox).await, "position 2");
+        queue.lock().await.join_queue("c", 0).unwrap();
+        assert_eq!(next_text(&mut inbox).await, "position 2");
+    }
+
+    #[test]
+    fn every_role_but_operator_waits_in_queue() {
+        // Clients that don't name a role are publishers, and must queue too
+        assert!(Role::Publisher.waits_in_queue());
+        assert!(Role::Viewer.waits_in_queue());
+        assert!(!Role::Operator.waits_in_queue());
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn operator_role_needs_the_policy_to_grant_it() {
+        let asked = query(&[("role", "operator")]);
+        assert!(connection_options(Identity::default(), &asked, 0).is_err());
+
+        let granted = Identity {
+            operator: true,
+            ..Identity::default()
+        };
+        let options = connection_options(granted, &asked, 0).unwrap();
+        assert_eq!(options.role, Role::Operator);
+
+        // Other roles are still the client's to choose
+        let options = connection_options(Identity::default(), &query(&[("role", "viewer")]), 0);
+        assert_eq!(options.unwrap().role, Role::Viewer);
+    }
+
+    #[tokio::test]
+    async fn resume_needs_the_token_not_the_id() {
+        let (peers, _) = shared(10);
+        let queue: SharedQueue = Arc::new(Mutex::new(Queue::new()));
+        let token = {
+            let mut queue = queue.lock().await;
+            queue.join_queue("alice", 0).unwrap();
+            queue.resume_token("alice").unwrap().to_owned()
+        };
+
+        // The id is visible to other clients, so it reclaims nothing
+        assert_eq!(resumed_id("alice", &peers, &queue).await, None);
+        assert_eq!(resumed_id("guess", &peers, &queue).await, None);
+        assert_eq!(
+            resumed_id(&token, &peers, &queue).await.as_deref(),
+            Some("alice")
+        );
+
+        // Nor can the token take over a connection that is still live
+        connect(&mut *peers.lock().await, "alice", Role::Viewer, None);
+        assert_eq!(resumed_id(&token, &peers, &queue).await, None);
+    }
+
+    #[tokio::test]
+    async fn offer_previews_past_the_rate_are_refused() {
+        let limiter = Mutex::new(MessageLimiter::new(1, 1, Instant::now()));
+        let request = || OfferPreviewRequest {
+            video: true,
+            audio: false,
+            codecs: Vec::new(),
+        };
+        let status = |reply| warp::Reply::into_response(reply).status();
+
+        let first = offer_preview(request(), &limiter).await;
+        assert_eq!(status(first), warp::http::StatusCode::OK);
+        let second = offer_preview(request(), &limiter).await;
+        assert_eq!(status(second), warp::http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn taps_need_the_configured_token() {
+        let token = "s3cret".to_owned();
+        assert!(tap_authorized(Some("s3cret"), Some(&token)));
+        assert!(!tap_authorized(Some("s3cret!"), Some(&token)));
+        assert!(!tap_authorized(Some("s3cret"), None));
+        // No token configured refuses every tap
+        assert!(!tap_authorized(None, Some(&token)));
+    }
+
+    struct ManualClock(std::sync::Mutex<Instant>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_keep_the_active_session_alive() {
+        let clock = Arc::new(ManualClock(std::sync::Mutex::new(Instant::now())));
+        let policy = SessionPolicy {
+            max_session: Duration::from_secs(300),
+            max_idle: Duration::from_secs(60),
+            accept_within: Some(Duration::from_secs(20)),
+            reconnect_grace: Duration::ZERO,
+        };
+        let queue: SharedQueue = Arc::new(Mutex::new(
+            Queue::with_policy(policy).with_clock(clock.clone()),
+        ));
+        let (peers, _) = shared(10);
+        connect(&mut *peers.lock().await, "alice", Role::Viewer, None);
+        {
+            let mut queue = queue.lock().await;
+            queue.join_queue("alice", 0).unwrap();
+            queue.process_queue();
+        }
+
+        // Well past both the acceptance window and the idle timeout
+        for _ in 0..8 {
+            *clock.0.lock().unwrap() += Duration::from_secs(15);
+            note_activity("alice", &peers, Some(&queue)).await;
+            assert!(queue.lock().await.cleanup_stale_sessions().is_none());
+        }
+        assert!(queue.lock().await.is_active("alice"));
+    }
+
+    fn offer_with_video(port: u16) -> String {
+        format!(
+            "v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=sendrecv\r\n\
+             m=video {} UDP/TLS/RTP/SAVPF 96\r\na=sendrecv\r\n",
+            port
+        )
+    }
+
+    #[tokio::test]
+    async fn renegotiation_removing_the_only_video_track_is_rejected() {
+        let config = ServerConfig {
+            min_video_tracks: 1,
+            ..test_config()
+        };
+        let (peers, _) = shared(10);
+        connect(&mut *peers.lock().await, "a", Role::Publisher, None);
+        let removed = offer_with_video(0);
+
+        // The first offer isn't a renegotiation, so it may carry anything
+        assert_eq!(
+            strips_tracks("a", &removed, false, &peers, &config).await,
+            None
+        );
+
+        peers.lock().await.get_mut("a").unwrap().last_offer = Some(Instant::now());
+        assert_eq!(
+            strips_tracks("a", &removed, false, &peers, &config).await,
+            Some("video")
+        );
+        let kept = offer_with_video(9);
+        assert_eq!(
+            strips_tracks("a", &kept, false, &peers, &config).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_refresh_last_seen_and_silence_is_flagged() {
+        let (peers, _) = shared(10);
+        connect(&mut *peers.lock().await, "a", Role::Viewer, None);
+        let stale_after = Duration::from_secs(60);
+        peers.lock().await.get_mut("a").unwrap().last_seen =
+            Instant::now() - Duration::from_secs(90);
+
+        let listed = list_peers(&peers, stale_after).await;
+        assert!(listed[0].stale);
+        assert!(listed[0].last_seen_secs_ago >= 90);
+
+        note_activity("a", &peers, None).await;
+        let listed = list_peers(&peers, stale_after).await;
+        assert!(!listed[0].stale);
+        assert_eq!(listed[0].last_seen_secs_ago, 0);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_captures_never_overwrite_each_other() {
+        let dir = std::env::temp_dir().join(format!("captures-{}", Uuid::new_v4()));
+        let store = LocalFsStore::new(&dir);
+        let (first, second) = tokio::join!(
+            save_capture(b"first", &store, "cam"),
+            save_capture(b"second", &store, "cam"),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.0, second.0);
+        assert_eq!(std::fs::read(&first.1).unwrap(), b"first");
+        assert_eq!(std::fs::read(&second.1).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        // A taken name is refused rather than overwritten
+        let e = store.store(&first.0, b"again").await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&first.1).unwrap(), b"first");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn offers_within_the_renegotiation_interval_are_refused() {
+        let (peers, _) = shared(10);
+        connect(&mut *peers.lock().await, "a", Role::Publisher, None);
+        let interval = Duration::from_secs(1);
+
+        assert!(accept_offer("a", &peers, interval).await);
+        let accepted = peers.lock().await["a"].last_offer;
+        assert!(!accept_offer("a", &peers, interval).await);
+        // A refused offer doesn't restart the interval
+        assert_eq!(peers.lock().await["a"].last_offer, accepted);
+
+        peers.lock().await.get_mut("a").unwrap().last_offer =
+            Some(Instant::now() - Duration::from_secs(2));
+        assert!(accept_offer("a", &peers, interval).await);
+        assert!(!accept_offer("unknown", &peers, interval).await);
+    }
+
+    #[tokio::test]
+    async fn a_waiting_client_that_disconnects_leaves_the_line_at_once() {
+        let (peers, _) = shared(10);
+        let queue = Arc::new(Mutex::new(Queue::new()));
+        for id in ["a", "b", "c", "d"] {
+            queue.lock().await.join_queue(id, 0).unwrap();
+        }
+        queue.lock().await.process_queue();
+        assert_eq!(queue.lock().await.position("d"), Some(2));
+
+        leave_on_disconnect("c", &queue, &peers, None, &test_config()).await;
+        let waiting = queue.lock().await;
+        assert_eq!(waiting.position("c"), None);
+        assert_eq!(waiting.position("b"), Some(0));
+        assert_eq!(waiting.position("d"), Some(1));
+        assert!(waiting.is_active("a"));
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_max_lifetime_are_closed_while_active() {
+        let (peers, _) = shared(10);
+        let mut old = connect(&mut *peers.lock().await, "old", Role::Publisher, None);
+        let mut young = connect(&mut *peers.lock().await, "young", Role::Viewer, None);
+        peers.lock().await.get_mut("old").unwrap().connected_at =
+            Instant::now() - Duration::from_secs(7200);
+        // Both were heard from just now
+        note_activity("old", &peers, None).await;
+        note_activity("young", &peers, None).await;
+
+        let max_lifetime = Some(Duration::from_secs(3600));
+        reap_pass(&peers, None, Duration::from_secs(60), max_lifetime, None).await;
+
+        let closing = received(&mut old);
+        assert_eq!(closing.len(), 2);
+        assert!(closing[0].to_str().unwrap().contains("\"max_lifetime\""));
+        assert!(closing[1].is_close());
+        let kept = received(&mut young);
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].is_ping());
+    }
+
+    #[tokio::test]
+    async fn both_ends_learn_the_negotiated_codecs() {
+        let (peers, _) = shared(10);
+        let (mut answerer, mut offerer) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "a", Role::Publisher, None),
+                connect(&mut peers, "b", Role::Viewer, None),
+            )
+        };
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\n\
+                   m=video 9 UDP/TLS/RTP/SAVPF 96 97\r\na=rtpmap:96 VP8/90000\r\n\
+                   a=rtpmap:97 rtx/90000\r\n";
+        let answer = SignalingMessage::Answer {
+            sdp: sdp.to_owned(),
+            compressed: false,
+            target: Some("b".to_owned()),
+            from: None,
+        };
+        announce_negotiated("a", &answer, &peers).await;
+
+        for inbox in [&mut answerer, &mut offerer] {
+            let messages = signaling(&received(inbox));
+            assert!(
+                matches!(
+                    messages.as_slice(),
+                    [SignalingMessage::Negotiated {
+                        video_codec: Some(video),
+                        audio_codec: Some(audio),
+                    }] if video == "VP8" && audio == "opus"
+                ),
+                "{:?}",
+                messages
+            );
+        }
+    }
+
+    fn offer(target: Option<&str>) -> SignalingMessage {
+        SignalingMessage::Offer {
+            sdp: "v=0\r\n".to_owned(),
+            compressed: false,
+            target: target.map(str::to_owned),
+            from: None,
+        }
+    }
+
+    // Forwards `message` if the room's glare rules let it through
+    async fn relay(
+        from: &str,
+        mut message: SignalingMessage,
+        peers: &Peers,
+        rooms: &Rooms,
+    ) -> bool {
+        message.set_sender(from);
+        let passed = pass_glare(from, &message, peers, rooms).await;
+        if passed {
+            forward_message(from, &message, peers).await;
+        }
+        passed
+    }
+
+    #[tokio::test]
+    async fn simultaneous_offers_in_a_room_deliver_only_one() {
+        let (peers, rooms) = shared(10);
+        let (mut first, mut second) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "a", Role::Publisher, None),
+                connect(&mut peers, "b", Role::Publisher, None),
+            )
+        };
+        // The first to join is impolite, the second polite
+        handle_join("a", "lobby".to_owned(), &peers, &rooms, true).await;
+        handle_join("b", "lobby".to_owned(), &peers, &rooms, true).await;
+        assert!(!peers.lock().await["a"].polite);
+        assert!(peers.lock().await["b"].polite);
+        received(&mut first);
+        received(&mut second);
+
+        let offers = |messages: &[SignalingMessage]| {
+            messages
+                .iter()
+                .filter(|m| matches!(m, SignalingMessage::Offer { .. }))
+                .count()
+        };
+
+        // Both offer at once: only the impolite side's offer goes through
+        assert!(relay("a", offer(Some("b")), &peers, &rooms).await);
+        assert!(!relay("b", offer(Some("a")), &peers, &rooms).await);
+        assert_eq!(offers(&signaling(&received(&mut second))), 1);
+        assert_eq!(offers(&signaling(&received(&mut first))), 0);
+
+        // Once it is answered the polite side may offer again
+        assert!(relay("b", answer(Some("a")), &peers, &rooms).await);
+        assert!(relay("b", offer(Some("a")), &peers, &rooms).await);
+        assert_eq!(offers(&signaling(&received(&mut first))), 1);
+    }
+
+    fn test_state(peers: Peers, rooms: Rooms) -> ServerState {
+        let config = test_config();
+        ServerState {
+            gate: PriorityGate::new(config.send_concurrency),
+            peers,
+            rooms,
+            queue: Arc::new(Mutex::new(Queue::new())),
+            webhook: None,
+            public_address: None,
+            dispatch: Arc::new(Dispatch::standard()),
+            governor: None,
+            auth: Arc::new(AllowAll),
+            images: Arc::new(LocalFsStore::new(std::env::temp_dir())),
+            config: Arc::new(config),
+        }
+    }
+
+    // An unqueued publisher's connection
+    fn test_connection(client_id: &str, state: ServerState) -> Connection {
+        Connection {
+            client_id: client_id.to_owned(),
+            options: ConnectionOptions {
+                priority: 0,
+                ticker: false,
+                role: Role::Publisher,
+                label: None,
+                user_id: None,
+                resume: None,
+            },
+            state,
+            queued: false,
+            flow: None,
+        }
+    }
+
+    // Handlers that report back to the connection which of them ran
+    fn report(
+        connection: &Connection,
+        code: &str,
+        message: SignalingMessage,
+    ) -> HandlerFuture<'static> {
+        let report = SignalingMessage::Error {
+            code: code.to_owned(),
+            detail: message.kind().to_owned(),
+        };
+        let (client_id, peers) = (connection.client_id.clone(), connection.state.peers.clone());
+        Box::pin(async move { send_to_peer(&client_id, &report, &peers).await })
+    }
+
+    fn handle_custom(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+        report(connection, "custom", message)
+    }
+
+    fn handle_default(connection: &Connection, message: SignalingMessage) -> HandlerFuture<'_> {
+        report(connection, "default", message)
+    }
+
+    #[tokio::test]
+    async fn registered_handlers_run_for_their_type_and_the_rest_hit_the_default() {
+        let (peers, rooms) = shared(10);
+        let mut inbox = connect(&mut *peers.lock().await, "a", Role::Publisher, None);
+        let connection = test_connection("a", test_state(peers, rooms));
+        let mut dispatch = Dispatch::new(handle_default);
+        dispatch.register("bitraterequest", handle_custom);
+
+        let request = SignalingMessage::BitrateRequest {
+            max_bitrate: 500_000,
+        };
+        dispatch.dispatch(&connection, request).await;
+        dispatch.dispatch(&connection, offer(Some("b"))).await;
+
+        let handled: Vec<(String, String)> = signaling(&received(&mut inbox))
+            .into_iter()
+            .filter_map(|message| match message {
+                SignalingMessage::Error { code, detail } => Some((code, detail)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            handled,
+            [
+                ("custom".to_owned(), "bitraterequest".to_owned()),
+                ("default".to_owned(), "offer".to_owned()),
+            ]
+        );
+    }
+
+    // Knows users by their API key and turns one of them away
+    struct ApiKeys;
+
+    impl AuthPolicy for ApiKeys {
+        fn authorize<'a>(&'a self, request: &'a AuthRequest) -> auth::AuthFuture<'a> {
+            Box::pin(async move {
+                match request.bearer_token() {
+                    Some("key-alice") => Ok(Identity {
+                        user_id: Some("alice".to_owned()),
+                        operator: false,
+                    }),
+                    Some("key-mallory") => Err(AuthError("mallory is banned".to_owned())),
+                    _ => Err(AuthError("unknown key".to_owned())),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn a_custom_policy_decides_who_connects_and_as_whom() {
+        let route = authorize(Arc::new(ApiKeys))
+            .map(|identity: Identity| {
+                let options = connection_options(identity, &HashMap::new(), 0).unwrap();
+                options.user_id.unwrap_or_default()
+            })
+            .recover(unauthorized_reply);
+
+        let response = warp::test::request()
+            .path("/signaling")
+            .header("authorization", "Bearer key-alice")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        assert_eq!(response.body(), "alice");
+
+        let response = warp::test::request()
+            .path("/signaling?token=key-mallory")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body(), "mallory is banned");
+    }
+
+    #[tokio::test]
+    async fn one_way_stats_warn_about_the_silent_direction() {
+        let (peers, rooms) = shared(10);
+        let mut inbox = connect(&mut *peers.lock().await, "a", Role::Publisher, None);
+        let threshold = Duration::from_millis(50);
+        let connection = Connection {
+            flow: Some(Mutex::new(FlowMonitor::new(threshold))),
+            ..test_connection("a", test_state(peers, rooms))
+        };
+        let stats = |bytes_sent, bytes_received| SignalingMessage::Stats {
+            bytes_sent,
+            bytes_received,
+        };
+
+        handle_stats(&connection, stats(1_000, 1_000)).await;
+        tokio::time::sleep(threshold * 2).await;
+        // Still receiving, but nothing more went out
+        handle_stats(&connection, stats(1_000, 5_000)).await;
+
+        let messages = signaling(&received(&mut inbox));
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [SignalingMessage::MediaWarning {
+                    direction: oneway::Direction::Outbound
+                }]
+            ),
+            "{:?}",
+            messages
+        );
+    }
+
+    #[tokio::test]
+    async fn binding_a_taken_port_gives_a_plain_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen = taken.local_addr().unwrap();
+
+        let bound = warp::serve(warp::any().map(warp::reply)).try_bind_ephemeral(listen);
+        let Err(e) = bound else {
+            panic!("bound {} twice", listen);
+        };
+        assert_eq!(
+            bind_error_message(listen, &e),
+            format!(
+                "Port {} already in use on 127.0.0.1; stop whatever holds it or pick \
+                 another with --listen",
+                listen.port()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn a_client_without_trickle_gets_the_non_trickle_path() {
+        let (peers, rooms) = shared(10);
+        let (mut publisher, mut viewer) = {
+            let mut peers = peers.lock().await;
+            (
+                connect(&mut peers, "pub", Role::Publisher, None),
+                connect(&mut peers, "viewer", Role::Viewer, None),
+            )
+        };
+        let connection = test_connection("pub", test_state(peers.clone(), rooms));
+        let hello = SignalingMessage::Hello {
+            features: vec!["compression".to_owned()],
+        };
+        handle_hello(&connection, hello).await;
+        assert!(!peers.lock().await["pub"]
+            .features
+            .contains(Feature::Trickle));
+        received(&mut publisher);
+
+        let mut offer = SignalingMessage::Offer {
+            sdp: "v=0\r\na=ice-options:trickle\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n".to_owned(),
+            compressed: false,
+            target: Some("viewer".to_owned()),
+            from: None,
+        };
+        offer.set_sender("pub");
+        forward_message("pub", &offer, &peers).await;
+        let delivered = signaling(&received(&mut viewer));
+        let [SignalingMessage::Offer { sdp, .. }] = delivered.as_slice() else {
+            panic!("viewer got {:?}", delivered);
+        };
+        // So the answerer gathers every candidate into its answer
+        assert!(!sdp::supports_trickle(sdp));
+
+        let mut candidate = SignalingMessage::Candidate {
+            candidate: "candidate:1 1 udp 1 127.0.0.1 9 typ host".to_owned(),
+            sdp_mid: Some("0".to_owned()),
+            sdp_mline_index: Some(0),
+            target: Some("pub".to_owned()),
+            from: None,
+        };
+        candidate.set_sender("viewer");
+        forward_message("viewer", &candidate, &peers).await;
+        assert!(received(&mut publisher).is_empty());
     }
 }
@@ -2,204 +2,2649 @@ use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{fs::File, io::BufReader, time::Duration};
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, handshake::client::Request, Message},
+};
+use webrtc_streaming::avsync::{self, SyncChange, Timeline, Track};
+use webrtc_streaming::congestion::{BandwidthProbe, LossMonitor, ProbeResult, WritePacer};
+use webrtc_streaming::depacketizer::{self, Assembled, FrameAssembler};
+use webrtc_streaming::features::{Feature, FeatureSet};
+use webrtc_streaming::images::{self, ImageAssembler};
+use webrtc_streaming::oneway;
+use webrtc_streaming::recording::{self, Recording};
+use webrtc_streaming::signaling::{self, CandidateInit, SessionDescriptionInit};
+use webrtc_streaming::transcode::Transcoder;
+use webrtc_streaming::{codecs, config, ffmpeg, h264, ice, sdp, shutdown, stun, thumbnail};
 
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors,
-        media_engine::{MediaEngine, MIME_TYPE_VP8},
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8},
+        setting_engine::SettingEngine,
         APIBuilder,
     },
-    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_server::RTCIceServer},
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_connection_state::RTCIceConnectionState,
+        ice_server::RTCIceServer,
+    },
     interceptor::registry::Registry,
-    media::{io::ivf_reader::IVFReader, Sample},
+    media::{
+        io::h264_reader::H264Reader,
+        io::ivf_reader::{IVFFileHeader, IVFReader},
+        io::ogg_reader::OggReader,
+        Sample,
+    },
     peer_connection::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
-        sdp::session_description::RTCSessionDescription,
+        policy::ice_transport_policy::RTCIceTransportPolicy,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+        receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
     },
+    rtcp::receiver_report::ReceiverReport,
+    rtp::codecs::{h264::H264Packet, vp8::Vp8Packet},
+    rtp::packetizer::Depacketizer,
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    stats::StatsReportType,
     track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+    track::track_remote::TrackRemote,
 };
 
+// Frame rate assumed when neither `--fps` nor the IVF header gives a usable one
+const DEFAULT_FPS: f64 = 30.0;
+// Frame intervals a timebase must fall within to be taken as the frame rate
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+// A fresh ffmpeg starts with a keyframe, so keyframe requests this soon after
+// a (re)start are already answered
+const MIN_KEYFRAME_RESTART: Duration = Duration::from_secs(2);
+
+const SIGNALING_URL: &str = "ws://localhost:3030/signaling";
+// Delay before the first reconnection attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Codes sent in `error` when a peer's message can't be applied
+const ERROR_BAD_SDP: &str = "bad_sdp";
+const ERROR_BAD_CANDIDATE: &str = "bad_candidate";
+const ERROR_INTERNAL: &str = "internal";
+const ERROR_TOO_MANY_TRACKS: &str = "too_many_tracks";
+const ERROR_FINGERPRINT_NOT_ALLOWED: &str = "fingerprint_not_allowed";
+// Errors that reconnecting and sending the same thing again can't fix
+const FATAL_ERRORS: &[&str] = &[ERROR_BAD_SDP, ERROR_TOO_MANY_TRACKS];
+// How often bytes sent are reported for the server's bandwidth governor
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+// How often the recording directory is checked against the retention limits
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 enum SignalingMessage {
+    // `compressed` marks SDP sent gzip-compressed and base64-encoded. We
+    // address negotiation to the remote peer's id in `target`; the server
+    // says who sent what we receive in `from`.
     Offer {
         sdp: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing)]
+        from: Option<String>,
     },
     Answer {
         sdp: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        compressed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing)]
+        from: Option<String>,
     },
     Candidate {
         candidate: String,
+        #[serde(rename = "sdpMid", alias = "sdp_mid")]
         sdp_mid: Option<String>,
+        #[serde(rename = "sdpMLineIndex", alias = "sdp_mline_index")]
         sdp_mline_index: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing)]
+        from: Option<String>,
+    },
+    // Several candidates gathered close together, sent as one message
+    Candidates {
+        list: Vec<CandidateInit>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing)]
+        from: Option<String>,
+    },
+    // Our own id and the peers we could negotiate with
+    Welcome {
+        your_id: String,
+        peers: Vec<String>,
+    },
+    PeerJoined {
+        id: String,
+    },
+    // Features we support, sent on connect; the server replies with the ones
+    // it will honour
+    Hello {
+        features: Vec<String>,
+    },
+    BitrateRequest {
+        max_bitrate: u64,
+    },
+    // Encoder settings a viewer would like; unset fields keep their value
+    StreamRequest {
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+        #[serde(default)]
+        fps: Option<u32>,
+        #[serde(default)]
+        bitrate: Option<u64>,
+    },
+    IceState {
+        state: String,
+    },
+    // Media bytes sent and received so far on this connection
+    Stats {
+        bytes_sent: u64,
+        #[serde(default)]
+        bytes_received: u64,
+    },
+    // Media in this direction has stopped while the other still flows
+    MediaWarning {
+        direction: oneway::Direction,
+    },
+    // The server's share of its total bandwidth for us; none lifts the cap
+    BitrateCap {
+        #[serde(default)]
+        max_bitrate: Option<u64>,
     },
+    // Codecs the server read from the answer
+    Negotiated {
+        #[serde(default)]
+        video_codec: Option<String>,
+        #[serde(default)]
+        audio_codec: Option<String>,
+    },
+    // An operation on a message failed, reported by the server or the peer
+    Error {
+        code: String,
+        detail: String,
+    },
+    // The server is going away
+    Bye,
 }
 
+type WsWrite = Arc<
+    Mutex<
+        futures_util::stream::SplitSink<
+            tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+            Message,
+        >,
+    >,
+>;
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Legacy gateways that can't trickle get every candidate inside the answer
+    let force_non_trickle = config::enabled("non-trickle", "RUSTWEBRTC_NON_TRICKLE");
+    // We only ever send one encoding, so simulcast is left out
+    let mut features = FeatureSet::all();
+    features.remove(Feature::Simulcast);
+    if force_non_trickle {
+        features.remove(Feature::Trickle);
+    }
+    let disabled_rtcp_fb: Vec<String> =
+        config::setting("disable-rtcp-fb", "RUSTWEBRTC_DISABLE_RTCP_FB")
+            .map(|list| list.split(',').map(|fb| fb.trim().to_owned()).collect())
+            .unwrap_or_default();
+    // Gateways that expect fixed payload type numbers, e.g. `vp8=96,opus=111`
+    let payload_type_pins = codecs::payload_type_pins();
+    // Restrict the advertised video codecs, optionally retrying with the full
+    // default set when the offer shares none of them
+    let mut preferred_codecs: Vec<String> = config::setting("codecs", "RUSTWEBRTC_CODECS")
+        .map(|list| list.split(',').map(|c| c.trim().to_owned()).collect())
+        .unwrap_or_default();
+    let codec_fallback = config::enabled("codec-fallback", "RUSTWEBRTC_CODEC_FALLBACK");
+    // Codec of the published video, which also decides how the source is read
+    let codec = config::setting("codec", "RUSTWEBRTC_CODEC")
+        .unwrap_or_else(|| "vp8".to_owned())
+        .to_ascii_lowercase();
+    let source_format = SourceFormat::for_codec(&codec)
+        .ok_or_else(|| anyhow::anyhow!("unknown --codec '{}', expected vp8, vp9 or h264", codec))?;
+    // Viewers that offer H.264 but not VP8 get the VP8 source re-encoded
+    let transcode_h264 = config::enabled("transcode-h264", "RUSTWEBRTC_TRANSCODE_H264");
+    if transcode_h264 && codec != "vp8" {
+        println!("Ignoring --transcode-h264, it only applies to a VP8 source");
+    }
+    let transcode_h264 = transcode_h264 && codec == "vp8";
+    // File sources start over at their end instead of stopping
+    let looping = config::enabled("loop", "RUSTWEBRTC_LOOP");
+    // `--av-source` plays a file's video and audio together, kept in sync,
+    // in place of the watched IVF directory. It brings its own audio track.
+    let av_source = config::setting("av-source", "RUSTWEBRTC_AV_SOURCE").map(config::expand_home);
+    if let Some(path) = &av_source {
+        if !std::path::Path::new(path).exists() {
+            anyhow::bail!("--av-source {} not found", path);
+        }
+        if matches!(source_format, SourceFormat::AnnexB) {
+            anyhow::bail!("--av-source encodes VP8 or VP9, not {}", codec);
+        }
+    }
+    // `--audio` adds an Opus track next to the video, fed from the given file
+    // or, without one, from the default microphone
+    let with_audio = config::has_flag("audio") || av_source.is_some();
+    let audio_input = config::flag_value("audio").map(config::expand_home);
+    if av_source.is_some() && audio_input.is_some() {
+        println!("Ignoring the --audio input, audio comes from --av-source");
+    }
+    if with_audio
+        && !preferred_codecs.is_empty()
+        && !preferred_codecs
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case("opus"))
+    {
+        preferred_codecs.push("opus".to_owned());
+    }
+    // Live capture settings; viewers' stream requests update them through the
+    // watch channel, which restarts ffmpeg with the new encoder arguments.
+    // `--input` names a device or a media file to encode, like `--capture`.
+    let capturing = config::has_flag("capture") || config::has_flag("input");
+    if capturing && av_source.is_some() {
+        anyhow::bail!("--av-source and --capture/--input both choose the video source, pick one");
+    }
+    let (capture_options, capture_options_rx) = watch::channel(ffmpeg::CaptureOptions {
+        device: config::flag_value("input")
+            .or_else(|| config::flag_value("capture"))
+            .map(config::expand_home),
+        vp9: codec == "vp9",
+        threads: config::parse_setting("threads", "RUSTWEBRTC_FFMPEG_THREADS", 2),
+        nice: config::setting("nice", "RUSTWEBRTC_FFMPEG_NICE").and_then(|n| n.parse().ok()),
+        extra_args: config::setting("ffmpeg-extra", "RUSTWEBRTC_FFMPEG_EXTRA")
+            .map(|extra| extra.split_whitespace().map(str::to_owned).collect())
+            .unwrap_or_default(),
+        ..Default::default()
+    });
+    let defaults = ffmpeg::EncoderLimits::default();
+    let encoder_limits = ffmpeg::EncoderLimits {
+        max_width: config::parse_setting("max-width", "RUSTWEBRTC_MAX_WIDTH", defaults.max_width),
+        max_height: config::parse_setting(
+            "max-height",
+            "RUSTWEBRTC_MAX_HEIGHT",
+            defaults.max_height,
+        ),
+        max_fps: config::parse_setting("max-fps", "RUSTWEBRTC_MAX_FPS", defaults.max_fps),
+        max_bitrate: config::parse_setting(
+            "max-bitrate",
+            "RUSTWEBRTC_MAX_BITRATE",
+            defaults.max_bitrate,
+        ),
+    };
+    // Guards against a hostile or broken server sending huge descriptions
+    let max_sdp_bytes: usize =
+        config::parse_setting("max-sdp-bytes", "RUSTWEBRTC_MAX_SDP_BYTES", 64 * 1024);
+    // Each m-line costs a transceiver, so offers with too many are dropped
+    // before they reach the peer connection
+    let max_tracks_per_offer: usize = config::parse_setting(
+        "max-tracks-per-offer",
+        "RUSTWEBRTC_MAX_TRACKS_PER_OFFER",
+        16,
+    );
+    // Answers go out gzip+base64 encoded when that makes them smaller
+    let compress_sdp = config::enabled("compress-sdp", "RUSTWEBRTC_COMPRESS_SDP");
+    // Closed deployments only answer offers from known DTLS certificates
+    let fingerprint_allowlist: Vec<String> =
+        config::setting("fingerprint-allowlist", "RUSTWEBRTC_FINGERPRINT_ALLOWLIST")
+            .map(|list| list.split(',').map(|fp| fp.trim().to_owned()).collect())
+            .unwrap_or_default();
+
+    // Debugging aids for ICE restarts: `--debug-ice` logs the credentials of
+    // every offer/answer, and `--ice-ufrag`/`--ice-pwd` pin ours so runs are
+    // reproducible. Pinned credentials survive a restart by design, so don't
+    // use them in production.
+    let debug_ice = config::enabled("debug-ice", "RUSTWEBRTC_DEBUG_ICE");
+    let ice_credentials = match (
+        config::setting("ice-ufrag", "RUSTWEBRTC_ICE_UFRAG"),
+        config::setting("ice-pwd", "RUSTWEBRTC_ICE_PWD"),
+    ) {
+        (Some(ufrag), Some(pwd)) => {
+            // RFC 8839 minimums
+            if ufrag.len() < 4 || pwd.len() < 22 {
+                anyhow::bail!("--ice-ufrag needs at least 4 characters and --ice-pwd at least 22");
+            }
+            println!("Using pinned ICE credentials, restarts will keep them");
+            Some((ufrag, pwd))
+        }
+        (None, None) => None,
+        _ => anyhow::bail!("--ice-ufrag and --ice-pwd must be given together"),
+    };
+
+    // Ordered STUN servers; the first to answer a binding request within the
+    // timeout is the one ICE uses
+    let stun_servers: Vec<String> = config::setting("stun-servers", "RUSTWEBRTC_STUN_SERVERS")
+        .map(|list| list.split(',').map(|url| url.trim().to_owned()).collect())
+        .unwrap_or_else(|| vec![stun::DEFAULT_SERVER.to_owned()]);
+    let stun_timeout = Duration::from_millis(config::parse_setting(
+        "stun-timeout-ms",
+        "RUSTWEBRTC_STUN_TIMEOUT_MS",
+        2000,
+    ));
+    let stun_servers = if stun_servers.len() > 1 {
+        match stun::first_reachable(&stun_servers, stun_timeout).await {
+            Some(url) => vec![url],
+            None => {
+                println!("No STUN server answered, leaving all of them to ICE");
+                stun_servers
+            }
+        }
+    } else {
+        stun_servers
+    };
+    let mut ice_servers = vec![RTCIceServer {
+        urls: stun_servers,
+        ..Default::default()
+    }];
+    // TURN relays (and any further STUN servers) for peers behind strict NATs
+    if let Some(json) = config::setting("ice-servers", "RUSTWEBRTC_ICE_SERVERS") {
+        ice_servers.extend(
+            ice::parse_ice_servers(&json)
+                .map_err(|e| anyhow::anyhow!("invalid --ice-servers: {}", e))?,
+        );
+    }
+
+    // Fail fast on a codec setup that every peer connection would trip over
+    codecs::self_check(&preferred_codecs)?;
+    if codec_fallback && !preferred_codecs.is_empty() {
+        codecs::self_check(&[])?;
+    }
+
+    // A codec the engine won't negotiate would only fail once an offer arrives
+    if !preferred_codecs.is_empty()
+        && !preferred_codecs
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(&codec))
+    {
+        anyhow::bail!(
+            "--codec {} is not among the registered codecs {:?}",
+            codec,
+            preferred_codecs
+        );
+    }
+    if capturing && source_format == SourceFormat::AnnexB {
+        anyhow::bail!("--capture encodes VP8 or VP9, it can't publish --codec h264");
+    }
+
+    // Create video track
+    let video_track = Arc::new(TrackLocalStaticSample::new(
+        codecs::video_codec(&codec)
+            .expect("source format implies a known codec")
+            .capability,
+        "video".to_owned(),
+        "webcam".to_owned(),
+    ));
+
+    let h264_track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_H264.to_owned(),
+            ..Default::default()
+        },
+        "video".to_owned(),
+        "webcam".to_owned(),
+    ));
+    // Without a connected viewer, video samples are dropped and live capture
+    // is stopped until one connects
+    let pause_without_viewers =
+        config::enabled("pause-without-viewers", "RUSTWEBRTC_PAUSE_WITHOUT_VIEWERS");
+    let (viewer_present, viewers) = watch::channel(false);
+    let viewer_present = Arc::new(viewer_present);
+    // Thumbnails for monitoring, rendered from the newest keyframe sent
+    let thumbnails = config::setting("thumbnail-dir", "RUSTWEBRTC_THUMBNAIL_DIR").map(|dir| {
+        thumbnail::ThumbnailOptions {
+            dir: dir.into(),
+            interval: Duration::from_secs(
+                config::parse_setting(
+                    "thumbnail-interval-secs",
+                    "RUSTWEBRTC_THUMBNAIL_INTERVAL_SECS",
+                    10,
+                )
+                .max(1),
+            ),
+            width: config::parse_setting("thumbnail-width", "RUSTWEBRTC_THUMBNAIL_WIDTH", 320),
+        }
+    });
+    let keyframes = thumbnails.map(|options| {
+        let (tap, keyframes) = thumbnail::KeyframeTap::channel();
+        tokio::spawn(thumbnail::run(options, keyframes));
+        tap
+    });
+    let video_output = VideoOutput {
+        track: Arc::clone(&video_track),
+        transcoder: Arc::new(Mutex::new(None)),
+        viewers: pause_without_viewers.then_some(viewers),
+        keyframes,
+    };
+    let transcode_threads = capture_options.borrow().threads;
+
+    let audio_track = with_audio.then(|| {
+        Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                ..Default::default()
+            },
+            "audio".to_owned(),
+            "webcam".to_owned(),
+        ))
+    });
+
+    // Candidates of different address families on each side can never pair;
+    // with a TURN server to bridge them, such a failed connection is offered
+    // again relay-only
+    let relay_on_mismatch = config::enabled(
+        "relay-on-family-mismatch",
+        "RUSTWEBRTC_RELAY_ON_FAMILY_MISMATCH",
+    );
+    if relay_on_mismatch && !ice::has_relay(&ice_servers) {
+        println!("No TURN server in --ice-servers, address family mismatches will only be logged");
+    }
+    let relay_on_mismatch = relay_on_mismatch && ice::has_relay(&ice_servers);
+    let max_frame_size = config::parse_setting(
+        "max-frame-size",
+        "RUSTWEBRTC_MAX_FRAME_SIZE",
+        depacketizer::DEFAULT_MAX_FRAME_SIZE,
+    );
+    let record_dir: Option<std::path::PathBuf> =
+        config::setting("record", "RUSTWEBRTC_RECORD_DIR").map(Into::into);
+    // Images the viewer sends over the "images" data channel are saved here
+    let image_dir: Option<std::path::PathBuf> =
+        config::setting("image-dir", "RUSTWEBRTC_IMAGE_DIR").map(Into::into);
+    let max_image_bytes = config::parse_setting(
+        "max-image-bytes",
+        "RUSTWEBRTC_MAX_IMAGE_BYTES",
+        images::DEFAULT_MAX_IMAGE_BYTES,
+    );
+    let record_formats: Vec<recording::Format> =
+        config::setting("record-formats", "RUSTWEBRTC_RECORD_FORMATS")
+            .unwrap_or_else(|| "ivf".to_owned())
+            .split(',')
+            .filter_map(|format| match format.parse() {
+                Ok(format) => Some(format),
+                Err(e) => {
+                    println!("Ignoring recording format: {}", e);
+                    None
+                }
+            })
+            .collect();
+    if let Some(dir) = &record_dir {
+        let retention = recording::RetentionPolicy {
+            max_total_bytes: Some(config::parse_setting(
+                "record-max-bytes",
+                "RUSTWEBRTC_RECORD_MAX_BYTES",
+                0,
+            ))
+            .filter(|bytes| *bytes > 0),
+            max_age: Some(config::parse_setting(
+                "record-max-age-secs",
+                "RUSTWEBRTC_RECORD_MAX_AGE_SECS",
+                0,
+            ))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+            compress_after: Some(config::parse_setting(
+                "record-compress-after-secs",
+                "RUSTWEBRTC_RECORD_COMPRESS_AFTER_SECS",
+                0,
+            ))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        };
+        if !retention.is_unlimited() {
+            tokio::spawn(enforce_retention(dir.clone(), retention));
+        }
+    }
+    // Named for the audio-only fallback: video is shed so the audio track,
+    // when there is one, keeps the transport's bandwidth. Without `--audio`
+    // it just stops feeding a congested link.
+    let shed_video_on_loss = config::enabled("audio-fallback", "RUSTWEBRTC_AUDIO_FALLBACK");
+    let candidate_batch = Some(config::parse_setting(
+        "batch-candidates-ms",
+        "RUSTWEBRTC_BATCH_CANDIDATES_MS",
+        0,
+    ))
+    .filter(|ms| *ms > 0)
+    .map(Duration::from_millis);
+    // A peer that finishes ICE but never the DTLS handshake is dropped after
+    // this long instead of holding the connection half open
+    let dtls_timeout = Some(config::parse_setting(
+        "dtls-timeout-secs",
+        "RUSTWEBRTC_DTLS_TIMEOUT_SECS",
+        0,
+    ))
+    .filter(|secs| *secs > 0)
+    .map(Duration::from_secs);
+    let probe_window =
+        config::enabled("probe-bandwidth", "RUSTWEBRTC_PROBE_BANDWIDTH").then(|| {
+            Duration::from_secs(config::parse_setting(
+                "probe-window-secs",
+                "RUSTWEBRTC_PROBE_WINDOW_SECS",
+                3,
+            ))
+        });
+    let audio_os = if with_audio {
+        Some(
+            ffmpeg::TargetOs::current()
+                .ok_or_else(|| anyhow::anyhow!("audio is only supported on Linux and macOS"))?,
+        )
+    } else {
+        None
+    };
+    let capture_options = Arc::new(capture_options);
+
+    // A dropped signaling connection is retried with exponential backoff;
+    // unset, `--max-retries` keeps retrying forever
+    let max_retries = parse_max_retries(config::setting("max-retries", "RUSTWEBRTC_MAX_RETRIES"))?;
+    // Presented to a server started with `--auth-token`
+    let auth_token = config::setting("auth-token", "RUSTWEBRTC_AUTH_TOKEN");
+    let mut backoff = INITIAL_BACKOFF;
+    let mut failures = 0u32;
+    let shutdown = shutdown::signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        // Connect to signaling server
+        let connected = tokio::select! {
+            connected = connect_async(signaling_request(auth_token.as_deref())?) => connected,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let ws_stream = match connected {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                failures += 1;
+                if max_retries.is_some_and(|max| failures > max) {
+                    anyhow::bail!(
+                        "giving up on the signaling server after {} attempts: {}",
+                        failures,
+                        e
+                    );
+                }
+                println!(
+                    "Cannot reach the signaling server ({}), retrying in {:?}",
+                    e, backoff
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = &mut shutdown => return Ok(()),
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+        failures = 0;
+        backoff = INITIAL_BACKOFF;
+        let (write, mut read) = ws_stream.split();
+        let write: WsWrite = Arc::new(Mutex::new(write));
+        send_signal(
+            &write,
+            &SignalingMessage::Hello {
+                features: features.names(),
+            },
+        )
+        .await;
+        let (relay_requests, mut relay_rx) = mpsc::unbounded_channel();
+        let (keyframe_requests, keyframe_rx) = mpsc::unbounded_channel();
+        let mut keyframe_rx = Some(keyframe_rx);
+
+        // Every connection gets a fresh peer connection and session state.
+        // Trickle our own candidates unless the answer is carrying them
+        let session = Session {
+            write: Arc::clone(&write),
+            ice_servers: ice_servers.clone(),
+            audio_track: audio_track.clone(),
+            trickle: Arc::new(AtomicBool::new(!force_non_trickle)),
+            remote_ssrcs: Arc::new(Mutex::new(Vec::new())),
+            families: Arc::new(Mutex::new(ice::FamilyTracker::default())),
+            force_relay: Arc::new(AtomicBool::new(false)),
+            relay_requests: relay_on_mismatch.then_some(relay_requests),
+            ice_credentials: ice_credentials.clone(),
+            viewer_present: Arc::clone(&viewer_present),
+            keyframe_requests: capturing.then_some(keyframe_requests),
+            max_frame_size,
+            record_dir: record_dir.clone(),
+            record_formats: record_formats.clone(),
+            image_dir: image_dir.clone(),
+            max_image_bytes,
+            video_paused: Arc::new(AtomicBool::new(false)),
+            shed_video_on_loss,
+            candidate_batch,
+            probe_window,
+            dtls_timeout,
+            remote_id: Arc::new(std::sync::Mutex::new(None)),
+        };
+        let video_paused = Arc::clone(&session.video_paused);
+
+        let mut pc = create_peer_connection(&preferred_codecs, &video_track, &session).await?;
+
+        // Handle incoming messages
+        let write_clone = Arc::clone(&write);
+        let trickle = Arc::clone(&session.trickle);
+        let track = Arc::clone(&video_track);
+        let h264_track = Arc::clone(&h264_track);
+        let output = video_output.clone();
+        let preferred_codecs = preferred_codecs.clone();
+        let fingerprint_allowlist = fingerprint_allowlist.clone();
+        let disabled_rtcp_fb = disabled_rtcp_fb.clone();
+        let payload_type_pins = payload_type_pins.clone();
+        // A cap belongs to the server's view of the previous connection
+        set_bitrate_cap(&capture_options, None);
+        let capture_options = Arc::clone(&capture_options);
+        let (stop, mut stopped) = watch::channel(());
+        // Fired once the first offer/answer exchange on this connection
+        // completes, which is when media starts flowing again
+        let (negotiated_tx, mut negotiated) = oneshot::channel::<()>();
+        let mut signaling = tokio::spawn(async move {
+            let mut negotiated_tx = Some(negotiated_tx);
+            let mut last_ice: Option<IceExchange> = None;
+
+            let mut using_defaults = preferred_codecs.is_empty();
+            let mut transcoding = false;
+            let mut stats = tokio::time::interval(STATS_INTERVAL);
+            loop {
+                let msg = tokio::select! {
+                    msg = read.next() => msg,
+                    _ = stats.tick() => {
+                        let (bytes_sent, bytes_received) = media_bytes(&pc).await;
+                        let report = SignalingMessage::Stats {
+                            bytes_sent,
+                            bytes_received,
+                        };
+                        if let Ok(json) = serde_json::to_string(&report) {
+                            if let Err(e) = write_clone.lock().await.send(Message::Text(json)).await {
+                                println!("Error sending stats: {}", e);
+                            }
+                        }
+                        continue;
+                    }
+                    Some(()) = relay_rx.recv() => {
+                        if session.force_relay.swap(true, Ordering::SeqCst) {
+                            continue;
+                        }
+                        println!("Offering a relay-only connection to bridge the address families");
+                        close_peer_connection(Arc::clone(&pc)).await;
+                        let codecs: &[String] = if using_defaults { &[] } else { &preferred_codecs };
+                        let video = if transcoding { &h264_track } else { &track };
+                        let offered = match create_peer_connection(codecs, video, &session).await {
+                            Ok(next) => {
+                                pc = next;
+                                session.families.lock().await.clear_remote();
+                                send_offer(&pc, &session).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = offered {
+                            println!("Error offering a relay-only connection: {}", e);
+                        }
+                        continue;
+                    }
+                    _ = stopped.changed() => None,
+                };
+                let Some(msg) = msg else { break };
+                if let Ok(msg) = msg {
+                    let text = msg.to_string();
+                    if let Ok(mut signal) = serde_json::from_str::<SignalingMessage>(&text) {
+                        if let Some(len) = oversized_sdp(&signal, max_sdp_bytes) {
+                            println!(
+                                "Ignoring {}-byte SDP, over the {}-byte limit",
+                                len, max_sdp_bytes
+                            );
+                            continue;
+                        }
+                        if let Err(e) = expand_sdp(&mut signal, max_sdp_bytes) {
+                            println!("Ignoring undecodable compressed SDP: {}", e);
+                            report_error(&write_clone, ERROR_BAD_SDP, e).await;
+                            continue;
+                        }
+                        if let SignalingMessage::Offer { from, .. }
+                        | SignalingMessage::Answer { from, .. }
+                        | SignalingMessage::Candidate { from, .. }
+                        | SignalingMessage::Candidates { from, .. } = &signal
+                        {
+                            session.learn_remote(from.clone(), true);
+                        }
+                        match signal {
+                            SignalingMessage::Offer { sdp, .. } => {
+                                let screened = screen_offer(
+                                    &sdp,
+                                    max_tracks_per_offer,
+                                    &fingerprint_allowlist,
+                                );
+                                if let Err(rejection) = screened {
+                                    println!("Rejecting offer: {}", rejection);
+                                    report_error(&write_clone, rejection.code(), &rejection).await;
+                                    continue;
+                                }
+                                {
+                                    let mut families = session.families.lock().await;
+                                    families.clear_remote();
+                                    families.add_remote_sdp(&sdp);
+                                }
+                                let offered_h264 = sdp::fmtp_for(&sdp, "H264");
+                                let ours = codecs::h264_fmtp();
+                                if !offered_h264.is_empty()
+                                    && !offered_h264
+                                        .iter()
+                                        .any(|theirs| codecs::h264_fmtp_compatible(&ours, theirs))
+                                {
+                                    println!(
+                                        "Offered H.264 profiles {:?} are incompatible with ours ({}), H.264 will not negotiate",
+                                        offered_h264, ours
+                                    );
+                                }
+                                let h264_only = transcode_h264
+                                    && sdp::fmtp_for(&sdp, "VP8").is_empty()
+                                    && !sdp::fmtp_for(&sdp, "H264").is_empty();
+                                if h264_only != transcoding {
+                                    // The track is fixed per peer connection, so
+                                    // switching codecs means a new one. The default
+                                    // codec set always includes H.264.
+                                    close_peer_connection(Arc::clone(&pc)).await;
+                                    let (codecs, video): (&[String], _) = if h264_only {
+                                        (&[], &h264_track)
+                                    } else {
+                                        (&preferred_codecs, &track)
+                                    };
+                                    match create_peer_connection(codecs, video, &session).await {
+                                        Ok(next) => pc = next,
+                                        Err(e) => {
+                                            println!("Error creating peer connection: {}", e);
+                                            report_error(&write_clone, ERROR_INTERNAL, e).await;
+                                            continue;
+                                        }
+                                    }
+                                    let transcoder = if h264_only {
+                                        println!("Viewer offered only H.264, transcoding VP8");
+                                        match Transcoder::spawn(
+                                            transcode_threads,
+                                            Arc::clone(&h264_track),
+                                        ) {
+                                            Ok(transcoder) => Some(transcoder),
+                                            Err(e) => {
+                                                println!("Error starting H.264 transcoder: {}", e);
+                                                None
+                                            }
+                                        }
+                                    } else {
+                                        None
+                                    };
+                                    *output.transcoder.lock().await = transcoder;
+                                    transcoding = h264_only;
+                                    using_defaults = h264_only || preferred_codecs.is_empty();
+                                }
+                                trickle.store(
+                                    !force_non_trickle && sdp::supports_trickle(&sdp),
+                                    Ordering::SeqCst,
+                                );
+                                let options = AnswerOptions {
+                                    trickle: trickle.load(Ordering::SeqCst),
+                                    disabled_rtcp_fb: disabled_rtcp_fb.clone(),
+                                    payload_type_pins: payload_type_pins.clone(),
+                                };
+                                let offer_ice = sdp::ice_credentials(&sdp);
+                                let mut answer = answer_offer(&pc, sdp.clone(), &options).await;
+                                if codec_fallback && !using_defaults && !has_video(&answer) {
+                                    println!(
+                                        "No common codec among {:?}, retrying with the default codec set",
+                                        preferred_codecs
+                                    );
+                                    using_defaults = true;
+                                    answer = answer_with_default_codecs(
+                                        &mut pc, sdp, &options, &track, &session,
+                                    )
+                                    .await;
+                                }
+                                // A bad offer is dropped; the remote can send another
+                                let sdp = match answer {
+                                    Ok(sdp) => sdp,
+                                    Err(e) => {
+                                        println!("Error answering offer: {}", e);
+                                        report_error(&write_clone, ERROR_BAD_SDP, e).await;
+                                        continue;
+                                    }
+                                };
+                                if debug_ice {
+                                    let exchange = IceExchange {
+                                        offer: offer_ice,
+                                        answer: sdp::ice_credentials(&sdp),
+                                    };
+                                    exchange.log(last_ice.as_ref());
+                                    last_ice = Some(exchange);
+                                }
+
+                                let encoded = compress_sdp
+                                    .then(|| signaling::compress_sdp(&sdp))
+                                    .filter(|encoded| encoded.len() < sdp.len());
+                                let (sdp, compressed) = match encoded {
+                                    Some(encoded) => (encoded, true),
+                                    None => (sdp, false),
+                                };
+                                let msg = SignalingMessage::Answer {
+                                    sdp,
+                                    compressed,
+                                    target: session.remote_id(),
+                                    from: None,
+                                };
+                                let json = match serde_json::to_string(&msg) {
+                                    Ok(json) => json,
+                                    Err(e) => {
+                                        println!("Error serializing answer: {}", e);
+                                        continue;
+                                    }
+                                };
+                                let mut write = write_clone.lock().await;
+                                if let Err(e) = write.send(Message::Text(json)).await {
+                                    println!("Error sending answer, closing: {}", e);
+                                    break;
+                                }
+                                if let Some(tx) = negotiated_tx.take() {
+                                    let _ = tx.send(());
+                                }
+                            }
+                            SignalingMessage::IceState { .. } | SignalingMessage::Stats { .. } => {}
+                            SignalingMessage::MediaWarning { direction } => {
+                                println!(
+                                    "Server reports our {:?} media stopped while the other direction flows, likely a NAT or firewall",
+                                    direction
+                                );
+                            }
+                            SignalingMessage::BitrateCap { max_bitrate } => {
+                                if !capturing {
+                                    println!("Ignoring bitrate cap, only --capture can re-encode");
+                                    continue;
+                                }
+                                set_bitrate_cap(&capture_options, max_bitrate);
+                            }
+                            SignalingMessage::Negotiated {
+                                video_codec,
+                                audio_codec,
+                            } => {
+                                println!(
+                                    "Negotiated video {}, audio {}",
+                                    video_codec.as_deref().unwrap_or("none"),
+                                    audio_codec.as_deref().unwrap_or("none")
+                                );
+                            }
+                            SignalingMessage::BitrateRequest { max_bitrate } => {
+                                request_remote_bitrate(&pc, &session, max_bitrate).await;
+                            }
+                            SignalingMessage::StreamRequest {
+                                width,
+                                height,
+                                fps,
+                                bitrate,
+                            } => {
+                                if !capturing {
+                                    println!(
+                                        "Ignoring stream request, only --capture can re-encode"
+                                    );
+                                    continue;
+                                }
+                                capture_options.send_modify(|options| {
+                                    options.apply_request(
+                                        width,
+                                        height,
+                                        fps,
+                                        bitrate,
+                                        &encoder_limits,
+                                    );
+                                    println!(
+                                        "Stream request applied: size {:?}, fps {:?}, bitrate {}",
+                                        options.size, options.fps, options.bitrate
+                                    );
+                                });
+                            }
+                            SignalingMessage::Answer { sdp, .. } => {
+                                session.families.lock().await.add_remote_sdp(&sdp);
+                                let result = match RTCSessionDescription::try_from(
+                                    SessionDescriptionInit::answer(sdp),
+                                ) {
+                                    Ok(answer) => {
+                                        pc.set_remote_description(answer).await.map_err(Into::into)
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                match result {
+                                    Ok(()) => {
+                                        if let Some(tx) = negotiated_tx.take() {
+                                            let _ = tx.send(());
+                                        }
+                                    }
+                                    Err(e) => {
+                                        println!("Error applying remote answer: {}", e);
+                                        report_error(&write_clone, ERROR_BAD_SDP, e).await;
+                                    }
+                                }
+                            }
+                            SignalingMessage::Candidate {
+                                candidate,
+                                sdp_mid,
+                                sdp_mline_index,
+                                ..
+                            } => {
+                                add_remote_candidate(
+                                    &pc,
+                                    &session,
+                                    CandidateInit {
+                                        candidate,
+                                        sdp_mid,
+                                        sdp_mline_index,
+                                    },
+                                )
+                                .await;
+                            }
+                            SignalingMessage::Candidates { list, .. } => {
+                                for candidate in list {
+                                    add_remote_candidate(&pc, &session, candidate).await;
+                                }
+                            }
+                            SignalingMessage::Error { code, detail } => {
+                                println!("Remote reported {}: {}", code, detail);
+                                if FATAL_ERRORS.contains(&code.as_str()) {
+                                    return Some(format!("{}: {}", code, detail));
+                                }
+                            }
+                            SignalingMessage::Welcome { your_id, peers } => {
+                                println!("Connected as {}", your_id);
+                                session.learn_remote(peers.last().cloned(), false);
+                            }
+                            SignalingMessage::PeerJoined { id } => {
+                                session.learn_remote(Some(id), false);
+                            }
+                            SignalingMessage::Hello { features } => {
+                                println!("Server honours features: {}", features.join(", "));
+                            }
+                            SignalingMessage::Bye => {
+                                println!("Signaling server is shutting down");
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            None
+        });
+
+        let mut streaming: Option<tokio::task::JoinHandle<Result<()>>> = None;
+        let mut audio: Option<tokio::task::JoinHandle<()>> = None;
+        // Set when the remote reported an error retrying can't get past
+        let mut fatal = None;
+        let shutting_down = loop {
+            tokio::select! {
+                Ok(()) = &mut negotiated, if streaming.is_none() => {
+                    if let (Some(os), Some(track), None) = (audio_os, audio_track.clone(), &av_source) {
+                        let input = audio_input.clone();
+                        audio = Some(tokio::spawn(async move {
+                            if let Err(e) = stream_audio(os, input, track).await {
+                                println!("Error streaming audio: {}", e);
+                            }
+                        }));
+                    }
+                    println!("Starting video stream...");
+                    let output = video_output.clone();
+                    let options = capture_options_rx.clone();
+                    let paused = Arc::clone(&video_paused);
+                    let keyframes = keyframe_rx.take().expect("media starts once per connection");
+                    let av = av_source.clone().zip(audio_track.clone());
+                    streaming = Some(tokio::spawn(async move {
+                        if capturing {
+                            stream_capture(options, keyframes, output, paused).await
+                        } else if let Some((path, audio)) = av {
+                            let options = options.borrow().clone();
+                            stream_av_file(&path, &options, output, audio, paused, looping).await
+                        } else {
+                            watchand_stream_video(
+                                "./ivf_files",
+                                source_format,
+                                output,
+                                paused,
+                                looping,
+                            )
+                                .await
+                        }
+                    }));
+                }
+                Some(result) = async { Some(streaming.as_mut()?.await) }, if streaming.is_some() => {
+                    let _ = stop.send(());
+                    let _ = (&mut signaling).await;
+                    result??;
+                    break true;
+                }
+                result = &mut signaling => {
+                    fatal = result.ok().flatten();
+                    break false;
+                }
+                _ = &mut shutdown => {
+                    println!("Shutting down, closing the peer connection");
+                    let _ = stop.send(());
+                    let _ = (&mut signaling).await;
+                    break true;
+                }
+            }
+        };
+        // Media is restarted only once the next connection negotiates
+        if let Some(task) = streaming {
+            task.abort();
+        }
+        if let Some(task) = audio {
+            task.abort();
+        }
+        *video_output.transcoder.lock().await = None;
+        if shutting_down {
+            return Ok(());
+        }
+        if let Some(error) = fatal {
+            anyhow::bail!("giving up after a fatal signaling error, {}", error);
+        }
+        println!("Lost the signaling connection, reconnecting");
+    }
+}
+
+// ICE credentials of one offer/answer exchange, as (ufrag, pwd)
+struct IceExchange {
+    offer: Option<(String, String)>,
+    answer: Option<(String, String)>,
+}
+
+impl IceExchange {
+    // A new remote ufrag means the offer was an ICE restart, and our answer
+    // should then carry new credentials too
+    fn log(&self, previous: Option<&IceExchange>) {
+        let ufrag =
+            |creds: &Option<(String, String)>| creds.as_ref().map(|(ufrag, _)| ufrag.clone());
+        println!(
+            "ICE credentials: offer {:?}, answer {:?}",
+            self.offer, self.answer
+        );
+        let Some(previous) = previous else { return };
+        if ufrag(&self.offer) == ufrag(&previous.offer) {
+            return;
+        }
+        if ufrag(&self.answer) == ufrag(&previous.answer) {
+            println!(
+                "ICE restart offered but our ufrag stayed {:?}",
+                ufrag(&self.answer)
+            );
+        } else {
+            println!(
+                "ICE restart: our ufrag changed from {:?} to {:?}",
+                ufrag(&previous.answer),
+                ufrag(&self.answer)
+            );
+        }
+    }
+}
+
+// Where the source's frames go: the video track, and also the H.264
+// transcoder while a viewer that only offered H.264 is connected to a VP8
+// source
+#[derive(Clone)]
+struct VideoOutput {
+    track: Arc<TrackLocalStaticSample>,
+    transcoder: Arc<Mutex<Option<Transcoder>>>,
+    // Whether a peer connection is connected; unset when video flows
+    // regardless
+    viewers: Option<watch::Receiver<bool>>,
+    // Feeds the thumbnail task when thumbnails are on
+    keyframes: Option<thumbnail::KeyframeTap>,
+}
+
+impl VideoOutput {
+    fn watched(&self) -> bool {
+        self.viewers
+            .as_ref()
+            .is_none_or(|viewers| *viewers.borrow())
+    }
+
+    async fn write_sample(&self, sample: &Sample) -> Result<()> {
+        if let Some(keyframes) = &self.keyframes {
+            keyframes.offer(&sample.data);
+        }
+        if !self.watched() {
+            return Ok(());
+        }
+        let mut transcoder = self.transcoder.lock().await;
+        if let Some(active) = transcoder.as_ref() {
+            if let Err(e) = active.write_frame(&sample.data, sample.duration).await {
+                println!("Stopped transcoding to H.264: {}", e);
+                *transcoder = None;
+            }
+        }
+        drop(transcoder);
+        self.track.write_sample(sample).await?;
+        Ok(())
+    }
+}
+
+// State shared between the signaling loop and the callbacks of whichever peer
+// connection is current
+#[derive(Clone)]
+struct Session {
+    write: WsWrite,
+    ice_servers: Vec<RTCIceServer>,
+    // Opus track added to every peer connection alongside the video
+    audio_track: Option<Arc<TrackLocalStaticSample>>,
+    trickle: Arc<AtomicBool>,
+    // SSRCs of the tracks the remote peer publishes to us
+    remote_ssrcs: Arc<Mutex<Vec<u32>>>,
+    // Cap on a reassembled inbound frame before it is dropped
+    max_frame_size: usize,
+    // Inbound VP8 tracks are recorded here, once per configured format
+    record_dir: Option<std::path::PathBuf>,
+    record_formats: Vec<recording::Format>,
+    // Images received over the data channel are saved here; unset ignores
+    // the channel
+    image_dir: Option<std::path::PathBuf>,
+    max_image_bytes: usize,
+    // Set while receiver reports show sustained severe loss; the video writers
+    // skip samples until loss recovers
+    video_paused: Arc<AtomicBool>,
+    shed_video_on_loss: bool,
+    // How long to collect bandwidth feedback after media starts, if probing
+    probe_window: Option<Duration>,
+    // Time allowed between ICE connecting and the connection reaching
+    // `connected`, if limited
+    dtls_timeout: Option<Duration>,
+    // Local candidates gathered within this window are sent as one message;
+    // unset sends each candidate as soon as it is found
+    candidate_batch: Option<Duration>,
+    // Address families of the current connection's candidates on both sides
+    families: Arc<Mutex<ice::FamilyTracker>>,
+    // Once set, peer connections only use relay candidates
+    force_relay: Arc<AtomicBool>,
+    // Asks the signaling loop for a relay-only connection after ICE failed
+    // on an address family mismatch; unset when no TURN server is configured
+    relay_requests: Option<mpsc::UnboundedSender<()>>,
+    // Forwards the receiver's PLI/FIR keyframe requests to the capture;
+    // unset for file sources, which can't produce a keyframe on demand
+    keyframe_requests: Option<mpsc::UnboundedSender<()>>,
+    // Fixed local ufrag/pwd instead of random ones, for debugging restarts
+    ice_credentials: Option<(String, String)>,
+    // Tracks whether the current peer connection is connected
+    viewer_present: Arc<watch::Sender<bool>>,
+    // Id of the peer we negotiate with, which our offers, answers and
+    // candidates are addressed to
+    remote_id: Arc<std::sync::Mutex<Option<String>>>,
+}
+
+impl Session {
+    fn remote_id(&self) -> Option<String> {
+        self.remote_id.lock().unwrap().clone()
+    }
+
+    // Whoever last sent us negotiation is the peer we answer. Otherwise the
+    // first peer we hear of is kept, so a newcomer can't redirect a call.
+    fn learn_remote(&self, id: Option<String>, authoritative: bool) {
+        let Some(id) = id else { return };
+        let mut remote = self.remote_id.lock().unwrap();
+        if authoritative || remote.is_none() {
+            *remote = Some(id);
+        }
+    }
+}
+
+async fn create_peer_connection(
+    codecs: &[String],
+    video_track: &Arc<TrackLocalStaticSample>,
+    session: &Session,
+) -> Result<Arc<RTCPeerConnection>> {
     // Create MediaEngine
     let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
+    codecs::register_codecs(&mut m, codecs)?;
 
     // Create a registry for interceptors
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m)?;
 
+    let mut settings = SettingEngine::default();
+    if let Some((ufrag, pwd)) = &session.ice_credentials {
+        settings.set_ice_credentials(ufrag.clone(), pwd.clone());
+    }
+
     // Create the API object
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
+        .with_setting_engine(settings)
         .build();
 
     // Prepare the configuration
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
+        ice_servers: session.ice_servers.clone(),
+        ice_transport_policy: if session.force_relay.load(Ordering::SeqCst) {
+            RTCIceTransportPolicy::Relay
+        } else {
+            RTCIceTransportPolicy::All
+        },
         ..Default::default()
     };
+    session.families.lock().await.clear_local();
 
     // Create a new RTCPeerConnection
     let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
-    // Create video track
-    let video_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_VP8.to_owned(),
-            ..Default::default()
-        },
-        "video".to_owned(),
-        "webcam".to_owned(),
-    ));
-
     // Add track to peer connection
     let rtp_sender = peer_connection
-        .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
+        .add_track(Arc::clone(video_track) as Arc<dyn TrackLocal + Send + Sync>)
         .await?;
 
-    // Handle RTCP packets
+    if let Some(audio_track) = &session.audio_track {
+        let audio_sender = peer_connection
+            .add_track(Arc::clone(audio_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        // Nothing is done with audio feedback, but it has to be read for the
+        // interceptors to process it
+        tokio::spawn(async move { while audio_sender.read_rtcp().await.is_ok() {} });
+    }
+
+    // Handle RTCP packets, passing on keyframe requests and watching receiver
+    // reports for sustained loss and REMB feedback for the warmup bandwidth
+    // estimate
+    let video_paused = Arc::clone(&session.video_paused);
+    let shed_video_on_loss = session.shed_video_on_loss;
+    let mut probe = session.probe_window.map(BandwidthProbe::new);
+    let keyframe_requests = session.keyframe_requests.clone();
     tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        let mut monitor = LossMonitor::new();
+        let mut keyframe_requests_seen = 0u64;
+        video_paused.store(false, Ordering::SeqCst);
+        while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+            let wants_keyframe = packets.iter().any(|packet| {
+                packet.as_any().is::<PictureLossIndication>()
+                    || packet.as_any().is::<FullIntraRequest>()
+            });
+            if wants_keyframe {
+                keyframe_requests_seen += 1;
+                match &keyframe_requests {
+                    Some(requests) => {
+                        println!(
+                            "Receiver asked for a keyframe (PLI/FIR #{})",
+                            keyframe_requests_seen
+                        );
+                        let _ = requests.send(());
+                    }
+                    // The file is sent as encoded, so the viewer waits for
+                    // its next keyframe
+                    None => println!(
+                        "Receiver asked for a keyframe (PLI/FIR #{}), which a file source can't produce",
+                        keyframe_requests_seen
+                    ),
+                }
+            }
+            if let Some(active) = probe.as_mut() {
+                let rembs = packets.iter().filter_map(|packet| {
+                    packet
+                        .as_any()
+                        .downcast_ref::<ReceiverEstimatedMaximumBitrate>()
+                        .map(|remb| remb.bitrate as u64)
+                });
+                match active.observe(std::time::Instant::now(), rembs) {
+                    ProbeResult::Probing => {}
+                    ProbeResult::Done(Some(bps)) => {
+                        println!("Bandwidth probe estimates {} bps available", bps);
+                        probe = None;
+                    }
+                    ProbeResult::Done(None) => {
+                        println!("Bandwidth probe got no REMB feedback from the peer");
+                        probe = None;
+                    }
+                }
+            }
+            if !shed_video_on_loss {
+                continue;
+            }
+            let reports = packets.iter().filter_map(|packet| {
+                packet
+                    .as_any()
+                    .downcast_ref::<ReceiverReport>()
+                    .map(|rr| rr.reports.iter())
+            });
+            for report in reports.flatten() {
+                if let Some(paused) = monitor.observe(report.fraction_lost) {
+                    println!(
+                        "{} video after sustained {} loss",
+                        if paused { "Pausing" } else { "Resuming" },
+                        if paused { "severe" } else { "low" }
+                    );
+                    video_paused.store(paused, Ordering::SeqCst);
+                }
+            }
+        }
     });
 
-    // Connect to signaling server
-    let (ws_stream, _) = connect_async("ws://localhost:3030/signaling").await?;
-    let (mut write, mut read) = ws_stream.split();
-    let write = Arc::new(Mutex::new(write));
-    let pc = Arc::clone(&peer_connection);
-
     // Handle connection state changes
+    let viewer_present = Arc::clone(&session.viewer_present);
     peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
         println!("Connection State has changed: {s}");
+        match s {
+            RTCPeerConnectionState::Connected => {
+                viewer_present.send_replace(true);
+            }
+            RTCPeerConnectionState::Disconnected
+            | RTCPeerConnectionState::Failed
+            | RTCPeerConnectionState::Closed => {
+                viewer_present.send_replace(false);
+            }
+            _ => {}
+        }
         Box::pin(async {})
     }));
 
-    // Handle incoming messages
-    let write_clone = Arc::clone(&write);
-    tokio::spawn(async move {
-        while let Some(msg) = read.next().await {
-            if let Ok(msg) = msg {
-                let text = msg.to_string();
-                if let Ok(signal) = serde_json::from_str::<SignalingMessage>(&text) {
-                    match signal {
-                        SignalingMessage::Offer { sdp } => {
-                            let offer = RTCSessionDescription::offer(sdp).unwrap();
-                            pc.set_remote_description(offer).await.unwrap();
-
-                            let answer = pc.create_answer(None).await.unwrap();
-                            pc.set_local_description(answer.clone()).await.unwrap();
-
-                            let msg = SignalingMessage::Answer { sdp: answer.sdp };
-                            let mut write = write_clone.lock().await;
-                            write
-                                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
-                                .await
-                                .unwrap();
-                        }
-                        SignalingMessage::Answer { sdp } => {
-                            let answer = RTCSessionDescription::answer(sdp).unwrap();
-                            pc.set_remote_description(answer).await.unwrap();
-                        }
-                        SignalingMessage::Candidate {
-                            candidate,
-                            sdp_mid,
-                            sdp_mline_index,
-                        } => {
-                            let candidate = RTCIceCandidateInit {
-                                candidate,
-                                sdp_mid,
-                                sdp_mline_index: sdp_mline_index.map(|x| x as u16),
-                                username_fragment: None,
-                            };
-                            if let Err(e) = pc.add_ice_candidate(candidate).await {
-                                println!("Error adding ICE candidate: {}", e);
-                            }
+    // Report ICE state so the server can tell an engaged session from a dead one
+    let state_write = Arc::clone(&session.write);
+    let state_families = Arc::clone(&session.families);
+    let relay_requests = session.relay_requests.clone();
+    let dtls_timeout = session.dtls_timeout;
+    let dtls_timer = Arc::new(AtomicBool::new(false));
+    let handshaking = Arc::downgrade(&peer_connection);
+    peer_connection.on_ice_connection_state_change(Box::new(move |s: RTCIceConnectionState| {
+        let write = Arc::clone(&state_write);
+        let families = Arc::clone(&state_families);
+        let relay_requests = relay_requests.clone();
+        if let Some(timeout) = dtls_timeout.filter(|_| {
+            matches!(
+                s,
+                RTCIceConnectionState::Connected | RTCIceConnectionState::Completed
+            ) && !dtls_timer.swap(true, Ordering::SeqCst)
+        }) {
+            tokio::spawn(close_unless_connected(handshaking.clone(), timeout));
+        }
+        Box::pin(async move {
+            if s == RTCIceConnectionState::Failed {
+                if let Some((local, remote)) = families.lock().await.mismatch() {
+                    println!(
+                        "ICE failed on an address family mismatch: we gathered {:?} candidates, the remote offered {:?}",
+                        local, remote
+                    );
+                    if let Some(requests) = relay_requests {
+                        let _ = requests.send(());
+                    }
+                }
+            }
+            let msg = SignalingMessage::IceState {
+                state: s.to_string(),
+            };
+            send_signal(&write, &msg).await;
+        })
+    }));
+
+    session.remote_ssrcs.lock().await.clear();
+    let remote_ssrcs = Arc::clone(&session.remote_ssrcs);
+    let max_frame_size = session.max_frame_size;
+    let record_dir = session.record_dir.clone();
+    let record_formats = session.record_formats.clone();
+    let weak_pc = Arc::downgrade(&peer_connection);
+    peer_connection.on_track(Box::new(move |track, _, _| {
+        let remote_ssrcs = Arc::clone(&remote_ssrcs);
+        let weak_pc = weak_pc.clone();
+        let record_dir = record_dir.clone();
+        let record_formats = record_formats.clone();
+        Box::pin(async move {
+            println!(
+                "Remote {} track started (ssrc {})",
+                track.kind(),
+                track.ssrc()
+            );
+            remote_ssrcs.lock().await.push(track.ssrc());
+            let mime_type = track.codec().capability.mime_type.to_ascii_lowercase();
+            let h264 = mime_type == MIME_TYPE_H264.to_ascii_lowercase();
+            if !h264 && mime_type != MIME_TYPE_VP8.to_ascii_lowercase() {
+                tokio::spawn(async move { while track.read_rtp().await.is_ok() {} });
+                return;
+            }
+            let recording = record_dir.and_then(|dir| {
+                let name = format!(
+                    "{}{}_{}",
+                    recording::FILE_PREFIX,
+                    track.ssrc(),
+                    uuid::Uuid::new_v4().simple()
+                );
+                let created = if h264 {
+                    Recording::create_h264(&dir, &name)
+                } else {
+                    Recording::create(&dir, &name, &record_formats)
+                };
+                match created {
+                    Ok(recording) => {
+                        for path in recording.paths() {
+                            println!("Recording ssrc {} to {}", track.ssrc(), path.display());
                         }
+                        Some(recording)
+                    }
+                    Err(e) => {
+                        println!("Error starting recording: {}", e);
+                        None
                     }
                 }
+            });
+            if h264 {
+                let assembler = FrameAssembler::new(H264Packet::default(), max_frame_size);
+                tokio::spawn(read_track(
+                    track,
+                    assembler,
+                    recording,
+                    weak_pc,
+                    max_frame_size,
+                ));
+            } else {
+                let assembler = FrameAssembler::new(Vp8Packet::default(), max_frame_size);
+                tokio::spawn(read_track(
+                    track,
+                    assembler,
+                    recording,
+                    weak_pc,
+                    max_frame_size,
+                ));
             }
-        }
-    });
+        })
+    }));
 
-    println!("Starting video stream...");
-    watchand_stream_video("./ivf_files", video_track).await?;
+    if let Some(dir) = session.image_dir.clone() {
+        let max_image_bytes = session.max_image_bytes;
+        peer_connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            if channel.label() == images::CHANNEL_LABEL {
+                receive_images(&channel, dir.clone(), max_image_bytes);
+            }
+            Box::pin(async {})
+        }));
+    }
 
-    Ok(())
+    let candidate_write = Arc::clone(&session.write);
+    let candidate_trickle = Arc::clone(&session.trickle);
+    let candidate_families = Arc::clone(&session.families);
+    let candidate_remote = Arc::clone(&session.remote_id);
+    let batch_window = session.candidate_batch;
+    let pending = Arc::new(Mutex::new(Vec::new()));
+    peer_connection.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+        let write = Arc::clone(&candidate_write);
+        let trickle = Arc::clone(&candidate_trickle);
+        let families = Arc::clone(&candidate_families);
+        let remote = Arc::clone(&candidate_remote);
+        let pending = Arc::clone(&pending);
+        Box::pin(async move {
+            let Some(c) = c else { return };
+            let init = match c.to_json() {
+                Ok(init) => CandidateInit {
+                    candidate: init.candidate,
+                    sdp_mid: init.sdp_mid,
+                    sdp_mline_index: init.sdp_mline_index.map(u32::from),
+                },
+                Err(e) => {
+                    println!("Error serializing ICE candidate: {}", e);
+                    return;
+                }
+            };
+            families.lock().await.add_local(&init.candidate);
+            if !trickle.load(Ordering::SeqCst) {
+                return;
+            }
+            let Some(window) = batch_window else {
+                let msg = SignalingMessage::Candidate {
+                    candidate: init.candidate,
+                    sdp_mid: init.sdp_mid,
+                    sdp_mline_index: init.sdp_mline_index,
+                    target: remote.lock().unwrap().clone(),
+                    from: None,
+                };
+                send_signal(&write, &msg).await;
+                return;
+            };
+            // The first candidate of a batch starts the window; everything
+            // gathered before it closes goes out together
+            let mut batch = pending.lock().await;
+            batch.push(init);
+            if batch.len() == 1 {
+                let pending = Arc::clone(&pending);
+                tokio::spawn(async move {
+                    tokio::time::sleep(window).await;
+                    let list = std::mem::take(&mut *pending.lock().await);
+                    let target = remote.lock().unwrap().clone();
+                    let msg = SignalingMessage::Candidates {
+                        list,
+                        target,
+                        from: None,
+                    };
+                    send_signal(&write, &msg).await;
+                });
+            }
+        })
+    }));
+
+    Ok(peer_connection)
 }
 
-async fn write_video_to_track(path: &str, track: Arc<TrackLocalStaticSample>) -> Result<()> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let (mut ivf, header) = IVFReader::new(reader)?;
+// Reassembles the images announced on the channel and saves each one to
+// `dir` as it completes
+fn receive_images(channel: &RTCDataChannel, dir: std::path::PathBuf, max_image_bytes: usize) {
+    println!("Receiving images on data channel {}", channel.label());
+    let assembler = Arc::new(std::sync::Mutex::new(ImageAssembler::new(max_image_bytes)));
+    let seq = Arc::new(AtomicU64::new(0));
+    channel.on_message(Box::new(move |message: DataChannelMessage| {
+        let received = {
+            let mut assembler = assembler.lock().unwrap();
+            if message.is_string {
+                let text = String::from_utf8_lossy(&message.data);
+                assembler.announce(&text).map(|()| None)
+            } else {
+                assembler.push(&message.data)
+            }
+        };
+        let image = match received {
+            Ok(image) => image,
+            Err(e) => {
+                println!("Dropping image: {}", e);
+                None
+            }
+        };
+        let dir = dir.clone();
+        let seq = Arc::clone(&seq);
+        Box::pin(async move {
+            let Some(image) = image else { return };
+            let name = format!(
+                "image-{}-{}.{}",
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis(),
+                seq.fetch_add(1, Ordering::Relaxed),
+                image.extension()
+            );
+            let path = dir.join(name);
+            let saved = async {
+                tokio::fs::create_dir_all(&dir).await?;
+                tokio::fs::write(&path, &image.bytes).await
+            };
+            match saved.await {
+                Ok(()) => println!(
+                    "Saved {} byte image to {}",
+                    image.bytes.len(),
+                    path.display()
+                ),
+                Err(e) => println!("Error saving image to {}: {}", path.display(), e),
+            }
+        })
+    }));
+}
 
-    let sleep_time = Duration::from_millis(
-        ((1000 * header.timebase_numerator) / header.timebase_denominator) as u64,
-    );
-    let mut ticker = tokio::time::interval(sleep_time);
+// Makes this side the offerer, for renegotiations the streamer starts itself.
+// Without trickle the offer waits for gathering so it carries every candidate.
+async fn send_offer(pc: &RTCPeerConnection, session: &Session) -> Result<()> {
+    let offer = pc.create_offer(None).await?;
+    let sdp = if session.trickle.load(Ordering::SeqCst) {
+        pc.set_local_description(offer.clone()).await?;
+        offer.sdp
+    } else {
+        let mut gather_complete = pc.gathering_complete_promise().await;
+        pc.set_local_description(offer).await?;
+        let _ = gather_complete.recv().await;
+        pc.local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("local description missing after gathering"))?
+            .sdp
+    };
+    send_signal(
+        &session.write,
+        &SignalingMessage::Offer {
+            sdp,
+            compressed: false,
+            target: session.remote_id(),
+            from: None,
+        },
+    )
+    .await;
+    Ok(())
+}
 
+// Sweeps the recording directory for the life of the process
+async fn enforce_retention(dir: std::path::PathBuf, policy: recording::RetentionPolicy) {
+    let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
     loop {
-        let frame = ivf.parse_next_frame()?.0;
-        track
-            .write_sample(&Sample {
-                data: frame.freeze(),
-                duration: Duration::from_secs(1),
-                ..Default::default()
-            })
-            .await?;
         ticker.tick().await;
+        let (dir, policy) = (dir.clone(), policy.clone());
+        let swept = tokio::task::spawn_blocking(move || {
+            if !dir.exists() {
+                return Ok(recording::Sweep::default());
+            }
+            recording::enforce_retention(&dir, &policy, std::time::SystemTime::now())
+        })
+        .await;
+        match swept {
+            Ok(Ok(sweep)) => {
+                for path in &sweep.compressed {
+                    println!("Compressed old recording to {}", path.display());
+                }
+                for path in &sweep.deleted {
+                    println!(
+                        "Deleted recording {} under the retention policy",
+                        path.display()
+                    );
+                }
+                if !sweep.deleted.is_empty() || !sweep.compressed.is_empty() {
+                    println!("Recordings now take {} bytes", sweep.total_bytes);
+                }
+            }
+            Ok(Err(e)) => println!("Recording retention sweep failed: {}", e),
+            Err(e) => println!("Recording retention sweep panicked: {}", e),
+        }
     }
 }
 
-//File watcher
-async fn watchand_stream_video(directory: &str, track: Arc<TrackLocalStaticSample>) -> Result<()> {
-    // Create a channel for file events
-    let (tx, mut rx) = mpsc::channel(100);
-
-    // Create an async file watcher
-    let mut watcher = RecommendedWatcher::new(
-        move |res| {
-            if let Ok(event) = res {
-                tx.blocking_send(event).expect("Failed to send event");
-            }
-        },
-        Config::default(),
-    )?;
+async fn send_signal(write: &WsWrite, msg: &SignalingMessage) {
+    let json = match serde_json::to_string(msg) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("Error serializing signaling message: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = write.lock().await.send(Message::Text(json)).await {
+        println!("Error sending signaling message: {}", e);
+    }
+}
+
+async fn add_remote_candidate(pc: &RTCPeerConnection, session: &Session, candidate: CandidateInit) {
+    session
+        .families
+        .lock()
+        .await
+        .add_remote(&candidate.candidate);
+    let candidate = RTCIceCandidateInit {
+        candidate: candidate.candidate,
+        sdp_mid: candidate.sdp_mid,
+        sdp_mline_index: candidate.sdp_mline_index.map(|x| x as u16),
+        username_fragment: None,
+    };
+    if let Err(e) = pc.add_ice_candidate(candidate).await {
+        println!("Error adding ICE candidate: {}", e);
+        report_error(&session.write, ERROR_BAD_CANDIDATE, e).await;
+    }
+}
+
+// Tells the remote side its message couldn't be applied instead of leaving it
+// waiting for an answer or a connection that won't come
+async fn report_error(write: &WsWrite, code: &str, detail: impl std::fmt::Display) {
+    let message = SignalingMessage::Error {
+        code: code.to_owned(),
+        detail: detail.to_string(),
+    };
+    let json = match serde_json::to_string(&message) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("Error serializing {} report: {}", code, e);
+            return;
+        }
+    };
+    if let Err(e) = write.lock().await.send(Message::Text(json)).await {
+        println!("Error sending {} report: {}", code, e);
+    }
+}
+
+// Media bytes sent and received so far, summed over every RTP stream
+async fn media_bytes(pc: &RTCPeerConnection) -> (u64, u64) {
+    pc.get_stats()
+        .await
+        .reports
+        .values()
+        .fold((0, 0), |(sent, received), report| match report {
+            StatsReportType::OutboundRTP(stats) => (sent + stats.bytes_sent, received),
+            StatsReportType::InboundRTP(stats) => (sent, received + stats.bytes_received),
+            _ => (sent, received),
+        })
+}
+
+// Records the server's cap, restarting the encoder only when that changes the
+// bitrate it runs at
+fn set_bitrate_cap(options: &watch::Sender<ffmpeg::CaptureOptions>, cap: Option<u64>) {
+    options.send_if_modified(|options| {
+        let before = options.effective_bitrate();
+        options.bitrate_cap = cap;
+        let after = options.effective_bitrate();
+        if before != after {
+            println!("Encoder bitrate now {} bps under the server's cap", after);
+        }
+        before != after
+    });
+}
+
+// Asks the remote publisher to cap its bitrate by sending it a REMB covering
+// every track it sends us
+async fn request_remote_bitrate(pc: &RTCPeerConnection, session: &Session, max_bitrate: u64) {
+    let ssrcs = session.remote_ssrcs.lock().await.clone();
+    if ssrcs.is_empty() {
+        println!("Ignoring bitrate request, no remote tracks yet");
+        return;
+    }
+    let remb = ReceiverEstimatedMaximumBitrate {
+        sender_ssrc: 0,
+        bitrate: max_bitrate as f32,
+        ssrcs,
+    };
+    match pc.write_rtcp(&[Box::new(remb)]).await {
+        Ok(_) => println!("Sent REMB capping remote bitrate at {} bps", max_bitrate),
+        Err(e) => println!("Error sending REMB: {}", e),
+    }
+}
+
+// A retry limit that doesn't parse is an error rather than quietly retrying
+// forever
+fn parse_max_retries(raw: Option<String>) -> Result<Option<u32>> {
+    raw.map(|n| {
+        n.parse()
+            .map_err(|e| anyhow::anyhow!("invalid --max-retries '{}': {}", n, e))
+    })
+    .transpose()
+}
+
+fn signaling_request(auth_token: Option<&str>) -> Result<Request> {
+    let mut request = SIGNALING_URL.into_client_request()?;
+    if let Some(token) = auth_token {
+        request
+            .headers_mut()
+            .insert("authorization", format!("Bearer {}", token).parse()?);
+    }
+    Ok(request)
+}
+
+// Reassembles a remote track's frames until it ends, recording them if asked
+// and requesting a keyframe whenever an oversized frame had to be dropped.
+// The recording is flushed and closed once the track is gone.
+async fn read_track<D: Depacketizer + Send>(
+    track: Arc<TrackRemote>,
+    mut assembler: FrameAssembler<D>,
+    mut recording: Option<Recording>,
+    weak_pc: std::sync::Weak<RTCPeerConnection>,
+    max_frame_size: usize,
+) {
+    while let Ok((packet, _)) = track.read_rtp().await {
+        match assembler.push(&packet) {
+            Assembled::Pending => continue,
+            Assembled::Frame(frame) => {
+                if let Some(recording) = recording.as_mut() {
+                    recording.write_frame(&frame, packet.header.timestamp);
+                }
+                continue;
+            }
+            Assembled::Dropped => {}
+        }
+        println!(
+            "Dropped a frame over {} bytes from ssrc {}, requesting a keyframe",
+            max_frame_size,
+            track.ssrc()
+        );
+        let Some(pc) = weak_pc.upgrade() else { break };
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc: track.ssrc(),
+        };
+        if let Err(e) = pc.write_rtcp(&[Box::new(pli)]).await {
+            println!("Error sending PLI: {}", e);
+        }
+    }
+    if let Some(recording) = recording {
+        let _ = tokio::task::spawn_blocking(move || recording.finish()).await;
+    }
+}
+
+// Closes a peer connection that ICE connected but that is still short of
+// `connected` once the timeout has passed, i.e. stuck in the DTLS handshake
+async fn close_unless_connected(pc: std::sync::Weak<RTCPeerConnection>, timeout: Duration) {
+    tokio::time::sleep(timeout).await;
+    let Some(pc) = pc.upgrade() else { return };
+    match pc.connection_state() {
+        RTCPeerConnectionState::Connecting | RTCPeerConnectionState::New => {
+            println!(
+                "Closing peer connection: DTLS did not complete within {:?} of ICE connecting",
+                timeout
+            );
+            close_peer_connection(pc).await;
+        }
+        _ => {}
+    }
+}
+
+// `RTCPeerConnection::close` isn't `Send`, so it's driven on a blocking thread
+// to stay usable from spawned tasks
+async fn close_peer_connection(pc: Arc<RTCPeerConnection>) {
+    let handle = tokio::runtime::Handle::current();
+    let result = tokio::task::spawn_blocking(move || handle.block_on(pc.close())).await;
+    match result {
+        Ok(Err(e)) => println!("Error closing peer connection: {}", e),
+        Err(e) => println!("Error closing peer connection: {}", e),
+        Ok(Ok(())) => {}
+    }
+}
+
+fn oversized_sdp(signal: &SignalingMessage, max_bytes: usize) -> Option<usize> {
+    match signal {
+        SignalingMessage::Offer { sdp, .. } | SignalingMessage::Answer { sdp, .. } => {
+            (sdp.len() > max_bytes).then_some(sdp.len())
+        }
+        _ => None,
+    }
+}
+
+// Why an offer is turned away before it reaches the peer connection
+#[derive(Debug, PartialEq)]
+enum OfferRejection {
+    TooManyTracks { sections: usize, max: usize },
+    // The DTLS fingerprints offered, none or some of them not allowlisted
+    UnknownFingerprint(Vec<String>),
+}
+
+impl OfferRejection {
+    fn code(&self) -> &'static str {
+        match self {
+            OfferRejection::TooManyTracks { .. } => ERROR_TOO_MANY_TRACKS,
+            OfferRejection::UnknownFingerprint(_) => ERROR_FINGERPRINT_NOT_ALLOWED,
+        }
+    }
+}
+
+impl std::fmt::Display for OfferRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OfferRejection::TooManyTracks { sections, max } => {
+                write!(f, "{} media sections, over the limit of {}", sections, max)
+            }
+            OfferRejection::UnknownFingerprint(offered) if offered.is_empty() => {
+                write!(
+                    f,
+                    "no DTLS fingerprint, and only allowlisted ones are accepted"
+                )
+            }
+            OfferRejection::UnknownFingerprint(offered) => write!(
+                f,
+                "DTLS fingerprint(s) {} not on the allowlist",
+                offered.join(", ")
+            ),
+        }
+    }
+}
+
+// Checks an offer against our limits, and against the fingerprint allowlist
+// when there is one, before any of it is applied
+fn screen_offer(sdp: &str, max_tracks: usize, allowlist: &[String]) -> Result<(), OfferRejection> {
+    let sections = sdp::media_count(sdp);
+    if sections > max_tracks {
+        return Err(OfferRejection::TooManyTracks {
+            sections,
+            max: max_tracks,
+        });
+    }
+    if !allowlist.is_empty() && !sdp::fingerprint_allowed(sdp, allowlist) {
+        return Err(OfferRejection::UnknownFingerprint(sdp::fingerprints(sdp)));
+    }
+    Ok(())
+}
+
+// Inflates a compressed offer or answer in place so the handlers only see
+// plain SDP; the size limit applies to the expanded text as well
+fn expand_sdp(signal: &mut SignalingMessage, max_bytes: usize) -> Result<()> {
+    if let SignalingMessage::Offer {
+        sdp, compressed, ..
+    }
+    | SignalingMessage::Answer {
+        sdp, compressed, ..
+    } = signal
+    {
+        if *compressed {
+            *sdp = signaling::decompress_sdp(sdp, max_bytes)?;
+            *compressed = false;
+        }
+    }
+    Ok(())
+}
+
+// A failed negotiation shows up either as an error or as a rejected video m-line
+fn has_video(answer: &Result<String>) -> bool {
+    match answer {
+        Ok(sdp) => {
+            sdp.contains("m=video") && !sdp::rejected_media(sdp).iter().any(|m| m == "video")
+        }
+        Err(_) => false,
+    }
+}
+
+// Replaces `pc` with a connection advertising the full default codec set and
+// answers the offer again on it
+async fn answer_with_default_codecs(
+    pc: &mut Arc<RTCPeerConnection>,
+    offer_sdp: String,
+    options: &AnswerOptions,
+    video_track: &Arc<TrackLocalStaticSample>,
+    session: &Session,
+) -> Result<String> {
+    close_peer_connection(Arc::clone(pc)).await;
+    *pc = create_peer_connection(&[], video_track, session)
+        .await
+        .map_err(|e| anyhow::anyhow!("creating fallback peer connection: {}", e))?;
+    answer_offer(pc, offer_sdp, options).await
+}
+
+struct AnswerOptions {
+    trickle: bool,
+    disabled_rtcp_fb: Vec<String>,
+    // Encoding names whose payload type the answer should use, whatever
+    // number the offer gave them
+    payload_type_pins: Vec<(String, u8)>,
+}
+
+// Without trickle the answer is held back until gathering completes so that
+// every local candidate is embedded in its SDP
+async fn answer_offer(
+    pc: &RTCPeerConnection,
+    offer_sdp: String,
+    options: &AnswerOptions,
+) -> Result<String> {
+    let offer = RTCSessionDescription::try_from(SessionDescriptionInit::offer(offer_sdp.clone()))?;
+    pc.set_remote_description(offer).await?;
+
+    let mut answer = pc.create_answer(None).await?;
+    if !options.disabled_rtcp_fb.is_empty() {
+        answer = SessionDescriptionInit::answer(sdp::strip_rtcp_feedback(
+            &answer.sdp,
+            &options.disabled_rtcp_fb,
+        ))
+        .try_into()?;
+    }
+    let answer_sdp = if options.trickle {
+        pc.set_local_description(answer.clone()).await?;
+        answer.sdp
+    } else {
+        let mut gather_complete = pc.gathering_complete_promise().await;
+        pc.set_local_description(answer).await?;
+        let _ = gather_complete.recv().await;
+
+        let local = pc
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("local description missing after gathering"))?;
+        println!(
+            "Sending non-trickle answer with {} candidates",
+            sdp::candidate_count(&local.sdp)
+        );
+        local.sdp
+    };
+
+    let (answer_sdp, rejected) =
+        sdp::reject_unsupported_media(&offer_sdp, &answer_sdp, is_supported_media);
+    if !rejected.is_empty() {
+        println!("Rejected unsupported media sections: {:?}", rejected);
+    }
+    if options.payload_type_pins.is_empty() {
+        return Ok(answer_sdp);
+    }
+    let (answer_sdp, collisions) = sdp::pin_payload_types(&answer_sdp, &options.payload_type_pins);
+    if !collisions.is_empty() {
+        println!(
+            "Left payload types as offered, the pinned numbers were taken: {:?}",
+            collisions
+        );
+    }
+    Ok(answer_sdp)
+}
+
+// Data channels are only understood over SCTP
+fn is_supported_media(media: &sdp::MediaLine) -> bool {
+    match media.kind.as_str() {
+        "audio" | "video" => true,
+        "application" => media.proto.contains("SCTP"),
+        _ => false,
+    }
+}
+
+// How source files for the selected codec are read
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SourceFormat {
+    // IVF carrying the given fourcc
+    Ivf(&'static [u8; 4]),
+    // Raw H.264 byte stream, which has no timing of its own
+    AnnexB,
+}
+
+impl SourceFormat {
+    fn for_codec(codec: &str) -> Option<Self> {
+        match codec {
+            "vp8" => Some(SourceFormat::Ivf(b"VP80")),
+            "vp9" => Some(SourceFormat::Ivf(b"VP90")),
+            "h264" => Some(SourceFormat::AnnexB),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            SourceFormat::Ivf(_) => "ivf",
+            SourceFormat::AnnexB => "h264",
+        }
+    }
+}
+
+fn fps_override() -> Option<f64> {
+    config::setting("fps", "RUSTWEBRTC_FPS")
+        .and_then(|fps| fps.parse::<f64>().ok())
+        .filter(|fps| *fps > 0.0)
+}
+
+// `--fps` overrides the source's timebase, which some encoders leave at the
+// container default rather than the real frame interval. A zero timebase, or
+// one that is a timestamp unit rather than a frame interval (our own
+// recordings use 1/90000), falls back to the default rate.
+fn frame_duration(header: &IVFFileHeader) -> Duration {
+    frame_duration_at(header, fps_override())
+}
+
+fn frame_duration_at(header: &IVFFileHeader, fps: Option<f64>) -> Duration {
+    if let Some(fps) = fps {
+        return Duration::from_secs_f64(1.0 / fps);
+    }
+    let fallback = Duration::from_secs_f64(1.0 / DEFAULT_FPS);
+    if header.timebase_numerator == 0 || header.timebase_denominator == 0 {
+        return fallback;
+    }
+    let interval = Duration::from_secs_f64(
+        header.timebase_numerator as f64 / header.timebase_denominator as f64,
+    );
+    if !(MIN_FRAME_INTERVAL..=MAX_FRAME_INTERVAL).contains(&interval) {
+        println!(
+            "IVF timebase {}/{} is not a usable frame interval, assuming {} fps",
+            header.timebase_numerator, header.timebase_denominator, DEFAULT_FPS
+        );
+        return fallback;
+    }
+    interval
+}
+
+// Time between two IVF frame timestamps, if it is a usable frame interval.
+// A live encoder stamps frames as the device delivers them, so this follows
+// the actual capture rate where the header's timebase is only a unit.
+fn timestamp_interval(header: &IVFFileHeader, previous: u64, current: u64) -> Option<Duration> {
+    if header.timebase_denominator == 0 || current <= previous {
+        return None;
+    }
+    let interval = Duration::from_secs_f64(
+        (current - previous) as f64 * header.timebase_numerator as f64
+            / header.timebase_denominator as f64,
+    );
+    (MIN_FRAME_INTERVAL..=MAX_FRAME_INTERVAL)
+        .contains(&interval)
+        .then_some(interval)
+}
+
+fn open_ivf(
+    path: &str,
+    format: SourceFormat,
+) -> Result<(IVFReader<BufReader<File>>, IVFFileHeader)> {
+    let (ivf, header) = IVFReader::new(BufReader::new(File::open(path)?))?;
+    if let SourceFormat::Ivf(fourcc) = format {
+        if header.four_cc != *fourcc {
+            anyhow::bail!(
+                "{} holds {}, not {}",
+                path,
+                String::from_utf8_lossy(&header.four_cc),
+                String::from_utf8_lossy(fourcc)
+            );
+        }
+    }
+    Ok((ivf, header))
+}
+
+fn is_eof(error: &webrtc::media::Error) -> bool {
+    matches!(error, webrtc::media::Error::Io(e) if e.0.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+// With `looping`, the file is reopened at its end and playback carries on from
+// the first frame; the ticker is kept so the pacing doesn't jump at the wrap
+async fn write_video_to_track(
+    path: &str,
+    format: SourceFormat,
+    output: VideoOutput,
+    paused: Arc<AtomicBool>,
+    looping: bool,
+) -> Result<()> {
+    let (mut ivf, header) = open_ivf(path, format)?;
+    let duration = frame_duration(&header);
+    let mut pacer = WritePacer::new(duration);
+    let mut ticker = pacer.ticker();
+    let mut frames_since_open = 0u64;
+
+    loop {
+        let frame = match ivf.parse_next_frame() {
+            Ok((frame, _)) => frame,
+            Err(e) if is_eof(&e) => {
+                if !looping {
+                    return Ok(());
+                }
+                if frames_since_open == 0 {
+                    anyhow::bail!("{} has no frames to loop", path);
+                }
+                (ivf, _) = open_ivf(path, format)?;
+                frames_since_open = 0;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        frames_since_open += 1;
+        if paused.load(Ordering::SeqCst) {
+            ticker.tick().await;
+            continue;
+        }
+        let sample = Sample {
+            data: frame.freeze(),
+            duration,
+            ..Default::default()
+        };
+        write_paced(&output, &sample, &mut pacer).await?;
+        ticker.tick().await;
+    }
+}
+
+// Writes one sample, logging when the send path backs up and drains again
+async fn write_paced(output: &VideoOutput, sample: &Sample, pacer: &mut WritePacer) -> Result<()> {
+    let started = tokio::time::Instant::now();
+    output.write_sample(sample).await?;
+    match pacer.observe(started.elapsed()) {
+        Some(true) => println!("Video send path backed up, reading waits on it"),
+        Some(false) => println!("Video send path drained, back to normal pace"),
+        None => {}
+    }
+    Ok(())
+}
+
+// Annex B files carry no frame rate, so `--fps` (or the default) paces them
+async fn write_h264_to_track(
+    path: &str,
+    output: VideoOutput,
+    paused: Arc<AtomicBool>,
+    looping: bool,
+) -> Result<()> {
+    let duration = Duration::from_secs_f64(1.0 / fps_override().unwrap_or(DEFAULT_FPS));
+    let mut pacer = WritePacer::new(duration);
+    let mut ticker = pacer.ticker();
+
+    loop {
+        let mut reader = H264Reader::new(BufReader::new(File::open(path)?), 1024 * 1024);
+        let mut access_units = 0u64;
+        while let Some(access_unit) = h264::next_access_unit(&mut reader) {
+            access_units += 1;
+            if !paused.load(Ordering::SeqCst) {
+                let sample = Sample {
+                    data: access_unit.into(),
+                    duration,
+                    ..Default::default()
+                };
+                write_paced(&output, &sample, &mut pacer).await?;
+            }
+            ticker.tick().await;
+        }
+        if !looping {
+            return Ok(());
+        }
+        if access_units == 0 {
+            anyhow::bail!("{} has no access units to loop", path);
+        }
+    }
+}
+
+// Live publisher: ffmpeg encodes the capture device to IVF on stdout, which a
+// blocking reader thread splits into frames for the track. The CLI can't be
+// told to force a keyframe, so keyframe requests restart ffmpeg, whose first
+// frame is always one.
+async fn stream_capture(
+    mut options: watch::Receiver<ffmpeg::CaptureOptions>,
+    mut keyframes: mpsc::UnboundedReceiver<()>,
+    output: VideoOutput,
+    paused: Arc<AtomicBool>,
+) -> Result<()> {
+    let os = ffmpeg::TargetOs::current()
+        .ok_or_else(|| anyhow::anyhow!("device capture is only supported on Linux and macOS"))?;
+    loop {
+        if let Some(mut viewers) = output.viewers.clone() {
+            if !*viewers.borrow_and_update() {
+                println!("No viewers, capture paused");
+                if viewers.wait_for(|watched| *watched).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        let current = options.borrow_and_update().clone();
+        if !run_capture(os, &current, &output, &paused, &mut options, &mut keyframes).await? {
+            return Ok(());
+        }
+    }
+}
+
+// Runs one ffmpeg process; returns true when it was stopped because the
+// capture options changed or a keyframe was requested, and should be started
+// again
+async fn run_capture(
+    os: ffmpeg::TargetOs,
+    options: &ffmpeg::CaptureOptions,
+    output: &VideoOutput,
+    paused: &AtomicBool,
+    changes: &mut watch::Receiver<ffmpeg::CaptureOptions>,
+    keyframes: &mut mpsc::UnboundedReceiver<()>,
+) -> Result<bool> {
+    let device = options
+        .device
+        .clone()
+        .unwrap_or_else(|| os.default_device().to_owned());
+    ffmpeg::check_device(os, Some(&device)).map_err(|e| anyhow::anyhow!(e))?;
+
+    let (program, args) = ffmpeg::capture_command(os, options);
+    let mut child = std::process::Command::new(&program)
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map(ffmpeg::Process::new)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!("{} not found on PATH", program),
+            _ => anyhow::anyhow!("failed to start ffmpeg: {}", e),
+        })?;
+    let stdout = child.stdout.take().expect("ffmpeg stdout is piped");
+
+    println!("Capturing from {}", device);
+    let started = std::time::Instant::now();
+    let mut viewers = output.viewers.clone();
+    let (tx, mut rx) = mpsc::channel::<Sample>(30);
+    let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+        let (mut ivf, header) = IVFReader::new(BufReader::new(stdout))?;
+        let fallback = frame_duration(&header);
+        let fixed = fps_override().is_some();
+        let mut previous = None;
+        loop {
+            let (frame, frame_header) = ivf.parse_next_frame()?;
+            // Each frame lasts as long as the gap that led up to it, which
+            // follows the device's real rate without holding a frame back
+            let interval = previous
+                .filter(|_| !fixed)
+                .and_then(|previous| timestamp_interval(&header, previous, frame_header.timestamp));
+            previous = Some(frame_header.timestamp);
+            let sample = Sample {
+                data: frame.freeze(),
+                duration: interval.unwrap_or(fallback),
+                ..Default::default()
+            };
+            if tx.blocking_send(sample).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            sample = rx.recv() => {
+                let Some(sample) = sample else { break };
+                if paused.load(Ordering::SeqCst) {
+                    continue;
+                }
+                output.write_sample(&sample).await?;
+            }
+            changed = changes.changed(), if changes.has_changed().is_ok() => {
+                if changed.is_err() {
+                    continue;
+                }
+                println!("Restarting capture with new encoder settings");
+                drop(child);
+                drop(rx);
+                let _ = reader.await;
+                return Ok(true);
+            }
+            Some(()) = keyframes.recv() => {
+                if started.elapsed() < MIN_KEYFRAME_RESTART {
+                    continue;
+                }
+                println!("Restarting capture to send a keyframe");
+                drop(child);
+                drop(rx);
+                let _ = reader.await;
+                return Ok(true);
+            }
+            _ = async {
+                match viewers.as_mut() {
+                    Some(viewers) => {
+                        let _ = viewers.wait_for(|watched| !*watched).await;
+                    }
+                    None => std::future::pending().await,
+                }
+            } => {
+                println!("Last viewer left, stopping capture");
+                drop(child);
+                drop(rx);
+                let _ = reader.await;
+                return Ok(true);
+            }
+        }
+    }
+
+    if let Err(e) = reader.await? {
+        // An exit status here means ffmpeg died on its own rather than the
+        // reader giving up on its output
+        let exited = child.try_wait()?;
+        let _ = child.kill();
+        let _ = child.wait();
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        let failure = ffmpeg::describe_failure(&device, &stderr);
+        return Err(match exited {
+            Some(status) => anyhow::anyhow!("{} (exited early, {})", failure, status),
+            None => anyhow::anyhow!("{} ({})", failure, e),
+        });
+    }
+    Ok(false)
+}
+
+type TimedSample = (Duration, Sample);
+
+// `--av-source`: separate ffmpeg processes decode the file's video and audio,
+// and each sample is written when its timestamp in the file comes due on
+// one shared clock. Looping starts the next pass where the longer track of
+// this one ended.
+async fn stream_av_file(
+    path: &str,
+    options: &ffmpeg::CaptureOptions,
+    video: VideoOutput,
+    audio: Arc<TrackLocalStaticSample>,
+    paused: Arc<AtomicBool>,
+    looping: bool,
+) -> Result<()> {
+    println!("Streaming video and audio from {}", path);
+    let mut timeline = Timeline::new(std::time::Instant::now());
+    let mut monitor = avsync::SkewMonitor::default();
+    loop {
+        let (mut video_child, mut video_rx, video_reader) =
+            spawn_demuxer(ffmpeg::av_video_args(path, options), 8, read_ivf_samples)?;
+        let (mut audio_child, mut audio_rx, audio_reader) =
+            spawn_demuxer(ffmpeg::av_audio_args(path), 25, read_ogg_samples)?;
+        let mut next_video = video_rx.recv().await;
+        let mut next_audio = audio_rx.recv().await;
+        loop {
+            // Whichever track's next sample is due first goes next
+            let (track, next) = match (&next_video, &next_audio) {
+                (None, None) => break,
+                (Some((video_pts, _)), Some((audio_pts, _))) if video_pts < audio_pts => {
+                    (Track::Video, &mut next_video)
+                }
+                (Some(_), None) => (Track::Video, &mut next_video),
+                _ => (Track::Audio, &mut next_audio),
+            };
+            let (pts, sample) = next.take().expect("the chosen track has a sample");
+            let due = timeline.due(pts, sample.duration);
+            tokio::time::sleep_until(due.into()).await;
+            match track {
+                Track::Video => {
+                    if !paused.load(Ordering::SeqCst) {
+                        video.write_sample(&sample).await?;
+                    }
+                    next_video = video_rx.recv().await;
+                }
+                Track::Audio => {
+                    audio.write_sample(&sample).await?;
+                    next_audio = audio_rx.recv().await;
+                }
+            }
+            let lateness = std::time::Instant::now().saturating_duration_since(due);
+            match monitor.record(track, lateness) {
+                Some(SyncChange::Lost { audio_behind_ms }) => println!(
+                    "Audio and video drifted {} ms apart (audio {})",
+                    audio_behind_ms.abs(),
+                    if audio_behind_ms > 0 {
+                        "behind"
+                    } else {
+                        "ahead"
+                    }
+                ),
+                Some(SyncChange::Regained) => println!("Audio and video back in sync"),
+                None => {}
+            }
+        }
+        video_reader.await??;
+        audio_reader.await??;
+        // ffmpeg's own complaint has already gone to our stderr
+        for (kind, child) in [("video", &mut video_child), ("audio", &mut audio_child)] {
+            let status = child.wait()?;
+            if !status.success() {
+                anyhow::bail!("{} ffmpeg for {} exited with {}", kind, path, status);
+            }
+        }
+        if !looping {
+            return Ok(());
+        }
+        if timeline.is_empty_pass() {
+            anyhow::bail!("{} has no media to loop", path);
+        }
+        timeline.next_pass();
+    }
+}
+
+// Starts ffmpeg and a blocking reader that turns its stdout into timestamped
+// samples. The small channel keeps the reader only a little ahead of playback.
+fn spawn_demuxer(
+    args: Vec<String>,
+    capacity: usize,
+    read: fn(std::process::ChildStdout, mpsc::Sender<TimedSample>) -> Result<()>,
+) -> Result<(
+    ffmpeg::Process,
+    mpsc::Receiver<TimedSample>,
+    tokio::task::JoinHandle<Result<()>>,
+)> {
+    let mut child = std::process::Command::new("ffmpeg")
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .map(ffmpeg::Process::new)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!("ffmpeg not found on PATH"),
+            _ => anyhow::anyhow!("failed to start ffmpeg: {}", e),
+        })?;
+    let stdout = child.stdout.take().expect("ffmpeg stdout is piped");
+    let (tx, rx) = mpsc::channel(capacity);
+    let reader = tokio::task::spawn_blocking(move || read(stdout, tx));
+    Ok((child, rx, reader))
+}
+
+// A frame lasts until the next one's timestamp, so each is held back until
+// that arrives; the last falls back to the header's frame rate
+fn read_ivf_samples(
+    stdout: std::process::ChildStdout,
+    tx: mpsc::Sender<TimedSample>,
+) -> Result<()> {
+    let (mut ivf, header) = IVFReader::new(BufReader::new(stdout))?;
+    let fallback = frame_duration(&header);
+    let pts = |timestamp: u64| {
+        Duration::from_secs_f64(
+            timestamp as f64 * header.timebase_numerator as f64
+                / header.timebase_denominator.max(1) as f64,
+        )
+    };
+    let mut held: Option<(u64, Sample)> = None;
+    loop {
+        let (frame, frame_header) = match ivf.parse_next_frame() {
+            Ok(frame) => frame,
+            Err(e) if is_eof(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some((timestamp, mut sample)) = held.take() {
+            // The exact gap, however long, keeps the track's RTP clock on
+            // the file's timeline
+            if frame_header.timestamp > timestamp {
+                sample.duration = pts(frame_header.timestamp) - pts(timestamp);
+            }
+            if tx.blocking_send((pts(timestamp), sample)).is_err() {
+                return Ok(());
+            }
+        }
+        let sample = Sample {
+            data: frame.freeze(),
+            duration: fallback,
+            ..Default::default()
+        };
+        held = Some((frame_header.timestamp, sample));
+    }
+    if let Some((timestamp, sample)) = held {
+        let _ = tx.blocking_send((pts(timestamp), sample));
+    }
+    Ok(())
+}
+
+// A page starts where the previous one's granule position left off, less the
+// encoder's pre-skip, in 48 kHz samples
+fn read_ogg_samples(
+    stdout: std::process::ChildStdout,
+    tx: mpsc::Sender<TimedSample>,
+) -> Result<()> {
+    let (mut ogg, header) = OggReader::new(BufReader::new(stdout), true)?;
+    let pre_skip = header.pre_skip as u64;
+    let mut last_granule = 0;
+    loop {
+        let (data, page_header) = match ogg.parse_next_page() {
+            Ok(page) => page,
+            Err(e) if is_eof(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        // Header pages carry no audio and leave the granule at zero
+        let samples = page_header.granule_position.saturating_sub(last_granule);
+        let start = last_granule.saturating_sub(pre_skip);
+        last_granule = page_header.granule_position;
+        if samples == 0 {
+            continue;
+        }
+        let sample = Sample {
+            data: data.freeze(),
+            duration: Duration::from_micros(samples * 1_000_000 / 48_000),
+            ..Default::default()
+        };
+        let pts = Duration::from_micros(start * 1_000_000 / 48_000);
+        if tx.blocking_send((pts, sample)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// ffmpeg encodes Opus into Ogg pages on stdout; each page's granule position
+// advance gives its duration in 48 kHz samples
+async fn stream_audio(
+    os: ffmpeg::TargetOs,
+    input: Option<String>,
+    track: Arc<TrackLocalStaticSample>,
+) -> Result<()> {
+    if let Some(path) = input
+        .as_deref()
+        .filter(|path| !std::path::Path::new(path).exists())
+    {
+        anyhow::bail!("audio input {} not found", path);
+    }
+    let args = ffmpeg::audio_args(os, input.as_deref());
+    let mut child = std::process::Command::new("ffmpeg")
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .map(ffmpeg::Process::new)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!("ffmpeg not found on PATH"),
+            _ => anyhow::anyhow!("failed to start ffmpeg: {}", e),
+        })?;
+    let stdout = child.stdout.take().expect("ffmpeg stdout is piped");
+
+    println!(
+        "Streaming audio from {}",
+        input.as_deref().unwrap_or("the default microphone")
+    );
+    let (tx, mut rx) = mpsc::channel::<Sample>(50);
+    let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+        let (mut ogg, _) = OggReader::new(BufReader::new(stdout), true)?;
+        let mut last_granule = 0;
+        loop {
+            let (data, header) = ogg.parse_next_page()?;
+            // Header pages carry no audio and leave the granule at zero
+            let samples = header.granule_position.saturating_sub(last_granule);
+            last_granule = header.granule_position;
+            if samples == 0 {
+                continue;
+            }
+            let sample = Sample {
+                data: data.freeze(),
+                duration: Duration::from_micros(samples * 1_000_000 / 48_000),
+                ..Default::default()
+            };
+            if tx.blocking_send(sample).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    let mut written = Ok(());
+    while let Some(sample) = rx.recv().await {
+        if let Err(e) = track.write_sample(&sample).await {
+            written = Err(e.into());
+            break;
+        }
+    }
+    // ffmpeg's own complaint has already gone to our stderr; say that it
+    // was ffmpeg that stopped rather than leaving just a short read
+    if let Some(status) = child.try_wait()?.filter(|status| !status.success()) {
+        println!("Audio ffmpeg exited early, {}", status);
+    }
+    drop(child);
+    drop(rx);
+    written.and(reader.await?)
+}
+
+//File watcher
+async fn watchand_stream_video(
+    directory: &str,
+    format: SourceFormat,
+    output: VideoOutput,
+    paused: Arc<AtomicBool>,
+    looping: bool,
+) -> Result<()> {
+    // Create a channel for file events
+    let (tx, mut rx) = mpsc::channel(100);
+
+    // Create an async file watcher
+    let mut watcher = RecommendedWatcher::new(
+        move |res| {
+            if let Ok(event) = res {
+                tx.blocking_send(event).expect("Failed to send event");
+            }
+        },
+        Config::default(),
+    )?;
 
     // Start watching the specified directory
     watcher.watch(std::path::Path::new(directory), RecursiveMode::NonRecursive)?;
 
     println!("Watching directory: {}", directory);
 
-    let mut current_file = String::new();
-
     // Event loop
     while let Some(event) = rx.recv().await {
         if let Event {
@@ -210,12 +2655,31 @@ async fn watchand_stream_video(directory: &str, track: Arc<TrackLocalStaticSampl
         {
             for path in paths {
                 if let Some(ext) = path.extension() {
-                    if ext == "ivf" {
+                    if ext == format.extension() {
                         println!("Detected change in file: {:?}", path);
-                        current_file = path.to_string_lossy().to_string();
-                        if let Err(e) =
-                            write_video_to_track(&current_file, Arc::clone(&track)).await
-                        {
+                        let current_file = path.to_string_lossy().to_string();
+                        let result = match format {
+                            SourceFormat::Ivf(_) => {
+                                write_video_to_track(
+                                    &current_file,
+                                    format,
+                                    output.clone(),
+                                    Arc::clone(&paused),
+                                    looping,
+                                )
+                                .await
+                            }
+                            SourceFormat::AnnexB => {
+                                write_h264_to_track(
+                                    &current_file,
+                                    output.clone(),
+                                    Arc::clone(&paused),
+                                    looping,
+                                )
+                                .await
+                            }
+                        };
+                        if let Err(e) = result {
                             println!("Error streaming video: {}", e);
                         }
                     }
@@ -226,3 +2690,430 @@ async fn watchand_stream_video(directory: &str, track: Arc<TrackLocalStaticSampl
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::WebSocketStream;
+    use webrtc::rtcp::reception_report::ReceptionReport;
+    use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+    use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+    use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+    // A session whose signaling socket is connected to the returned server
+    // end over loopback, so what the streamer sends can be read back
+    async fn test_session(trickle: bool) -> (Session, WebSocketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(connect_async(url), async {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        let (write, _read) = client.unwrap().0.split();
+        let (viewer_present, _) = watch::channel(false);
+        let session = Session {
+            write: Arc::new(Mutex::new(write)),
+            ice_servers: Vec::new(),
+            audio_track: None,
+            trickle: Arc::new(AtomicBool::new(trickle)),
+            remote_ssrcs: Arc::new(Mutex::new(Vec::new())),
+            max_frame_size: depacketizer::DEFAULT_MAX_FRAME_SIZE,
+            record_dir: None,
+            record_formats: Vec::new(),
+            image_dir: None,
+            max_image_bytes: 0,
+            video_paused: Arc::new(AtomicBool::new(false)),
+            shed_video_on_loss: false,
+            probe_window: None,
+            dtls_timeout: None,
+            candidate_batch: None,
+            families: Arc::new(Mutex::new(ice::FamilyTracker::default())),
+            force_relay: Arc::new(AtomicBool::new(false)),
+            relay_requests: None,
+            keyframe_requests: None,
+            ice_credentials: None,
+            viewer_present: Arc::new(viewer_present),
+            remote_id: Arc::new(std::sync::Mutex::new(None)),
+        };
+        (session, server)
+    }
+
+    fn video_track(mime_type: &str) -> Arc<TrackLocalStaticSample> {
+        Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: mime_type.to_owned(),
+                ..Default::default()
+            },
+            "video".to_owned(),
+            "test".to_owned(),
+        ))
+    }
+
+    // A browser-like peer that only speaks `codecs`, or every default codec
+    // when empty
+    async fn remote_peer(codecs: &[String]) -> Arc<RTCPeerConnection> {
+        let mut m = MediaEngine::default();
+        codecs::register_codecs(&mut m, codecs).unwrap();
+        let api = APIBuilder::new().with_media_engine(m).build();
+        Arc::new(
+            api.new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        )
+    }
+
+    async fn receive_video(remote: &RTCPeerConnection) {
+        remote
+            .add_transceiver_from_kind(
+                RTPCodecType::Video,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: Vec::new(),
+                }),
+            )
+            .await
+            .unwrap();
+    }
+
+    // An offer to receive video from a remote peer
+    async fn viewer_offer(codecs: &[String]) -> (Arc<RTCPeerConnection>, String) {
+        let pc = remote_peer(codecs).await;
+        receive_video(&pc).await;
+        let offer = pc.create_offer(None).await.unwrap();
+        pc.set_local_description(offer.clone()).await.unwrap();
+        (pc, offer.sdp)
+    }
+
+    // Connects `remote` to the streamer's `pc` over loopback, the remote
+    // offering with every candidate and the streamer answering
+    async fn connect_remote(remote: &RTCPeerConnection, pc: &RTCPeerConnection) {
+        let offer = remote.create_offer(None).await.unwrap();
+        let mut gathered = remote.gathering_complete_promise().await;
+        remote.set_local_description(offer).await.unwrap();
+        let _ = gathered.recv().await;
+        let offer = remote.local_description().await.unwrap().sdp;
+        let answer = answer_offer(pc, offer, &answer_options(false))
+            .await
+            .unwrap();
+        remote
+            .set_remote_description(RTCSessionDescription::answer(answer).unwrap())
+            .await
+            .unwrap();
+    }
+
+    fn answer_options(trickle: bool) -> AnswerOptions {
+        AnswerOptions {
+            trickle,
+            disabled_rtcp_fb: Vec::new(),
+            payload_type_pins: Vec::new(),
+        }
+    }
+
+    // Messages the streamer sends within `wait`
+    async fn sent_within(server: &mut WebSocketStream<TcpStream>, wait: Duration) -> Vec<String> {
+        let mut sent = Vec::new();
+        while let Ok(Some(Ok(message))) = tokio::time::timeout(wait, server.next()).await {
+            if let Message::Text(text) = message {
+                sent.push(text);
+            }
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn forced_non_trickle_answer_carries_every_candidate() {
+        let (session, mut server) = test_session(false).await;
+        let track = video_track(MIME_TYPE_VP8);
+        let pc = create_peer_connection(&[], &track, &session).await.unwrap();
+        // The viewer could take trickled candidates, like any browser
+        let (viewer, offer) = viewer_offer(&[]).await;
+        let offer = offer.replacen("t=0 0\r\n", "t=0 0\r\na=ice-options:trickle\r\n", 1);
+        assert!(sdp::supports_trickle(&offer));
+
+        let answer = answer_offer(&pc, offer, &answer_options(false))
+            .await
+            .unwrap();
+        let gathered = pc.local_description().await.unwrap().sdp;
+        assert!(sdp::candidate_count(&answer) > 0);
+        assert_eq!(
+            sdp::candidate_count(&answer),
+            sdp::candidate_count(&gathered)
+        );
+        let sent = sent_within(&mut server, Duration::from_millis(500)).await;
+        assert!(
+            !sent.iter().any(|text| text.contains("\"candidate")),
+            "{:?}",
+            sent
+        );
+
+        close_peer_connection(pc).await;
+        close_peer_connection(viewer).await;
+    }
+
+    #[tokio::test]
+    async fn trickled_answer_leaves_candidates_to_their_own_messages() {
+        let (session, mut server) = test_session(true).await;
+        let track = video_track(MIME_TYPE_VP8);
+        let pc = create_peer_connection(&[], &track, &session).await.unwrap();
+        let (viewer, offer) = viewer_offer(&[]).await;
+
+        let answer = answer_offer(&pc, offer, &answer_options(true))
+            .await
+            .unwrap();
+        assert_eq!(sdp::candidate_count(&answer), 0);
+        let sent = sent_within(&mut server, Duration::from_millis(500)).await;
+        assert!(
+            sent.iter().any(|text| text.contains("\"candidate\"")),
+            "{:?}",
+            sent
+        );
+
+        close_peer_connection(pc).await;
+        close_peer_connection(viewer).await;
+    }
+
+    #[tokio::test]
+    async fn offers_sharing_no_preferred_codec_fall_back_to_the_defaults() {
+        let (session, _server) = test_session(true).await;
+        let track = video_track(MIME_TYPE_VP8);
+        let (viewer, offer) = viewer_offer(&["vp8".to_owned()]).await;
+        let options = answer_options(true);
+
+        let mut pc = create_peer_connection(&["vp9".to_owned()], &track, &session)
+            .await
+            .unwrap();
+        let restricted = answer_offer(&pc, offer.clone(), &options).await;
+        assert!(!has_video(&restricted));
+
+        let restricted_pc = Arc::clone(&pc);
+        let answer = answer_with_default_codecs(&mut pc, offer, &options, &track, &session).await;
+        assert!(!Arc::ptr_eq(&pc, &restricted_pc));
+        assert!(has_video(&answer));
+        assert_eq!(
+            sdp::negotiated_codec(&answer.unwrap(), "video").as_deref(),
+            Some("VP8")
+        );
+
+        close_peer_connection(pc).await;
+        close_peer_connection(viewer).await;
+    }
+
+    #[tokio::test]
+    async fn bitrate_requests_reach_the_publisher_as_remb() {
+        let (session, _server) = test_session(false).await;
+        let pc = create_peer_connection(&[], &video_track(MIME_TYPE_VP8), &session)
+            .await
+            .unwrap();
+
+        // A publisher sending us VP8
+        let publisher = remote_peer(&[]).await;
+        let sender = publisher
+            .add_track(video_track(MIME_TYPE_VP8) as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .unwrap();
+        connect_remote(&publisher, &pc).await;
+        let ssrc = sender.get_parameters().await.encodings[0].ssrc;
+        session.remote_ssrcs.lock().await.push(ssrc);
+
+        let remb = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                request_remote_bitrate(&pc, &session, 300_000).await;
+                let read = tokio::time::timeout(Duration::from_millis(200), sender.read_rtcp());
+                if let Ok(Ok((packets, _))) = read.await {
+                    if let Some(remb) = packets.iter().find_map(|packet| {
+                        packet
+                            .as_any()
+                            .downcast_ref::<ReceiverEstimatedMaximumBitrate>()
+                            .cloned()
+                    }) {
+                        return remb;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("the publisher never got a REMB");
+        assert_eq!(remb.bitrate, 300_000.0);
+        assert_eq!(remb.ssrcs, vec![ssrc]);
+
+        close_peer_connection(pc).await;
+        close_peer_connection(publisher).await;
+    }
+
+    #[tokio::test]
+    async fn sustained_loss_reports_pause_the_video() {
+        let (mut session, _server) = test_session(false).await;
+        session.shed_video_on_loss = true;
+        let pc = create_peer_connection(&[], &video_track(MIME_TYPE_VP8), &session)
+            .await
+            .unwrap();
+        let viewer = remote_peer(&[]).await;
+        receive_video(&viewer).await;
+        connect_remote(&viewer, &pc).await;
+        let video_ssrc = pc.get_senders().await[0].get_parameters().await.encodings[0].ssrc;
+
+        // The viewer reports losing most of the video
+        let report = ReceiverReport {
+            reports: vec![ReceptionReport {
+                ssrc: video_ssrc,
+                fraction_lost: 200,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !session.video_paused.load(Ordering::SeqCst) {
+                let _ = viewer.write_rtcp(&[Box::new(report.clone())]).await;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("video never paused under sustained loss");
+
+        close_peer_connection(pc).await;
+        close_peer_connection(viewer).await;
+    }
+
+    #[tokio::test]
+    async fn connections_short_of_connected_are_closed_after_the_dtls_timeout() {
+        let (session, _server) = test_session(false).await;
+        let track = video_track(MIME_TYPE_VP8);
+
+        // A connection that never gets as far as connected
+        let stuck = create_peer_connection(&[], &track, &session).await.unwrap();
+        assert_ne!(stuck.connection_state(), RTCPeerConnectionState::Connected);
+        close_unless_connected(Arc::downgrade(&stuck), Duration::from_millis(50)).await;
+        assert_eq!(stuck.connection_state(), RTCPeerConnectionState::Closed);
+
+        let pc = create_peer_connection(&[], &track, &session).await.unwrap();
+        let viewer = remote_peer(&[]).await;
+        receive_video(&viewer).await;
+        connect_remote(&viewer, &pc).await;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while pc.connection_state() != RTCPeerConnectionState::Connected {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the viewer never connected");
+        close_unless_connected(Arc::downgrade(&pc), Duration::from_millis(50)).await;
+        assert_eq!(pc.connection_state(), RTCPeerConnectionState::Connected);
+
+        close_peer_connection(pc).await;
+        close_peer_connection(viewer).await;
+    }
+
+    fn ivf_header(timebase_numerator: u32, timebase_denominator: u32) -> IVFFileHeader {
+        IVFFileHeader {
+            signature: *b"DKIF",
+            version: 0,
+            header_size: 32,
+            four_cc: *b"VP80",
+            width: 640,
+            height: 480,
+            timebase_denominator,
+            timebase_numerator,
+            num_frames: 0,
+            unused: 0,
+        }
+    }
+
+    #[test]
+    fn sample_duration_follows_the_frame_rate() {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let near = |duration: Duration, expected: f64| (ms(duration) - expected).abs() < 0.5;
+
+        // From the header's timebase
+        assert!(near(frame_duration_at(&ivf_header(1, 60), None), 16.67));
+        assert!(near(frame_duration_at(&ivf_header(1, 30), None), 33.33));
+        // From --fps, whatever the header says
+        assert!(near(
+            frame_duration_at(&ivf_header(1, 30), Some(60.0)),
+            16.67
+        ));
+        assert!(near(
+            frame_duration_at(&ivf_header(1, 60), Some(30.0)),
+            33.33
+        ));
+        // A timestamp unit rather than a frame interval falls back to 30 fps
+        assert!(near(frame_duration_at(&ivf_header(1, 90000), None), 33.33));
+        assert!(near(frame_duration_at(&ivf_header(0, 0), None), 33.33));
+    }
+
+    fn offer(media: &[&str], fingerprint: &str) -> String {
+        let mut sdp = format!(
+            "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=fingerprint:sha-256 {}\r\n",
+            fingerprint
+        );
+        for (mid, kind) in media.iter().enumerate() {
+            sdp += &format!(
+                "m={} 9 UDP/TLS/RTP/SAVPF 96\r\nc=IN IP4 0.0.0.0\r\na=mid:{}\r\n",
+                kind, mid
+            );
+        }
+        sdp
+    }
+
+    const FINGERPRINT: &str = "AB:CD:EF:01";
+
+    #[test]
+    fn offer_within_the_track_limit_passes() {
+        let sdp = offer(&["audio", "video"], FINGERPRINT);
+        assert_eq!(screen_offer(&sdp, 2, &[]), Ok(()));
+    }
+
+    #[test]
+    fn offer_over_the_track_limit_is_rejected() {
+        let sdp = offer(&["video"; 5], FINGERPRINT);
+        let rejection = screen_offer(&sdp, 4, &[]).unwrap_err();
+        assert_eq!(
+            rejection,
+            OfferRejection::TooManyTracks {
+                sections: 5,
+                max: 4
+            }
+        );
+        assert_eq!(rejection.code(), "too_many_tracks");
+        assert!(FATAL_ERRORS.contains(&rejection.code()));
+    }
+
+    #[test]
+    fn allowlisted_fingerprint_proceeds() {
+        let sdp = offer(&["video"], "ab:cd:ef:01");
+        let allowlist = vec!["sha-256 AB:CD:EF:01".to_owned(), "12:34".to_owned()];
+        assert_eq!(screen_offer(&sdp, 16, &allowlist), Ok(()));
+    }
+
+    #[test]
+    fn unknown_fingerprint_is_rejected() {
+        let sdp = offer(&["video"], "99:99:99:99");
+        let allowlist = vec![format!("sha-256 {}", FINGERPRINT)];
+        let rejection = screen_offer(&sdp, 16, &allowlist).unwrap_err();
+        assert_eq!(
+            rejection,
+            OfferRejection::UnknownFingerprint(vec!["99:99:99:99".to_owned()])
+        );
+        assert_eq!(rejection.code(), "fingerprint_not_allowed");
+
+        // An offer without any fingerprint can't be vouched for either
+        let bare: String = sdp
+            .lines()
+            .filter(|line| !line.starts_with("a=fingerprint"))
+            .map(|line| format!("{}\r\n", line))
+            .collect();
+        assert_eq!(
+            screen_offer(&bare, 16, &allowlist),
+            Err(OfferRejection::UnknownFingerprint(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn unparsable_max_retries_is_an_error() {
+        assert_eq!(parse_max_retries(None).unwrap(), None);
+        assert_eq!(parse_max_retries(Some("3".to_owned())).unwrap(), Some(3));
+        for bad in ["three", "-1", ""] {
+            assert!(parse_max_retries(Some(bad.to_owned())).is_err(), "{}", bad);
+        }
+    }
+}
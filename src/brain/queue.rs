@@ -0,0 +1,726 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::auth::tokens_match;
+
+pub const MAX_SESSION_DURATION: Duration = Duration::from_secs(300);
+pub const MAX_IDLE_TIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: String,
+    pub started_at: Instant,
+    pub last_activity: Instant,
+    // Whether the owner has shown any activity since being promoted
+    pub accepted: bool,
+    // When the owner's connection dropped, while they may still come back
+    pub disconnected_at: Option<Instant>,
+    // Proves a reconnecting client is the owner; see `Queue::resume_token`
+    resume_token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndReason {
+    MaxDuration,
+    Idle,
+    // Promoted, but the owner never showed up within the acceptance window
+    Unaccepted,
+    // The owner disconnected and didn't come back within the reconnect grace
+    Disconnected,
+}
+
+impl std::fmt::Display for EndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EndReason::MaxDuration => write!(f, "reached its maximum duration"),
+            EndReason::Idle => write!(f, "went idle"),
+            EndReason::Unaccepted => write!(f, "was never taken up"),
+            EndReason::Disconnected => write!(f, "lost its owner's connection"),
+        }
+    }
+}
+
+// Every timer that can end the active session, in one place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionPolicy {
+    pub max_session: Duration,
+    pub max_idle: Duration,
+    // A promoted user must show activity within this, so an absent one
+    // doesn't hold the session for a whole idle timeout; unset waits for it
+    pub accept_within: Option<Duration>,
+    // How long a disconnected owner keeps the session to reconnect; zero
+    // ends it as soon as they drop
+    pub reconnect_grace: Duration,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            max_session: MAX_SESSION_DURATION,
+            max_idle: MAX_IDLE_TIME,
+            accept_within: None,
+            reconnect_grace: Duration::ZERO,
+        }
+    }
+}
+
+impl SessionPolicy {
+    // Why the session should end at `now`, if it should. The duration cap
+    // comes first, then acceptance, then idleness.
+    pub fn evaluate(&self, session: &Session, now: Instant) -> Option<EndReason> {
+        let age = now.saturating_duration_since(session.started_at);
+        if age > self.max_session {
+            return Some(EndReason::MaxDuration);
+        }
+        if session
+            .disconnected_at
+            .is_some_and(|at| now.saturating_duration_since(at) >= self.reconnect_grace)
+        {
+            return Some(EndReason::Disconnected);
+        }
+        if !session.accepted && self.accept_within.is_some_and(|window| age > window) {
+            return Some(EndReason::Unaccepted);
+        }
+        if now.saturating_duration_since(session.last_activity) > self.max_idle {
+            return Some(EndReason::Idle);
+        }
+        None
+    }
+}
+
+// Where the queue reads the time, so timeouts can be driven by a clock that
+// is advanced by hand instead of waited out
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Point-in-time counts for monitoring
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueMetrics {
+    pub waiting: usize,
+    pub active_session: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    // The line is at its configured capacity
+    Full,
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "the queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Waiting {
+    user_id: String,
+    priority: u8,
+    // Saves from before tokens existed have none, and can't be resumed
+    #[serde(default)]
+    resume_token: String,
+}
+
+// On-disk form of the line. `Instant`s can't be stored, so the active
+// session keeps how long it had run and the wall-clock time of the save.
+#[derive(Serialize, Deserialize)]
+struct SavedQueue {
+    waiting: Vec<Waiting>,
+    active: Option<SavedSession>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    user_id: String,
+    elapsed_secs: u64,
+    saved_at_unix_secs: u64,
+    #[serde(default)]
+    resume_token: String,
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueState {
+    pub position: usize,
+    pub estimated_wait_secs: u64,
+}
+
+// Arbitrates a single active session between connected users; everyone else
+// waits until `process_queue` promotes them. Higher priorities are served
+// first, in FIFO order within a priority.
+pub struct Queue {
+    // Kept sorted by descending priority
+    waiting: VecDeque<Waiting>,
+    active_session: Option<Session>,
+    policy: SessionPolicy,
+    // Joins beyond this many waiting users are refused; unlimited when unset
+    max_waiting: Option<usize>,
+    // How long before the active session's deadline the head of the line is
+    // told to get ready; zero disables the warning
+    handoff_lead: Duration,
+    // Who has already been warned for the current session
+    prepared: Option<String>,
+    // Ended sessions and their total length, for estimating waits
+    sessions_ended: u32,
+    session_time: Duration,
+    updates: broadcast::Sender<QueueState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::with_policy(SessionPolicy::default())
+    }
+
+    // A session ends after `max_session` in total, or after `max_idle`
+    // without activity from its owner
+    pub fn with_limits(max_session: Duration, max_idle: Duration) -> Self {
+        Self::with_policy(SessionPolicy {
+            max_session,
+            max_idle,
+            ..SessionPolicy::default()
+        })
+    }
+
+    pub fn with_policy(policy: SessionPolicy) -> Self {
+        let (updates, _) = broadcast::channel(64);
+        Queue {
+            waiting: VecDeque::new(),
+            active_session: None,
+            policy,
+            max_waiting: None,
+            handoff_lead: Duration::ZERO,
+            prepared: None,
+            sessions_ended: 0,
+            session_time: Duration::ZERO,
+            updates,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_handoff_lead(mut self, lead: Duration) -> Self {
+        self.handoff_lead = lead;
+        self
+    }
+
+    pub fn with_max_waiting(mut self, max: Option<usize>) -> Self {
+        self.max_waiting = max;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn since(&self, instant: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(instant)
+    }
+
+    // Writes the line and the active session's owner as JSON, through a
+    // temporary file so a crash mid-write leaves the previous save intact
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let saved = SavedQueue {
+            waiting: self.waiting.iter().cloned().collect(),
+            active: self.active_session.as_ref().map(|session| SavedSession {
+                user_id: session.user_id.clone(),
+                elapsed_secs: self.since(session.started_at).as_secs(),
+                saved_at_unix_secs: unix_secs(),
+                resume_token: session.resume_token.clone(),
+            }),
+        };
+        let json = serde_json::to_vec(&saved)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(tmp, path)
+    }
+
+    // Restores a saved line. The time the server was down counts against
+    // the active session, which is dropped if that uses up its duration; its
+    // idle timer starts over so the owner has time to reconnect.
+    pub fn load_from_path(path: &Path, policy: SessionPolicy) -> io::Result<Self> {
+        let saved: SavedQueue = serde_json::from_slice(&std::fs::read(path)?)?;
+        let mut queue = Queue::with_policy(policy);
+        queue.waiting = saved.waiting.into();
+        queue
+            .waiting
+            .make_contiguous()
+            .sort_by_key(|entry| std::cmp::Reverse(entry.priority));
+        queue.active_session = saved.active.and_then(|session| {
+            let downtime = unix_secs().saturating_sub(session.saved_at_unix_secs);
+            let elapsed = Duration::from_secs(session.elapsed_secs.saturating_add(downtime));
+            if elapsed >= queue.policy.max_session {
+                return None;
+            }
+            let now = queue.clock.now();
+            Some(Session {
+                user_id: session.user_id,
+                started_at: now.checked_sub(elapsed)?,
+                last_activity: now,
+                accepted: true,
+                disconnected_at: None,
+                resume_token: session.resume_token,
+            })
+        });
+        Ok(queue)
+    }
+
+    // Drops everyone `keep` rejects from the line and from the active session
+    pub fn retain_users(&mut self, keep: impl Fn(&str) -> bool) {
+        let before = self.waiting.len();
+        self.waiting.retain(|entry| keep(&entry.user_id));
+        let mut changed = self.waiting.len() != before;
+        if self
+            .active_session
+            .as_ref()
+            .is_some_and(|session| !keep(&session.user_id))
+        {
+            self.active_session = None;
+            changed = true;
+        }
+        if changed {
+            self.notify();
+        }
+    }
+
+    // Whether the user is waiting or holds the active session
+    pub fn holds(&self, user_id: &str) -> bool {
+        self.is_active(user_id) || self.position(user_id).is_some()
+    }
+
+    // Queues the user behind everyone of the same or higher priority. Returns
+    // false if they were already in line or active.
+    pub fn join_queue(&mut self, user_id: &str, priority: u8) -> Result<bool, QueueError> {
+        if self.holds(user_id) {
+            return Ok(false);
+        }
+        if self
+            .max_waiting
+            .is_some_and(|max| self.waiting.len() >= max)
+        {
+            return Err(QueueError::Full);
+        }
+        let index = self
+            .waiting
+            .iter()
+            .position(|entry| entry.priority < priority)
+            .unwrap_or(self.waiting.len());
+        self.waiting.insert(
+            index,
+            Waiting {
+                user_id: user_id.to_owned(),
+                priority,
+                resume_token: Uuid::new_v4().simple().to_string(),
+            },
+        );
+        self.notify();
+        Ok(true)
+    }
+
+    pub fn leave_queue(&mut self, user_id: &str) -> bool {
+        let before = self.waiting.len();
+        self.waiting.retain(|entry| entry.user_id != user_id);
+        let removed = self.waiting.len() != before;
+        if removed {
+            self.notify();
+        }
+        removed
+    }
+
+    // Promotes the head of the line when nobody holds the session
+    pub fn process_queue(&mut self) -> Option<String> {
+        if self.active_session.is_some() {
+            return None;
+        }
+        let Waiting {
+            user_id,
+            resume_token,
+            ..
+        } = self.waiting.pop_front()?;
+        let now = self.clock.now();
+        self.prepared = None;
+        self.active_session = Some(Session {
+            user_id: user_id.clone(),
+            started_at: now,
+            last_activity: now,
+            accepted: false,
+            disconnected_at: None,
+            resume_token,
+        });
+        self.notify();
+        Some(user_id)
+    }
+
+    // The secret a user's client presents to reclaim its place or session
+    // after reconnecting. Ids are shown to other clients, so they can't
+    // serve as proof on their own.
+    pub fn resume_token(&self, user_id: &str) -> Option<&str> {
+        let token = match &self.active_session {
+            Some(session) if session.user_id == user_id => &session.resume_token,
+            _ => {
+                &self
+                    .waiting
+                    .iter()
+                    .find(|entry| entry.user_id == user_id)?
+                    .resume_token
+            }
+        };
+        Some(token.as_str()).filter(|token| !token.is_empty())
+    }
+
+    // Whose place `token` reclaims, if anyone's
+    pub fn resumable_user(&self, token: &str) -> Option<&str> {
+        let active = self
+            .active_session
+            .iter()
+            .map(|session| (&session.user_id, &session.resume_token));
+        let waiting = self
+            .waiting
+            .iter()
+            .map(|entry| (&entry.user_id, &entry.resume_token));
+        active
+            .chain(waiting)
+            .find(|(_, expected)| !expected.is_empty() && tokens_match(token, expected))
+            .map(|(user_id, _)| user_id.as_str())
+    }
+
+    pub fn end_session(&mut self) -> Option<Session> {
+        let ended = self.active_session.take();
+        if let Some(session) = &ended {
+            self.sessions_ended = self.sessions_ended.saturating_add(1);
+            self.session_time += self.since(session.started_at);
+            self.notify();
+        }
+        ended
+    }
+
+    // Marks whether the active session's owner is connected. A disconnected
+    // owner's session ends at the next cleanup once the policy's reconnect
+    // grace runs out.
+    pub fn set_connected(&mut self, user_id: &str, connected: bool) {
+        let now = self.clock.now();
+        if let Some(session) = self
+            .active_session
+            .as_mut()
+            .filter(|session| session.user_id == user_id)
+        {
+            session.disconnected_at = (!connected).then_some(now);
+        }
+    }
+
+    pub fn update_activity(&mut self, user_id: &str) {
+        let now = self.clock.now();
+        if let Some(session) = self
+            .active_session
+            .as_mut()
+            .filter(|s| s.user_id == user_id)
+        {
+            session.last_activity = now;
+            session.accepted = true;
+        }
+    }
+
+    // Ends the active session once the policy says it is over, and says why
+    pub fn cleanup_stale_sessions(&mut self) -> Option<(Session, EndReason)> {
+        let reason = self
+            .policy
+            .evaluate(self.active_session.as_ref()?, self.clock.now())?;
+        Some((self.end_session()?, reason))
+    }
+
+    // Looks ahead to the active session's deadline: once it is within the
+    // handoff lead, returns the head of the line and the time left, once per
+    // user, so they can warm up their connection before being promoted. Only
+    // the session length is predictable; an idle timeout gives no warning.
+    pub fn due_for_handoff(&mut self) -> Option<(String, Duration)> {
+        if self.handoff_lead.is_zero() {
+            return None;
+        }
+        let session = self.active_session.as_ref()?;
+        let remaining = self
+            .policy
+            .max_session
+            .saturating_sub(self.since(session.started_at));
+        let next = &self.waiting.front()?.user_id;
+        if remaining > self.handoff_lead || self.prepared.as_ref() == Some(next) {
+            return None;
+        }
+        self.prepared = Some(next.clone());
+        Some((next.clone(), remaining))
+    }
+
+    pub fn is_active(&self, user_id: &str) -> bool {
+        self.active_user() == Some(user_id)
+    }
+
+    pub fn active_user(&self) -> Option<&str> {
+        self.active_session.as_ref().map(|s| s.user_id.as_str())
+    }
+
+    // Zero-based index in the waiting line, counting everyone of higher
+    // priority ahead
+    pub fn position(&self, user_id: &str) -> Option<usize> {
+        self.waiting
+            .iter()
+            .position(|entry| entry.user_id == user_id)
+    }
+
+    pub fn metrics_snapshot(&self) -> QueueMetrics {
+        QueueMetrics {
+            waiting: self.waiting.len(),
+            active_session: self.active_session.is_some(),
+        }
+    }
+
+    pub fn subscribe_to_updates(&self) -> broadcast::Receiver<QueueState> {
+        self.updates.subscribe()
+    }
+
+    // The user's place in line and the expected wait for that slot: what is
+    // left of the active session plus an average session per user ahead. The
+    // active user is at position 0 with no wait; unknown users get None.
+    pub fn position_of(&self, user_id: &str) -> Option<QueueState> {
+        if self.is_active(user_id) {
+            return Some(QueueState {
+                position: 0,
+                estimated_wait_secs: 0,
+            });
+        }
+        let position = self.position(user_id)?;
+        let average = self.average_session();
+        let active_left = self
+            .active_session
+            .as_ref()
+            .map(|session| average.saturating_sub(self.since(session.started_at)))
+            .unwrap_or_default();
+        Some(QueueState {
+            position,
+            estimated_wait_secs: (active_left + average * position as u32).as_secs(),
+        })
+    }
+
+    // Until a session has ended, sessions are assumed to run their full length
+    fn average_session(&self) -> Duration {
+        if self.sessions_ended == 0 {
+            return self.policy.max_session;
+        }
+        self.session_time / self.sessions_ended
+    }
+
+    // Broadcast on every change: where a newcomer would stand at the lowest
+    // priority, i.e. the length of the line
+    fn line_state(&self) -> QueueState {
+        QueueState {
+            position: self.waiting.len(),
+            estimated_wait_secs: self.waiting.len() as u64 * self.policy.max_session.as_secs(),
+        }
+    }
+
+    fn notify(&self) {
+        // Nobody listening is fine
+        let _ = self.updates.send(self.line_state());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // A clock that only moves when told to
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn new() -> Arc<Self> {
+            Arc::new(ManualClock(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn policy() -> SessionPolicy {
+        SessionPolicy {
+            max_session: Duration::from_secs(300),
+            max_idle: Duration::from_secs(60),
+            accept_within: Some(Duration::from_secs(20)),
+            reconnect_grace: Duration::from_secs(10),
+        }
+    }
+
+    fn session_at(clock: &ManualClock) -> Session {
+        let now = clock.now();
+        Session {
+            user_id: "alice".to_owned(),
+            started_at: now,
+            last_activity: now,
+            accepted: true,
+            disconnected_at: None,
+            resume_token: String::new(),
+        }
+    }
+
+    #[test]
+    fn evaluate_keeps_a_live_session() {
+        let clock = ManualClock::new();
+        let session = session_at(&clock);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(policy().evaluate(&session, clock.now()), None);
+    }
+
+    #[test]
+    fn evaluate_ends_at_max_duration() {
+        let clock = ManualClock::new();
+        let mut session = session_at(&clock);
+        clock.advance(Duration::from_secs(301));
+        session.last_activity = clock.now();
+        assert_eq!(
+            policy().evaluate(&session, clock.now()),
+            Some(EndReason::MaxDuration)
+        );
+    }
+
+    #[test]
+    fn evaluate_ends_when_idle() {
+        let clock = ManualClock::new();
+        let session = session_at(&clock);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(
+            policy().evaluate(&session, clock.now()),
+            Some(EndReason::Idle)
+        );
+    }
+
+    #[test]
+    fn evaluate_ends_when_unaccepted() {
+        let clock = ManualClock::new();
+        let mut session = session_at(&clock);
+        session.accepted = false;
+        clock.advance(Duration::from_secs(21));
+        assert_eq!(
+            policy().evaluate(&session, clock.now()),
+            Some(EndReason::Unaccepted)
+        );
+    }
+
+    #[test]
+    fn evaluate_ends_after_reconnect_grace() {
+        let clock = ManualClock::new();
+        let mut session = session_at(&clock);
+        session.disconnected_at = Some(clock.now());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(policy().evaluate(&session, clock.now()), None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            policy().evaluate(&session, clock.now()),
+            Some(EndReason::Disconnected)
+        );
+    }
+
+    #[test]
+    fn queue_times_out_on_a_manual_clock() {
+        let clock = ManualClock::new();
+        let mut queue = Queue::with_limits(Duration::from_secs(300), Duration::from_secs(60))
+            .with_clock(clock.clone());
+        queue.join_queue("alice", 0).unwrap();
+        queue.join_queue("bob", 0).unwrap();
+
+        // Kept active past the session cap
+        assert_eq!(queue.process_queue().as_deref(), Some("alice"));
+        for _ in 0..6 {
+            clock.advance(Duration::from_secs(50));
+            queue.update_activity("alice");
+            assert!(queue.cleanup_stale_sessions().is_none());
+        }
+        clock.advance(Duration::from_secs(1));
+        let (ended, reason) = queue.cleanup_stale_sessions().unwrap();
+        assert_eq!(
+            (ended.user_id.as_str(), reason),
+            ("alice", EndReason::MaxDuration)
+        );
+
+        // Left alone past the idle timeout
+        assert_eq!(queue.process_queue().as_deref(), Some("bob"));
+        clock.advance(Duration::from_secs(60));
+        assert!(queue.cleanup_stale_sessions().is_none());
+        clock.advance(Duration::from_secs(1));
+        let (ended, reason) = queue.cleanup_stale_sessions().unwrap();
+        assert_eq!((ended.user_id.as_str(), reason), ("bob", EndReason::Idle));
+        assert_eq!(queue.active_user(), None);
+    }
+
+    #[test]
+    fn resume_tokens_follow_their_user_across_promotion_and_restart() {
+        let mut queue = Queue::new();
+        queue.join_queue("alice", 0).unwrap();
+        queue.join_queue("bob", 0).unwrap();
+        let alice = queue.resume_token("alice").unwrap().to_owned();
+        let bob = queue.resume_token("bob").unwrap().to_owned();
+        assert_ne!(alice, bob);
+        assert_eq!(queue.resumable_user(&alice), Some("alice"));
+        assert_eq!(queue.resumable_user("alice"), None);
+
+        queue.process_queue();
+        assert_eq!(queue.resume_token("alice"), Some(alice.as_str()));
+
+        let path = std::env::temp_dir().join(format!("queue-{}.json", Uuid::new_v4()));
+        queue.save_to_path(&path).unwrap();
+        let restored = Queue::load_from_path(&path, SessionPolicy::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.resumable_user(&alice), Some("alice"));
+        assert_eq!(restored.resumable_user(&bob), Some("bob"));
+
+        queue.end_session();
+        assert_eq!(queue.resumable_user(&alice), None);
+    }
+
+    #[test]
+    fn join_past_capacity_is_refused() {
+        let mut queue = Queue::new().with_max_waiting(Some(2));
+        assert_eq!(queue.join_queue("alice", 0), Ok(true));
+        assert_eq!(queue.join_queue("bob", 0), Ok(true));
+        assert_eq!(queue.join_queue("carol", 5), Err(QueueError::Full));
+        assert_eq!(queue.position("alice"), Some(0));
+        assert_eq!(queue.position("bob"), Some(1));
+        assert_eq!(queue.position("carol"), None);
+        assert_eq!(queue.metrics_snapshot().waiting, 2);
+    }
+}
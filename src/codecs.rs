@@ -0,0 +1,237 @@
+use crate::config;
+use anyhow::{Context, Result};
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::rtp_transceiver::RTCPFeedback;
+
+fn video_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+        ("transport-cc", ""),
+    ]
+    .iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_string(),
+        parameter: parameter.to_string(),
+    })
+    .collect()
+}
+
+pub const DEFAULT_H264_FMTP: &str =
+    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f";
+
+// Peers that only decode a particular H.264 profile can have it advertised
+// instead of constrained baseline
+pub fn h264_fmtp() -> String {
+    config::setting("h264-fmtp", "RUSTWEBRTC_H264_FMTP")
+        .unwrap_or_else(|| DEFAULT_H264_FMTP.to_owned())
+}
+
+fn fmtp_param<'a>(fmtp: &'a str, key: &str) -> Option<&'a str> {
+    fmtp.split(';').find_map(|param| {
+        let (k, v) = param.trim().split_once('=')?;
+        k.eq_ignore_ascii_case(key).then_some(v)
+    })
+}
+
+// Two H.264 fmtp lines interoperate when they agree on the profile (the
+// first four hex digits of profile-level-id; the level may differ) and on
+// packetization-mode, which defaults to 0 when absent
+pub fn h264_fmtp_compatible(ours: &str, theirs: &str) -> bool {
+    let profile = |fmtp| {
+        fmtp_param(fmtp, "profile-level-id")
+            .and_then(|id| id.get(..4))
+            .map(str::to_ascii_lowercase)
+    };
+    let mode = |fmtp| fmtp_param(fmtp, "packetization-mode").unwrap_or("0");
+    profile(ours).is_some() && profile(ours) == profile(theirs) && mode(ours) == mode(theirs)
+}
+
+pub fn video_codec(name: &str) -> Option<RTCRtpCodecParameters> {
+    let (mime_type, sdp_fmtp_line, payload_type) = match name.to_ascii_lowercase().as_str() {
+        "vp8" => (MIME_TYPE_VP8, String::new(), 96),
+        "vp9" => (MIME_TYPE_VP9, "profile-id=0".to_owned(), 98),
+        "h264" => (MIME_TYPE_H264, h264_fmtp(), 102),
+        _ => return None,
+    };
+    Some(RTCRtpCodecParameters {
+        capability: RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate: 90000,
+            channels: 0,
+            sdp_fmtp_line,
+            rtcp_feedback: video_feedback(),
+        },
+        payload_type,
+        ..Default::default()
+    })
+}
+
+pub fn audio_codec(name: &str) -> Option<RTCRtpCodecParameters> {
+    match name.to_ascii_lowercase().as_str() {
+        "opus" => Some(RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                rtcp_feedback: vec![],
+            },
+            payload_type: 111,
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+pub fn codec(name: &str) -> Option<(RTCRtpCodecParameters, RTPCodecType)> {
+    video_codec(name)
+        .map(|c| (c, RTPCodecType::Video))
+        .or_else(|| audio_codec(name).map(|c| (c, RTPCodecType::Audio)))
+}
+
+// The name rtpmap lines use, e.g. "VP8" for video/VP8
+fn encoding_name(codec: &RTCRtpCodecParameters) -> &str {
+    let mime_type = codec.capability.mime_type.as_str();
+    mime_type.rsplit('/').next().unwrap_or(mime_type)
+}
+
+// Parses `vp8=96,opus=111` into (rtpmap encoding name, payload type) pairs.
+// Two codecs pinned to one number are refused.
+pub fn parse_payload_type_pins(raw: &str) -> Result<Vec<(String, u8)>> {
+    let mut pins: Vec<(String, u8)> = Vec::new();
+    for entry in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, pt) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("payload type pin '{}' is not codec=number", entry))?;
+        let (codec, _) =
+            codec(name.trim()).ok_or_else(|| anyhow::anyhow!("unknown codec {}", name))?;
+        let pt: u8 = pt
+            .trim()
+            .parse()
+            .ok()
+            .filter(|pt| *pt <= 127)
+            .ok_or_else(|| anyhow::anyhow!("payload type '{}' is not 0-127", pt))?;
+        let encoding = encoding_name(&codec).to_owned();
+        if let Some((other, _)) = pins.iter().find(|(_, taken)| *taken == pt) {
+            anyhow::bail!(
+                "{} and {} are both pinned to payload type {}",
+                other,
+                encoding,
+                pt
+            );
+        }
+        pins.push((encoding, pt));
+    }
+    Ok(pins)
+}
+
+// Payload type numbers pinned with --payload-types for gateways that expect
+// fixed ones; a malformed setting was already refused by the self-check
+pub fn payload_type_pins() -> Vec<(String, u8)> {
+    config::setting("payload-types", "RUSTWEBRTC_PAYLOAD_TYPES")
+        .and_then(|raw| parse_payload_type_pins(&raw).ok())
+        .unwrap_or_default()
+}
+
+fn pinned_codec(
+    name: &str,
+    pins: &[(String, u8)],
+) -> Option<(RTCRtpCodecParameters, RTPCodecType)> {
+    let (mut codec, kind) = codec(name)?;
+    if let Some((_, pt)) = pins
+        .iter()
+        .find(|(encoding, _)| encoding.eq_ignore_ascii_case(encoding_name(&codec)))
+    {
+        codec.payload_type = *pt;
+    }
+    Some((codec, kind))
+}
+
+// An empty list registers the engine's full default set; pinned payload
+// types apply to named codecs
+pub fn register_codecs(m: &mut MediaEngine, names: &[String]) -> Result<()> {
+    if names.is_empty() {
+        m.register_default_codecs()?;
+        return Ok(());
+    }
+    let pins = payload_type_pins();
+    for name in names {
+        let (codec, kind) =
+            pinned_codec(name, &pins).ok_or_else(|| anyhow::anyhow!("unknown codec {}", name))?;
+        m.register_codec(codec, kind)?;
+    }
+    Ok(())
+}
+
+// Registers the codecs on a throwaway engine so a bad configuration is
+// reported once at startup instead of on every new peer connection
+pub fn self_check(names: &[String]) -> Result<()> {
+    let mut m = MediaEngine::default();
+    let described = if names.is_empty() {
+        "default codecs".to_owned()
+    } else {
+        names.join(", ")
+    };
+    let pins = match config::setting("payload-types", "RUSTWEBRTC_PAYLOAD_TYPES") {
+        Some(raw) => parse_payload_type_pins(&raw).context("invalid --payload-types")?,
+        None => Vec::new(),
+    };
+    // A pin can land on the number another configured codec uses by default
+    let mut taken: Vec<(u8, &str)> = Vec::new();
+    for name in names {
+        let Some((codec, _)) = pinned_codec(name, &pins) else {
+            continue;
+        };
+        if let Some((_, other)) = taken.iter().find(|(pt, _)| *pt == codec.payload_type) {
+            anyhow::bail!(
+                "{} and {} would share payload type {}",
+                other,
+                name,
+                codec.payload_type
+            );
+        }
+        taken.push((codec.payload_type, name));
+    }
+    register_codecs(&mut m, names)
+        .with_context(|| format!("codec registration failed for {}", described))?;
+    if names.iter().any(|name| name.eq_ignore_ascii_case("h264"))
+        && fmtp_param(&h264_fmtp(), "profile-level-id").is_none()
+    {
+        anyhow::bail!("H.264 fmtp '{}' has no profile-level-id", h264_fmtp());
+    }
+    println!("Codec self-check passed for {}", described);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_registration_is_reported_by_the_self_check() {
+        let names = vec!["vp8".to_owned(), "av1".to_owned()];
+        let e = self_check(&names).unwrap_err();
+        assert!(
+            format!("{:#}", e).starts_with("codec registration failed for vp8, av1"),
+            "{:#}",
+            e
+        );
+        // The same setup is an error for each connection, not a panic
+        assert!(register_codecs(&mut MediaEngine::default(), &names).is_err());
+
+        assert!(self_check(&["vp8".to_owned(), "opus".to_owned()]).is_ok());
+        assert!(self_check(&[]).is_ok());
+    }
+}
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+// Returns the value passed as `--name value` or `--name=value` on the command line
+pub fn flag_value(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next_if(|next| !next.starts_with("--"));
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_owned());
+        }
+    }
+    None
+}
+
+pub fn has_flag(name: &str) -> bool {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    std::env::args()
+        .skip(1)
+        .any(|arg| arg == flag || arg.starts_with(&prefix))
+}
+
+// A command line flag takes precedence over the environment variable
+pub fn setting(flag: &str, env: &str) -> Option<String> {
+    flag_value(flag).or_else(|| std::env::var(env).ok())
+}
+
+pub fn parse_setting<T: FromStr>(flag: &str, env: &str, default: T) -> T {
+    match setting(flag, env) {
+        Some(raw) => match raw.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                eprintln!(
+                    "Invalid value '{}' for --{} / {}, using default",
+                    raw, flag, env
+                );
+                default
+            }
+        },
+        None => default,
+    }
+}
+
+pub fn enabled(flag: &str, env: &str) -> bool {
+    has_flag(flag)
+        || matches!(
+            std::env::var(env).as_deref(),
+            Ok("1") | Ok("true") | Ok("yes")
+        )
+}
+
+// Expands a leading `~` to $HOME, as a shell would have; paths passed
+// through env vars or quoted on the command line arrive unexpanded
+pub fn expand_home(path: String) -> String {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return path,
+    };
+    match std::env::var("HOME") {
+        Ok(home) => format!("{}{}", home, rest),
+        Err(_) => path,
+    }
+}
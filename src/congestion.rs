@@ -0,0 +1,175 @@
+// Tracks receiver feedback to decide when video should be shed and to
+// estimate the available bandwidth at connection start, and paces file
+// readers against a backed-up send path
+use std::time::{Duration, Instant};
+
+// RTCP `fraction_lost` is a fixed-point fraction of 256
+const SEVERE_LOSS: u8 = 77; // ~30%
+const RECOVERED_LOSS: u8 = 13; // ~5%
+const REPORTS_TO_SWITCH: u32 = 3;
+
+// A few consecutive bad or good reports are required before switching, so a
+// single lossy interval doesn't make video flap on and off
+#[derive(Debug, Default)]
+pub struct LossMonitor {
+    paused: bool,
+    streak: u32,
+}
+
+impl LossMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Feeds one receiver report; returns the new paused state when it changes
+    pub fn observe(&mut self, fraction_lost: u8) -> Option<bool> {
+        let pushing_state_change = if self.paused {
+            fraction_lost <= RECOVERED_LOSS
+        } else {
+            fraction_lost >= SEVERE_LOSS
+        };
+        if !pushing_state_change {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < REPORTS_TO_SWITCH {
+            return None;
+        }
+        self.streak = 0;
+        self.paused = !self.paused;
+        Some(self.paused)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProbeResult {
+    Probing,
+    // Peak bitrate the receiver reported during the window, if it sent any
+    Done(Option<u64>),
+}
+
+// Warmup estimate of the path capacity from the receiver's REMB feedback.
+// Sample tracks can't emit padding, so the probe rides on the regular media
+// and the window opens with the first RTCP feedback, i.e. once it is flowing.
+#[derive(Debug)]
+pub struct BandwidthProbe {
+    window: Duration,
+    started: Option<Instant>,
+    peak_bps: Option<u64>,
+}
+
+impl BandwidthProbe {
+    pub fn new(window: Duration) -> Self {
+        BandwidthProbe {
+            window,
+            started: None,
+            peak_bps: None,
+        }
+    }
+
+    pub fn observe(
+        &mut self,
+        now: Instant,
+        remb_bps: impl IntoIterator<Item = u64>,
+    ) -> ProbeResult {
+        let started = *self.started.get_or_insert(now);
+        if let Some(peak) = remb_bps.into_iter().max() {
+            self.peak_bps = Some(self.peak_bps.map_or(peak, |current| current.max(peak)));
+        }
+        if now.duration_since(started) < self.window {
+            ProbeResult::Probing
+        } else {
+            ProbeResult::Done(self.peak_bps)
+        }
+    }
+}
+
+// Notices a send path that has backed up: a sample write taking longer than
+// a frame interval means samples are queuing behind it. File readers pace
+// with a ticker that delays rather than bursts after such a write, so while
+// writes are slow reading simply waits on them instead of racing ahead.
+#[derive(Debug)]
+pub struct WritePacer {
+    interval: Duration,
+    backed_up: bool,
+}
+
+impl WritePacer {
+    pub fn new(interval: Duration) -> Self {
+        WritePacer {
+            interval,
+            backed_up: false,
+        }
+    }
+
+    // A ticker for the read loop that never fires missed ticks in a burst
+    pub fn ticker(&self) -> tokio::time::Interval {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker
+    }
+
+    // Feeds how long one write took; returns the new state when it changes
+    pub fn observe(&mut self, took: Duration) -> Option<bool> {
+        let backed_up = took > self.interval;
+        if backed_up == self.backed_up {
+            return None;
+        }
+        self.backed_up = backed_up;
+        Some(backed_up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustained_severe_loss_pauses_video_until_it_recovers() {
+        let mut monitor = LossMonitor::new();
+
+        // One lossy interval is not enough
+        assert_eq!(monitor.observe(200), None);
+        assert_eq!(monitor.observe(0), None);
+        assert_eq!(monitor.observe(SEVERE_LOSS), None);
+        assert_eq!(monitor.observe(SEVERE_LOSS), None);
+        assert_eq!(monitor.observe(255), Some(true));
+        assert!(monitor.is_paused());
+
+        // Moderate loss keeps video paused, sustained low loss resumes it
+        assert_eq!(monitor.observe(40), None);
+        assert_eq!(monitor.observe(RECOVERED_LOSS), None);
+        assert_eq!(monitor.observe(40), None);
+        assert_eq!(monitor.observe(0), None);
+        assert_eq!(monitor.observe(5), None);
+        assert_eq!(monitor.observe(RECOVERED_LOSS), Some(false));
+        assert!(!monitor.is_paused());
+    }
+
+    #[tokio::test]
+    async fn a_backed_up_write_delays_reading_instead_of_bursting() {
+        let interval = Duration::from_millis(20);
+        let mut pacer = WritePacer::new(interval);
+        let mut ticker = pacer.ticker();
+        ticker.tick().await;
+
+        // A write that took five frame intervals marks the path backed up...
+        tokio::time::sleep(interval * 5).await;
+        assert_eq!(pacer.observe(interval * 5), Some(true));
+        assert_eq!(pacer.observe(interval * 2), None);
+
+        // ...and the frames it held up are not read in a burst afterwards
+        let resumed = Instant::now();
+        for _ in 0..3 {
+            ticker.tick().await;
+        }
+        assert!(resumed.elapsed() >= interval * 2);
+
+        assert_eq!(pacer.observe(interval / 2), Some(false));
+    }
+}
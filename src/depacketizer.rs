@@ -0,0 +1,122 @@
+// Reassembles depacketized RTP payloads into whole frames, bounding how much
+// a sender can make us buffer for a frame that never completes
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Assembled {
+    Pending,
+    Frame(Vec<u8>),
+    // The partial frame outgrew the cap and was thrown away; the sender
+    // should be asked for a keyframe to resynchronise
+    Dropped,
+}
+
+pub struct FrameAssembler<D: Depacketizer> {
+    depacketizer: D,
+    max_frame_size: usize,
+    buffer: Vec<u8>,
+    timestamp: Option<u32>,
+    // Set after a drop so the rest of the oversized frame is ignored
+    discarding: bool,
+}
+
+impl<D: Depacketizer> FrameAssembler<D> {
+    pub fn new(depacketizer: D, max_frame_size: usize) -> Self {
+        FrameAssembler {
+            depacketizer,
+            max_frame_size,
+            buffer: Vec::new(),
+            timestamp: None,
+            discarding: false,
+        }
+    }
+
+    // Packets of one frame share a timestamp and the last carries the marker
+    // bit; a new timestamp abandons whatever was left incomplete
+    pub fn push(&mut self, packet: &Packet) -> Assembled {
+        let timestamp = packet.header.timestamp;
+        if self.timestamp != Some(timestamp) {
+            self.buffer.clear();
+            self.timestamp = Some(timestamp);
+            self.discarding = false;
+        }
+        if self.discarding {
+            return Assembled::Pending;
+        }
+        let payload = match self.depacketizer.depacketize(&packet.payload) {
+            Ok(payload) => payload,
+            Err(_) => return Assembled::Pending,
+        };
+        if self.buffer.len() + payload.len() > self.max_frame_size {
+            // Release the memory rather than just truncating
+            self.buffer = Vec::new();
+            self.discarding = true;
+            return Assembled::Dropped;
+        }
+        self.buffer.extend_from_slice(&payload);
+        if packet.header.marker {
+            self.timestamp = None;
+            return Assembled::Frame(std::mem::take(&mut self.buffer));
+        }
+        Assembled::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::codecs::vp8::Vp8Packet;
+
+    // A VP8 packet carrying `len` bytes, starting a partition when `start`
+    fn vp8(timestamp: u32, start: bool, marker: bool, len: usize) -> Packet {
+        let mut payload = vec![if start { 0x10 } else { 0x00 }];
+        payload.resize(len + 1, 0xab);
+        let mut packet = Packet {
+            payload: payload.into(),
+            ..Default::default()
+        };
+        packet.header.timestamp = timestamp;
+        packet.header.marker = marker;
+        packet
+    }
+
+    #[test]
+    fn frames_over_the_cap_are_dropped_for_a_keyframe() {
+        let mut assembler = FrameAssembler::new(Vp8Packet::default(), 1000);
+
+        // Fragments that keep coming without ever completing the frame
+        assert_eq!(
+            assembler.push(&vp8(1, true, false, 600)),
+            Assembled::Pending
+        );
+        assert_eq!(
+            assembler.push(&vp8(1, false, false, 600)),
+            Assembled::Dropped
+        );
+        assert_eq!(assembler.buffer.capacity(), 0);
+        for _ in 0..10 {
+            assert_eq!(
+                assembler.push(&vp8(1, false, false, 600)),
+                Assembled::Pending
+            );
+        }
+        assert_eq!(
+            assembler.push(&vp8(1, false, true, 600)),
+            Assembled::Pending
+        );
+        assert_eq!(assembler.buffer.capacity(), 0);
+
+        // The next frame is reassembled as usual
+        assert_eq!(
+            assembler.push(&vp8(2, true, false, 400)),
+            Assembled::Pending
+        );
+        assert_eq!(
+            assembler.push(&vp8(2, false, true, 400)),
+            Assembled::Frame(vec![0xab; 800])
+        );
+    }
+}
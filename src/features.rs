@@ -0,0 +1,110 @@
+// Capabilities a client advertises in the `hello` message it sends on
+// connecting. The server keeps the set per connection and adapts what it
+// forwards to match. A client that never says hello is assumed to support
+// everything, which is how every client was treated before negotiation.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Feature {
+    // Takes candidates as separate messages rather than only inside the SDP
+    Trickle,
+    DataChannel,
+    Simulcast,
+    // Can expand gzip-compressed SDP
+    Compression,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 4] = [
+        Feature::Trickle,
+        Feature::DataChannel,
+        Feature::Simulcast,
+        Feature::Compression,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Trickle => "trickle",
+            Feature::DataChannel => "data-channel",
+            Feature::Simulcast => "simulcast",
+            Feature::Compression => "compression",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL
+            .into_iter()
+            .find(|feature| feature.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureSet(u8);
+
+impl Default for FeatureSet {
+    fn default() -> Self {
+        FeatureSet::all()
+    }
+}
+
+impl FeatureSet {
+    pub fn all() -> Self {
+        Feature::ALL.into_iter().collect()
+    }
+
+    pub fn empty() -> Self {
+        FeatureSet(0)
+    }
+
+    // The features named in a hello, and the names that weren't recognised
+    pub fn parse(names: &[String]) -> (Self, Vec<String>) {
+        let mut set = FeatureSet::empty();
+        let mut unknown = Vec::new();
+        for name in names {
+            match Feature::from_name(name) {
+                Some(feature) => set.insert(feature),
+                None => unknown.push(name.clone()),
+            }
+        }
+        (set, unknown)
+    }
+
+    pub fn contains(self, feature: Feature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        self.0 |= feature.bit();
+    }
+
+    pub fn remove(&mut self, feature: Feature) {
+        self.0 &= !feature.bit();
+    }
+
+    pub fn intersection(self, other: FeatureSet) -> Self {
+        FeatureSet(self.0 & other.0)
+    }
+
+    pub fn names(self) -> Vec<String> {
+        Feature::ALL
+            .into_iter()
+            .filter(|feature| self.contains(*feature))
+            .map(|feature| feature.name().to_owned())
+            .collect()
+    }
+}
+
+impl FromIterator<Feature> for FeatureSet {
+    fn from_iter<I: IntoIterator<Item = Feature>>(iter: I) -> Self {
+        let mut set = FeatureSet::empty();
+        for feature in iter {
+            set.insert(feature);
+        }
+        set
+    }
+}
@@ -0,0 +1,387 @@
+// Builds ffmpeg command lines for the live capture publisher and recordings,
+// and owns the processes started from them
+use std::ops::{Deref, DerefMut};
+use std::process::Child;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetOs {
+    Linux,
+    MacOs,
+}
+
+impl TargetOs {
+    pub fn current() -> Option<Self> {
+        if cfg!(target_os = "linux") {
+            Some(TargetOs::Linux)
+        } else if cfg!(target_os = "macos") {
+            Some(TargetOs::MacOs)
+        } else {
+            None
+        }
+    }
+
+    pub fn default_device(self) -> &'static str {
+        match self {
+            TargetOs::Linux => "/dev/video0",
+            TargetOs::MacOs => "0",
+        }
+    }
+}
+
+// A regular file is played in real time on a loop, like an audio file;
+// anything else is a capture device
+pub fn capture_input_args(os: TargetOs, device: Option<&str>) -> Vec<String> {
+    let device = device.unwrap_or(os.default_device());
+    let args: &[&str] = match os {
+        _ if std::path::Path::new(device).is_file() => &["-re", "-stream_loop", "-1", "-i", device],
+        TargetOs::Linux => &["-f", "v4l2", "-i", device],
+        TargetOs::MacOs => &["-f", "avfoundation", "-framerate", "30", "-i", device],
+    };
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    pub device: Option<String>,
+    // Encodes VP9 instead of VP8; both are written as IVF
+    pub vp9: bool,
+    // Caps the encoder's worker threads so it can't take over a shared host
+    pub threads: usize,
+    // Runs ffmpeg under `nice` with this adjustment
+    pub nice: Option<i32>,
+    // Output scaling and frame rate; the device's native mode when unset
+    pub size: Option<(u32, u32)>,
+    pub fps: Option<u32>,
+    pub bitrate: u64,
+    // Set by the server while it is near its total bandwidth ceiling
+    pub bitrate_cap: Option<u64>,
+    // Passed through to ffmpeg after our own encoder options, so they win
+    pub extra_args: Vec<String>,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            device: None,
+            vp9: false,
+            threads: 2,
+            nice: None,
+            size: None,
+            fps: None,
+            bitrate: 1_000_000,
+            bitrate_cap: None,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+// Upper bounds on what a viewer may ask the encoder for
+#[derive(Clone, Copy, Debug)]
+pub struct EncoderLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: u32,
+    pub max_bitrate: u64,
+}
+
+impl Default for EncoderLimits {
+    fn default() -> Self {
+        EncoderLimits {
+            max_width: 1920,
+            max_height: 1080,
+            max_fps: 60,
+            max_bitrate: 4_000_000,
+        }
+    }
+}
+
+impl CaptureOptions {
+    pub fn effective_bitrate(&self) -> u64 {
+        self.bitrate_cap
+            .map_or(self.bitrate, |cap| self.bitrate.min(cap))
+    }
+
+    // Applies the parts of a viewer's request that are set, clamped to the
+    // limits. A size needs both dimensions; zero values are ignored.
+    pub fn apply_request(
+        &mut self,
+        width: Option<u32>,
+        height: Option<u32>,
+        fps: Option<u32>,
+        bitrate: Option<u64>,
+        limits: &EncoderLimits,
+    ) {
+        if let (Some(width), Some(height)) = (width, height) {
+            if width > 0 && height > 0 {
+                // libvpx needs even dimensions
+                let even = |v: u32| (v & !1).max(2);
+                self.size = Some((
+                    even(width.min(limits.max_width)),
+                    even(height.min(limits.max_height)),
+                ));
+            }
+        }
+        if let Some(fps) = fps.filter(|fps| *fps > 0) {
+            self.fps = Some(fps.min(limits.max_fps));
+        }
+        if let Some(bitrate) = bitrate.filter(|bitrate| *bitrate > 0) {
+            self.bitrate = bitrate.min(limits.max_bitrate);
+        }
+    }
+}
+
+// Encodes the capture as VP8 (or VP9) and writes an IVF stream to stdout
+pub fn capture_args(os: TargetOs, options: &CaptureOptions) -> Vec<String> {
+    let mut args = vec![
+        "-hide_banner".to_owned(),
+        "-loglevel".to_owned(),
+        "error".to_owned(),
+    ];
+    args.extend(capture_input_args(os, options.device.as_deref()));
+    args.extend(["-threads".to_owned(), options.threads.to_string()]);
+    if let Some((width, height)) = options.size {
+        args.extend(["-vf".to_owned(), format!("scale={}:{}", width, height)]);
+    }
+    if let Some(fps) = options.fps {
+        args.extend(["-r".to_owned(), fps.to_string()]);
+    }
+    let encoder = if options.vp9 { "libvpx-vp9" } else { "libvpx" };
+    args.extend(
+        ["-c:v", encoder, "-deadline", "realtime", "-b:v"]
+            .iter()
+            .map(|arg| arg.to_string()),
+    );
+    args.push(options.effective_bitrate().to_string());
+    args.extend(options.extra_args.iter().cloned());
+    args.extend(["-an", "-f", "ivf", "-"].iter().map(|arg| arg.to_string()));
+    args
+}
+
+// Encodes audio as Opus in Ogg on stdout, one 20 ms packet per page. A file
+// input is looped in real time; without one the platform's default
+// microphone is captured.
+pub fn audio_args(os: TargetOs, input: Option<&str>) -> Vec<String> {
+    let mut args: Vec<&str> = vec!["-hide_banner", "-loglevel", "error"];
+    match (input, os) {
+        (Some(path), _) => args.extend(["-re", "-stream_loop", "-1", "-i", path]),
+        (None, TargetOs::Linux) => args.extend(["-f", "alsa", "-i", "default"]),
+        (None, TargetOs::MacOs) => args.extend(["-f", "avfoundation", "-i", ":0"]),
+    }
+    args.extend([
+        "-vn",
+        "-c:a",
+        "libopus",
+        "-ar",
+        "48000",
+        "-ac",
+        "2",
+        "-page_duration",
+        "20000",
+        "-f",
+        "ogg",
+        "-",
+    ]);
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+// Program and arguments to spawn, wrapping ffmpeg in `nice` when requested
+pub fn capture_command(os: TargetOs, options: &CaptureOptions) -> (String, Vec<String>) {
+    let args = capture_args(os, options);
+    match options.nice {
+        Some(adjustment) => {
+            let mut nice_args = vec!["-n".to_owned(), adjustment.to_string(), "ffmpeg".to_owned()];
+            nice_args.extend(args);
+            ("nice".to_owned(), nice_args)
+        }
+        None => ("ffmpeg".to_owned(), args),
+    }
+}
+
+// `--av-source` demuxes one file with two processes, one per stream. Both
+// keep the file's timestamps, shifted so the file starts at zero, which puts
+// their outputs on one timeline. Neither is paced with -re: the streamer
+// plays both against a shared clock instead.
+fn av_input_args(path: &str) -> Vec<String> {
+    [
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-copyts",
+        "-start_at_zero",
+        "-i",
+        path,
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+// The file's first video stream as VP8 (or VP9) IVF on stdout
+pub fn av_video_args(path: &str, options: &CaptureOptions) -> Vec<String> {
+    let mut args = av_input_args(path);
+    args.extend(["-map".to_owned(), "0:v:0".to_owned()]);
+    args.extend(["-threads".to_owned(), options.threads.to_string()]);
+    let encoder = if options.vp9 { "libvpx-vp9" } else { "libvpx" };
+    args.extend(
+        ["-c:v", encoder, "-deadline", "realtime", "-b:v"]
+            .iter()
+            .map(|arg| arg.to_string()),
+    );
+    args.push(options.effective_bitrate().to_string());
+    args.extend(options.extra_args.iter().cloned());
+    args.extend(["-an", "-f", "ivf", "-"].iter().map(|arg| arg.to_string()));
+    args
+}
+
+// The file's first audio stream as Opus in Ogg on stdout, as `audio_args`
+pub fn av_audio_args(path: &str) -> Vec<String> {
+    let mut args = av_input_args(path);
+    args.extend(
+        [
+            "-map",
+            "0:a:0",
+            "-vn",
+            "-c:a",
+            "libopus",
+            "-ar",
+            "48000",
+            "-ac",
+            "2",
+            "-page_duration",
+            "20000",
+            "-f",
+            "ogg",
+            "-",
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    args
+}
+
+// Reads an IVF stream on stdin and transcodes it into an MP4 file
+pub fn mp4_transcode_args(output: &str) -> Vec<String> {
+    [
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-f",
+        "ivf",
+        "-i",
+        "-",
+        "-c:v",
+        "libx264",
+        "-preset",
+        "veryfast",
+        "-pix_fmt",
+        "yuv420p",
+        "-movflags",
+        "+faststart",
+        "-y",
+        output,
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+// Reads an IVF stream on stdin and writes low-latency baseline H.264 as a raw
+// Annex B stream to stdout
+pub fn h264_transcode_args(threads: usize) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-loglevel", "error", "-f", "ivf", "-i", "-"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    args.extend(["-threads".to_owned(), threads.to_string()]);
+    args.extend(
+        [
+            "-c:v",
+            "libx264",
+            "-preset",
+            "ultrafast",
+            "-tune",
+            "zerolatency",
+            "-profile:v",
+            "baseline",
+            "-pix_fmt",
+            "yuv420p",
+            "-an",
+            "-f",
+            "h264",
+            "-",
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    args
+}
+
+// Decodes the first frame of an IVF stream on stdin and writes it to stdout
+// as a JPEG `width` pixels wide
+pub fn thumbnail_args(width: u32) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-loglevel", "error", "-f", "ivf", "-i", "-"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    // -2 keeps the aspect ratio with an even height
+    args.extend(["-vf".to_owned(), format!("scale={}:-2", width)]);
+    args.extend(
+        ["-frames:v", "1", "-c:v", "mjpeg", "-f", "image2pipe", "-"]
+            .iter()
+            .map(|arg| arg.to_string()),
+    );
+    args
+}
+
+// avfoundation devices are indices rather than paths, so on macOS only an
+// input given as a path can be checked up front
+pub fn check_device(os: TargetOs, device: Option<&str>) -> Result<(), String> {
+    let device = device.unwrap_or(os.default_device());
+    let is_path = os == TargetOs::Linux || device.contains('/');
+    if is_path && !std::path::Path::new(device).exists() {
+        return Err(format!("capture input {} not found", device));
+    }
+    Ok(())
+}
+
+pub fn describe_failure(device: &str, stderr: &str) -> String {
+    if stderr.contains("Device or resource busy") {
+        format!("capture device {} is busy", device)
+    } else if stderr.contains("No such file or directory") {
+        format!("capture device {} not found", device)
+    } else {
+        let detail = stderr.lines().last().unwrap_or("no output").trim();
+        format!("ffmpeg failed to capture from {}: {}", device, detail)
+    }
+}
+
+// A running ffmpeg that is killed and reaped when dropped, so a task that is
+// aborted or bails out early can't leave it running
+pub struct Process(Child);
+
+impl Process {
+    pub fn new(child: Child) -> Self {
+        Process(child)
+    }
+}
+
+impl Deref for Process {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.0
+    }
+}
+
+impl DerefMut for Process {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.0
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
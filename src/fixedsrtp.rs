@@ -0,0 +1,197 @@
+// Test-only SRTP with keying material fixed up front instead of exported
+// from a DTLS handshake, so packets captured off the wire can be decrypted
+// and their payload checked byte for byte. Built only with the
+// `fixed-srtp-keys` feature; nothing in the binaries uses it, and it must
+// never be used outside tests.
+use anyhow::Result;
+use hyper::body::Bytes;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use webrtc::rtp::codecs::vp8::{Vp8Packet, Vp8Payloader};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::{new_packetizer, Packetizer};
+use webrtc::rtp::sequence::new_random_sequencer;
+use webrtc::srtp::config::{Config, SessionKeys};
+use webrtc::srtp::context::Context;
+use webrtc::srtp::protection_profile::ProtectionProfile;
+use webrtc::srtp::session::Session;
+use webrtc::util::Unmarshal;
+
+use crate::depacketizer::{Assembled, FrameAssembler, DEFAULT_MAX_FRAME_SIZE};
+
+pub const PROFILE: ProtectionProfile = ProtectionProfile::Aes128CmHmacSha1_80;
+pub const VP8_PAYLOAD_TYPE: u8 = 96;
+const MTU: usize = 1200;
+// Aes128CmHmacSha1_80's master key and salt sizes
+const KEY_LEN: usize = 16;
+const SALT_LEN: usize = 14;
+
+// One direction's master key and salt, sized for PROFILE
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedKeys {
+    pub master_key: Vec<u8>,
+    pub master_salt: Vec<u8>,
+}
+
+impl FixedKeys {
+    // Distinct, recognisable bytes derived from `seed`
+    pub fn from_seed(seed: u8) -> Self {
+        let fill = |len: usize, offset: u8| {
+            (0..len)
+                .map(|i| {
+                    seed.wrapping_mul(31)
+                        .wrapping_add(offset)
+                        .wrapping_add(i as u8)
+                })
+                .collect()
+        };
+        FixedKeys {
+            master_key: fill(KEY_LEN, 0),
+            master_salt: fill(SALT_LEN, 0x80),
+        }
+    }
+
+    // A context that decrypts what a session sending with these keys wrote
+    pub fn decryptor(&self) -> Result<Context> {
+        Ok(Context::new(
+            &self.master_key,
+            &self.master_salt,
+            PROFILE,
+            None,
+            None,
+        )?)
+    }
+}
+
+// An SRTP session on `socket` that encrypts with `local` and decrypts with
+// `remote`, skipping DTLS altogether
+pub async fn session(socket: UdpSocket, local: &FixedKeys, remote: &FixedKeys) -> Result<Session> {
+    let config = Config {
+        keys: SessionKeys {
+            local_master_key: local.master_key.clone(),
+            local_master_salt: local.master_salt.clone(),
+            remote_master_key: remote.master_key.clone(),
+            remote_master_salt: remote.master_salt.clone(),
+        },
+        profile: PROFILE,
+        ..Config::default()
+    };
+    Ok(Session::new(Arc::new(socket), config, true).await?)
+}
+
+// Packetizes one VP8 sample the way a TrackLocalStaticSample does and
+// writes it through the session
+pub async fn write_vp8_sample(session: &Session, ssrc: u32, sample: &[u8]) -> Result<usize> {
+    let mut packetizer = new_packetizer(
+        MTU,
+        VP8_PAYLOAD_TYPE,
+        ssrc,
+        Box::<Vp8Payloader>::default(),
+        Box::new(new_random_sequencer()),
+        90_000,
+    );
+    let packets = packetizer
+        .packetize(&Bytes::copy_from_slice(sample), 3000)
+        .await?;
+    for packet in &packets {
+        session.write_rtp(packet).await?;
+    }
+    Ok(packets.len())
+}
+
+// Decrypts SRTP packets read off `socket` with `decryptor` until a whole
+// VP8 frame has been reassembled
+pub async fn read_vp8_frame(socket: &UdpSocket, decryptor: &mut Context) -> Result<Vec<u8>> {
+    let mut assembler = FrameAssembler::new(Vp8Packet::default(), DEFAULT_MAX_FRAME_SIZE);
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let plain = decryptor.decrypt_rtp(&buf[..len])?;
+        let packet = Packet::unmarshal(&mut plain.as_ref())?;
+        if let Assembled::Frame(frame) = assembler.push(&packet) {
+            return Ok(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    // Larger than one packet, so reassembly across packets is covered too
+    fn sample() -> Vec<u8> {
+        // A VP8 keyframe starts with an even first byte and the start code
+        let mut sample = vec![0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a];
+        sample.extend((0..4000u32).map(|i| (i * 7 + i / 13) as u8));
+        sample
+    }
+
+    #[tokio::test]
+    async fn written_vp8_sample_decrypts_to_the_original_bytes() {
+        let (sender, receiver) = socket_pair().await;
+        let send_keys = FixedKeys::from_seed(1);
+        let session = session(sender, &send_keys, &FixedKeys::from_seed(2))
+            .await
+            .unwrap();
+
+        let sample = sample();
+        let packets = write_vp8_sample(&session, 0x1234_5678, &sample)
+            .await
+            .unwrap();
+        assert!(packets > 1);
+
+        let mut decryptor = send_keys.decryptor().unwrap();
+        let frame = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_vp8_frame(&receiver, &mut decryptor),
+        )
+        .await
+        .expect("no frame arrived")
+        .unwrap();
+        assert_eq!(frame, sample);
+        session.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wire_bytes_are_encrypted() {
+        let (sender, receiver) = socket_pair().await;
+        let send_keys = FixedKeys::from_seed(3);
+        let session = session(sender, &send_keys, &FixedKeys::from_seed(4))
+            .await
+            .unwrap();
+        write_vp8_sample(&session, 7, b"\x10\x02\x00\x9d\x01\x2a plain text")
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 1500];
+        let len = tokio::time::timeout(Duration::from_secs(5), receiver.recv(&mut buf))
+            .await
+            .expect("no packet arrived")
+            .unwrap();
+        let captured = &buf[..len];
+        assert!(!captured.windows(10).any(|w| w == b"plain text"));
+
+        let plain = send_keys
+            .decryptor()
+            .unwrap()
+            .decrypt_rtp(captured)
+            .unwrap();
+        let packet = Packet::unmarshal(&mut plain.as_ref()).unwrap();
+        assert_eq!(packet.header.ssrc, 7);
+        assert!(packet.payload.ends_with(b"plain text"));
+
+        // Any other key fails authentication
+        let mut wrong = FixedKeys::from_seed(5).decryptor().unwrap();
+        assert!(wrong.decrypt_rtp(captured).is_err());
+        session.close().await.unwrap();
+    }
+}
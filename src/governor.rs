@@ -0,0 +1,100 @@
+// Holds the server's total outbound media rate under a ceiling. Connections
+// report their running byte counts; once the sum of their rates reaches
+// CAP_AT of the ceiling, each is capped at its own rate scaled down so the
+// total lands back on that mark. Caps are lifted only once the total falls
+// below LIFT_AT, far enough under that they don't flap on and off.
+use std::collections::HashMap;
+use std::time::Instant;
+
+const CAP_AT: f64 = 0.9;
+const LIFT_AT: f64 = 0.6;
+// A new cap within this fraction of the one already sent isn't resent
+const MIN_CHANGE: f64 = 0.1;
+// Below this a video stream isn't worth watching
+pub const MIN_CAP_BPS: u64 = 100_000;
+
+#[derive(Debug)]
+struct Usage {
+    bytes: u64,
+    at: Instant,
+    rate_bps: u64,
+    cap_bps: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct BandwidthGovernor {
+    ceiling_bps: u64,
+    usage: HashMap<String, Usage>,
+}
+
+impl BandwidthGovernor {
+    pub fn new(ceiling_bps: u64) -> Self {
+        BandwidthGovernor {
+            ceiling_bps,
+            usage: HashMap::new(),
+        }
+    }
+
+    // Takes a connection's running total of media bytes sent. A count that
+    // went backwards means its peer connection was replaced, so the rate is
+    // measured again from there.
+    pub fn record(&mut self, id: &str, bytes_sent: u64, now: Instant) {
+        let Some(usage) = self.usage.get_mut(id) else {
+            self.usage.insert(
+                id.to_owned(),
+                Usage {
+                    bytes: bytes_sent,
+                    at: now,
+                    rate_bps: 0,
+                    cap_bps: None,
+                },
+            );
+            return;
+        };
+        let elapsed = now.saturating_duration_since(usage.at).as_secs_f64();
+        if bytes_sent >= usage.bytes && elapsed > 0.0 {
+            usage.rate_bps = ((bytes_sent - usage.bytes) as f64 * 8.0 / elapsed) as u64;
+        }
+        usage.bytes = bytes_sent;
+        usage.at = now;
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.usage.remove(id);
+    }
+
+    pub fn total_bps(&self) -> u64 {
+        self.usage.values().map(|usage| usage.rate_bps).sum()
+    }
+
+    // Returns the caps that changed since the last call, None meaning a cap
+    // was lifted. Connections without a measured rate yet are left alone.
+    pub fn rebalance(&mut self) -> Vec<(String, Option<u64>)> {
+        let total = self.total_bps() as f64;
+        let ceiling = self.ceiling_bps as f64;
+        let mut changes = Vec::new();
+        if total >= ceiling * CAP_AT {
+            let scale = ceiling * CAP_AT / total;
+            for (id, usage) in &mut self.usage {
+                if usage.rate_bps == 0 {
+                    continue;
+                }
+                let cap = ((usage.rate_bps as f64 * scale) as u64).max(MIN_CAP_BPS);
+                let moved = usage.cap_bps.is_none_or(|current| {
+                    (cap as f64 - current as f64).abs() > current as f64 * MIN_CHANGE
+                });
+                if moved {
+                    usage.cap_bps = Some(cap);
+                    changes.push((id.clone(), Some(cap)));
+                }
+            }
+        } else if total < ceiling * LIFT_AT {
+            for (id, usage) in &mut self.usage {
+                if usage.cap_bps.take().is_some() {
+                    changes.push((id.clone(), None));
+                }
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,547 @@
+// Minimal gzip (RFC 1952) for compressing SDP on the signaling channel.
+// Compression emits fixed-Huffman DEFLATE blocks, which suits text of a few
+// kilobytes; decompression accepts any DEFLATE stream, so payloads from the
+// browser's CompressionStream decode too.
+use anyhow::{bail, ensure, Result};
+use std::collections::HashMap;
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Bounds the match search so pathological input stays linear
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which a dynamic block lists its code length code lengths
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    out.bytes
+        .extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    deflate(data, &mut out);
+    let mut bytes = out.finish();
+    bytes.extend_from_slice(&crc32(data).to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes
+}
+
+// Refuses to produce more than `max_len` bytes, so a small hostile payload
+// can't expand into an unbounded allocation
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    ensure!(
+        data.len() >= 18 && data[..3] == [0x1f, 0x8b, 8],
+        "not gzip data"
+    );
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        let extra = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        pos += 2 + usize::from(u16::from_le_bytes([extra[0], extra[1]]));
+    }
+    for flag in [0x08, 0x10] {
+        // Zero-terminated file name and comment
+        if flags & flag != 0 {
+            let end = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            pos += end + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let body = data.get(pos..data.len() - 8).ok_or_else(truncated)?;
+    let out = inflate(body, max_len)?;
+    let trailer = &data[data.len() - 8..];
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    ensure!(crc == crc32(&out), "gzip checksum mismatch");
+    ensure!(size == out.len() as u32, "gzip length mismatch");
+    Ok(out)
+}
+
+fn truncated() -> anyhow::Error {
+    anyhow::anyhow!("truncated gzip data")
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+fn write_literal(out: &mut BitWriter, symbol: u16) {
+    let symbol = u32::from(symbol);
+    match symbol {
+        0..=143 => out.write_code(0x30 + symbol, 8),
+        144..=255 => out.write_code(0x190 + symbol - 144, 9),
+        256..=279 => out.write_code(symbol - 256, 7),
+        _ => out.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(out: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_literal(out, 257 + code as u16);
+    out.write(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        u32::from(LENGTH_EXTRA[code]),
+    );
+    let code = DIST_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    out.write_code(code as u32, 5);
+    out.write(
+        (distance - DIST_BASE[code] as usize) as u32,
+        u32::from(DIST_EXTRA[code]),
+    );
+}
+
+// One final fixed-Huffman block, with matches found through a hash of the
+// next three bytes
+fn deflate(data: &[u8], out: &mut BitWriter) {
+    out.write(1, 1); // final block
+    out.write(1, 2); // fixed Huffman codes
+    let mut chains: HashMap<[u8; 3], Vec<usize>> = HashMap::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if let Some(key) = data.get(pos..pos + MIN_MATCH) {
+            let key = [key[0], key[1], key[2]];
+            if let Some(candidates) = chains.get(&key) {
+                let limit = (data.len() - pos).min(MAX_MATCH);
+                for &start in candidates.iter().rev().take(MAX_CHAIN) {
+                    if pos - start > WINDOW {
+                        break;
+                    }
+                    let length = data[start..]
+                        .iter()
+                        .zip(&data[pos..pos + limit])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if length > best.0 {
+                        best = (length, pos - start);
+                    }
+                }
+            }
+        }
+        let advance = if best.0 >= MIN_MATCH {
+            write_match(out, best.0, best.1);
+            best.0
+        } else {
+            write_literal(out, u16::from(data[pos]));
+            1
+        };
+        for at in pos..pos + advance {
+            if let Some(key) = data.get(at..at + MIN_MATCH) {
+                chains.entry([key[0], key[1], key[2]]).or_default().push(at);
+            }
+        }
+        pos += advance;
+    }
+    write_literal(out, 256);
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u32> {
+        let byte = *self.data.get(self.pos).ok_or_else(truncated)?;
+        let value = u32::from(byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            value |= self.bit()? << i;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+// Canonical Huffman code as the number of codes of each length plus the
+// symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[usize::from(offsets[usize::from(length)])] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= input.bit()? as i32;
+            let count = i32::from(self.counts[length]);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("invalid Huffman code")
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(input: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[index] = input.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let symbol = code_length_code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| anyhow::anyhow!("repeat with no previous length"))?;
+                (previous, 3 + input.bits(2)?)
+            }
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    ensure!(
+        lengths.len() == literals + distances,
+        "code lengths overrun the table"
+    );
+    Ok((
+        Huffman::new(&lengths[..literals]),
+        Huffman::new(&lengths[literals..]),
+    ))
+}
+
+fn inflate(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut input = BitReader {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = input.bit()? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                let header = data.get(input.pos..input.pos + 4).ok_or_else(truncated)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                ensure!(len == !nlen, "corrupt stored block length");
+                let len = usize::from(len);
+                let stored = data
+                    .get(input.pos + 4..input.pos + 4 + len)
+                    .ok_or_else(truncated)?;
+                ensure!(out.len() + len <= max_len, "decompressed data too large");
+                out.extend_from_slice(stored);
+                input.pos += 4 + len;
+            }
+            kind @ (1 | 2) => {
+                let (literal, distance) = if kind == 1 {
+                    fixed_codes()
+                } else {
+                    dynamic_codes(&mut input)?
+                };
+                loop {
+                    let symbol = literal.decode(&mut input)?;
+                    if symbol < 256 {
+                        ensure!(out.len() < max_len, "decompressed data too large");
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+                    let code = usize::from(symbol - 257);
+                    ensure!(code < LENGTH_BASE.len(), "invalid length code");
+                    let length = usize::from(LENGTH_BASE[code])
+                        + input.bits(u32::from(LENGTH_EXTRA[code]))? as usize;
+                    let code = usize::from(distance.decode(&mut input)?);
+                    ensure!(code < DIST_BASE.len(), "invalid distance code");
+                    let back = usize::from(DIST_BASE[code])
+                        + input.bits(u32::from(DIST_EXTRA[code]))? as usize;
+                    ensure!(back <= out.len(), "distance before start of data");
+                    ensure!(out.len() + length <= max_len, "decompressed data too large");
+                    let start = out.len() - back;
+                    for i in 0..length {
+                        out.push(out[start + i]);
+                    }
+                }
+            }
+            _ => bail!("invalid DEFLATE block type"),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::{compress_sdp, decompress_sdp};
+    use base64::{engine::general_purpose, Engine};
+
+    const SDP: &str = "v=0\r\n\
+o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+t=0 0\r\n\
+a=group:BUNDLE 0 1\r\n\
+a=extmap-allow-mixed\r\n\
+a=msid-semantic: WMS stream\r\n\
+m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=rtcp:9 IN IP4 0.0.0.0\r\n\
+a=candidate:1 1 udp 2130706431 192.168.1.10 54321 typ host generation 0\r\n\
+a=candidate:2 1 udp 1694498815 203.0.113.7 61000 typ srflx raddr 192.168.1.10 rport 54321\r\n\
+a=ice-ufrag:Fx8q\r\n\
+a=ice-pwd:Hc0Rk3e3m1VxQx0Y1m4w2F0n\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 5B:0E:33:9C:7A:AC:62:56:0D:4C:36:6B:1F:9A:28:11:B6:2D:6A:93:FE:22:0C:55:7E:F4:A8:91:3F:50:11:DE\r\n\
+a=setup:actpass\r\n\
+a=mid:0\r\n\
+a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+a=extmap:2 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r\n\
+a=sendrecv\r\n\
+a=msid:stream audio\r\n\
+a=rtcp-mux\r\n\
+a=rtpmap:111 opus/48000/2\r\n\
+a=rtcp-fb:111 transport-cc\r\n\
+a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+a=rtpmap:63 red/48000/2\r\n\
+a=rtpmap:9 G722/8000\r\n\
+a=rtpmap:0 PCMU/8000\r\n\
+a=rtpmap:8 PCMA/8000\r\n\
+m=video 9 UDP/TLS/RTP/SAVPF 96 97 98 99\r\n\
+c=IN IP4 0.0.0.0\r\n\
+a=rtcp:9 IN IP4 0.0.0.0\r\n\
+a=ice-ufrag:Fx8q\r\n\
+a=ice-pwd:Hc0Rk3e3m1VxQx0Y1m4w2F0n\r\n\
+a=ice-options:trickle\r\n\
+a=fingerprint:sha-256 5B:0E:33:9C:7A:AC:62:56:0D:4C:36:6B:1F:9A:28:11:B6:2D:6A:93:FE:22:0C:55:7E:F4:A8:91:3F:50:11:DE\r\n\
+a=setup:actpass\r\n\
+a=mid:1\r\n\
+a=extmap:2 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r\n\
+a=sendrecv\r\n\
+a=msid:stream video\r\n\
+a=rtcp-mux\r\n\
+a=rtcp-rsize\r\n\
+a=rtpmap:96 VP8/90000\r\n\
+a=rtcp-fb:96 goog-remb\r\n\
+a=rtcp-fb:96 transport-cc\r\n\
+a=rtcp-fb:96 ccm fir\r\n\
+a=rtcp-fb:96 nack\r\n\
+a=rtcp-fb:96 nack pli\r\n\
+a=rtpmap:97 rtx/90000\r\n\
+a=fmtp:97 apt=96\r\n\
+a=rtpmap:98 VP9/90000\r\n\
+a=rtcp-fb:98 goog-remb\r\n\
+a=rtcp-fb:98 transport-cc\r\n\
+a=rtcp-fb:98 ccm fir\r\n\
+a=rtcp-fb:98 nack\r\n\
+a=rtcp-fb:98 nack pli\r\n\
+a=fmtp:98 profile-id=0\r\n\
+a=rtpmap:99 rtx/90000\r\n\
+a=fmtp:99 apt=98\r\n";
+
+    #[test]
+    fn sdp_round_trips_and_shrinks() {
+        let encoded = compress_sdp(SDP);
+        assert!(
+            encoded.len() < SDP.len(),
+            "{} >= {}",
+            encoded.len(),
+            SDP.len()
+        );
+        assert_eq!(decompress_sdp(&encoded, SDP.len()).unwrap(), SDP);
+    }
+
+    #[test]
+    fn round_trips_edge_inputs() {
+        let long_run = vec![b'a'; 70_000];
+        let noise: Vec<u8> = (0..5_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        for data in [&[][..], b"x", &long_run, &noise] {
+            assert_eq!(decompress(&compress(data), data.len()).unwrap(), data);
+        }
+    }
+
+    // Written by zlib at level 9, so the body is a dynamic-Huffman block
+    // rather than the fixed codes `compress` emits
+    #[test]
+    fn decodes_dynamic_blocks() {
+        let gz = general_purpose::STANDARD
+            .decode(
+                "H4sIAAAAAAACA9XLQQqDMBAF0L3gHeYEw/yZGBPBwwTT0oKo1BTa2yviHcTtg5f6IU35nVN5dCDQNy+k\
+                 MGnFO9shKsMHBkOocaag8l/oNa+lrtLNr54XPjoXQ0BDKsbCgHFLHiJy1PXzHH/X3Q32WcclpAEAAA==",
+            )
+            .unwrap();
+        let mut expected =
+            "a=candidate:1 1 udp 2130706431 192.168.1.10 54321 typ host\r\n".repeat(4);
+        expected += &"a=candidate:2 1 udp 1694498815 203.0.113.7 61000 typ srflx\r\n".repeat(3);
+        assert_eq!(decompress(&gz, 1024).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let gz = compress(SDP.as_bytes());
+        for len in [0, 10, 17, gz.len() / 2, gz.len() - 1] {
+            assert!(
+                decompress(&gz[..len], SDP.len()).is_err(),
+                "accepted {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_checksum_is_an_error() {
+        let mut gz = compress(SDP.as_bytes());
+        let crc = gz.len() - 8;
+        gz[crc] ^= 1;
+        assert!(decompress(&gz, SDP.len()).is_err());
+    }
+
+    // A final stored block holding "hello", with `nlen` as its NLEN field
+    fn stored_hello(nlen: u16) -> Vec<u8> {
+        let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 0x01, 5, 0];
+        gz.extend_from_slice(&nlen.to_le_bytes());
+        gz.extend_from_slice(b"hello");
+        gz.extend_from_slice(&crc32(b"hello").to_le_bytes());
+        gz.extend_from_slice(&5u32.to_le_bytes());
+        gz
+    }
+
+    #[test]
+    fn stored_block_length_must_match_its_complement() {
+        assert_eq!(decompress(&stored_hello(!5), 100).unwrap(), b"hello");
+        let err = decompress(&stored_hello(!4), 100).unwrap_err();
+        assert!(err.to_string().contains("stored block"), "{}", err);
+    }
+
+    #[test]
+    fn output_past_max_len_is_refused() {
+        let gz = compress(&[0u8; 100_000]);
+        assert!(gz.len() < 1_000);
+        let err = decompress(&gz, 99_999).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+        assert!(decompress(&gz, 100_000).is_ok());
+        assert!(decompress_sdp(&compress_sdp(SDP), SDP.len() - 1).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+// Groups the NAL units of an Annex B H.264 stream into access units, the unit
+// a TrackLocalStaticSample expects per sample
+use std::io::Read;
+use webrtc::media::io::h264_reader::{H264Reader, NalUnitType};
+
+const ANNEX_B_START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+// Parameter sets travel with the picture that follows them. Returns None at
+// the end of the stream.
+pub fn next_access_unit<R: Read>(reader: &mut H264Reader<R>) -> Option<Vec<u8>> {
+    let mut access_unit = Vec::new();
+    while let Ok(nal) = reader.next_nal() {
+        access_unit.extend_from_slice(&ANNEX_B_START_CODE);
+        access_unit.extend_from_slice(&nal.data);
+        if matches!(
+            nal.unit_type,
+            NalUnitType::CodedSliceIdr | NalUnitType::CodedSliceNonIdr
+        ) {
+            return Some(access_unit);
+        }
+    }
+    None
+}
+
+// Splits an Annex B byte stream that arrives in arbitrary chunks, such as
+// reads from a pipe, into the same access units as `next_access_unit`. Bytes
+// after the last start code are held back until the next one shows where
+// that NAL unit ends.
+#[derive(Default)]
+pub struct AnnexBFramer {
+    buffer: Vec<u8>,
+    access_unit: Vec<u8>,
+}
+
+impl AnnexBFramer {
+    // Returns every access unit the chunk completed, oldest first
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        let starts = start_codes(&self.buffer);
+        let mut complete = Vec::new();
+        for pair in starts.windows(2) {
+            let (_, payload) = pair[0];
+            let (next, _) = pair[1];
+            let nal = self.buffer[payload..next].to_vec();
+            self.add_nal(&nal, &mut complete);
+        }
+        if let Some(&(last, _)) = starts.last() {
+            self.buffer.drain(..last);
+        }
+        complete
+    }
+
+    // Flushes the NAL unit left at the end of the stream and returns the
+    // access unit it closed, if any
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut complete = Vec::new();
+        if let Some(&(_, payload)) = start_codes(&buffer).first() {
+            self.add_nal(&buffer[payload..], &mut complete);
+        }
+        complete.pop()
+    }
+
+    fn add_nal(&mut self, nal: &[u8], complete: &mut Vec<Vec<u8>>) {
+        let Some(header) = nal.first() else { return };
+        self.access_unit.extend_from_slice(&ANNEX_B_START_CODE);
+        self.access_unit.extend_from_slice(nal);
+        // Coded slices (non-IDR and IDR) end an access unit
+        if matches!(header & 0x1f, 1 | 5) {
+            complete.push(std::mem::take(&mut self.access_unit));
+        }
+    }
+}
+
+// (start code offset, payload offset) of every 3- or 4-byte start code
+fn start_codes(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            let start = if i > 0 && data[i - 1] == 0 { i - 1 } else { i };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+}
@@ -0,0 +1,135 @@
+// ICE servers beyond the STUN fallback list, given as JSON in the shape of
+// the browser's RTCIceServer:
+// `[{"urls": ["turn:turn.example.com:3478"], "username": "u", "credential": "p"}]`
+// and tracking of the address families each side's candidates use
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Urls {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct IceServerEntry {
+    urls: Urls,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+fn is_turn(url: &str) -> bool {
+    url.starts_with("turn:") || url.starts_with("turns:")
+}
+
+// TURN uses long-term credentials, so a TURN url without both a username and
+// a credential could never allocate and is refused up front
+pub fn parse_ice_servers(json: &str) -> Result<Vec<RTCIceServer>> {
+    let entries: Vec<IceServerEntry> = serde_json::from_str(json)?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let urls = match entry.urls {
+                Urls::One(url) => vec![url],
+                Urls::Many(urls) => urls,
+            };
+            if urls.is_empty() {
+                bail!("ICE server entry has no urls");
+            }
+            for url in &urls {
+                if !url.starts_with("stun:") && !is_turn(url) {
+                    bail!("ICE server url '{}' must use stun:, turn: or turns:", url);
+                }
+                if is_turn(url) && (entry.username.is_none() || entry.credential.is_none()) {
+                    bail!("TURN server '{}' needs a username and credential", url);
+                }
+            }
+            Ok(RTCIceServer {
+                urls,
+                username: entry.username.unwrap_or_default(),
+                credential: entry.credential.unwrap_or_default(),
+                credential_type: RTCIceCredentialType::Password,
+            })
+        })
+        .collect()
+}
+
+pub fn has_relay(servers: &[RTCIceServer]) -> bool {
+    servers.iter().flat_map(|s| &s.urls).any(|url| is_turn(url))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+// Family of a candidate's connection address, with or without the `a=` and
+// `candidate:` prefixes. mDNS hostnames give None since their family isn't
+// known until they resolve.
+pub fn candidate_family(candidate: &str) -> Option<AddressFamily> {
+    let candidate = candidate.trim();
+    let candidate = candidate.strip_prefix("a=").unwrap_or(candidate);
+    let candidate = candidate.strip_prefix("candidate:").unwrap_or(candidate);
+    match candidate.split_whitespace().nth(4)?.parse().ok()? {
+        IpAddr::V4(_) => Some(AddressFamily::V4),
+        IpAddr::V6(_) => Some(AddressFamily::V6),
+    }
+}
+
+// Candidates pair only within one address family, so if every local candidate
+// is IPv4 and every remote one IPv6 (or the reverse) ICE can never succeed.
+// A TURN relay can bridge the two.
+#[derive(Debug, Default)]
+pub struct FamilyTracker {
+    local: BTreeSet<AddressFamily>,
+    remote: BTreeSet<AddressFamily>,
+}
+
+impl FamilyTracker {
+    pub fn add_local(&mut self, candidate: &str) {
+        self.local.extend(candidate_family(candidate));
+    }
+
+    pub fn add_remote(&mut self, candidate: &str) {
+        self.remote.extend(candidate_family(candidate));
+    }
+
+    // Every candidate embedded in a remote description
+    pub fn add_remote_sdp(&mut self, sdp: &str) {
+        for line in sdp.lines().filter(|line| line.starts_with("a=candidate:")) {
+            self.add_remote(line);
+        }
+    }
+
+    // A new peer connection gathers again
+    pub fn clear_local(&mut self) {
+        self.local.clear();
+    }
+
+    // A new negotiation brings a new set of remote candidates
+    pub fn clear_remote(&mut self) {
+        self.remote.clear();
+    }
+
+    // The local and remote families when both sides have candidates of a
+    // known family but none in common
+    pub fn mismatch(&self) -> Option<(Vec<AddressFamily>, Vec<AddressFamily>)> {
+        let disjoint = !self.local.is_empty()
+            && !self.remote.is_empty()
+            && self.local.is_disjoint(&self.remote);
+        disjoint.then(|| {
+            (
+                self.local.iter().copied().collect(),
+                self.remote.iter().copied().collect(),
+            )
+        })
+    }
+}
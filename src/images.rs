@@ -0,0 +1,116 @@
+// Reassembles images sent over the "images" data channel, which keeps large
+// captures off the signaling socket. The sender announces each image with a
+// small JSON text message giving its size, then sends the bytes as binary
+// messages; the image is complete once that many have arrived.
+use serde::Deserialize;
+
+pub const CHANNEL_LABEL: &str = "images";
+pub const DEFAULT_MAX_IMAGE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct Announcement {
+    pub size: usize,
+    #[serde(default)]
+    pub mime: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Image {
+    pub bytes: Vec<u8>,
+    pub mime: Option<String>,
+}
+
+impl Image {
+    pub fn extension(&self) -> &'static str {
+        match self.mime.as_deref() {
+            Some("image/png") => "png",
+            Some("image/jpeg") => "jpg",
+            Some("image/webp") => "webp",
+            _ => "bin",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    BadAnnouncement(String),
+    TooLarge { size: usize, max: usize },
+    // Bytes arrived with no announced image to put them in
+    Unannounced,
+    // More bytes arrived than were announced; the image is dropped
+    Overrun { expected: usize, received: usize },
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::BadAnnouncement(e) => write!(f, "bad image announcement: {}", e),
+            ImageError::TooLarge { size, max } => {
+                write!(f, "image of {} bytes is over the {} byte limit", size, max)
+            }
+            ImageError::Unannounced => write!(f, "image data arrived before an announcement"),
+            ImageError::Overrun { expected, received } => write!(
+                f,
+                "image announced as {} bytes sent at least {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+#[derive(Debug)]
+pub struct ImageAssembler {
+    max_size: usize,
+    pending: Option<(Announcement, Vec<u8>)>,
+}
+
+impl ImageAssembler {
+    pub fn new(max_size: usize) -> Self {
+        ImageAssembler {
+            max_size,
+            pending: None,
+        }
+    }
+
+    // Starts a new image. One still incomplete is abandoned: the sender has
+    // moved on from it.
+    pub fn announce(&mut self, text: &str) -> Result<(), ImageError> {
+        self.pending = None;
+        let announcement: Announcement =
+            serde_json::from_str(text).map_err(|e| ImageError::BadAnnouncement(e.to_string()))?;
+        if announcement.size == 0 {
+            return Err(ImageError::BadAnnouncement("empty image".to_owned()));
+        }
+        if announcement.size > self.max_size {
+            return Err(ImageError::TooLarge {
+                size: announcement.size,
+                max: self.max_size,
+            });
+        }
+        let buffer = Vec::with_capacity(announcement.size);
+        self.pending = Some((announcement, buffer));
+        Ok(())
+    }
+
+    // Adds one binary chunk, returning the image once it is complete
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Image>, ImageError> {
+        let (announcement, buffer) = self.pending.as_mut().ok_or(ImageError::Unannounced)?;
+        let received = buffer.len() + chunk.len();
+        if received > announcement.size {
+            let expected = announcement.size;
+            self.pending = None;
+            return Err(ImageError::Overrun { expected, received });
+        }
+        buffer.extend_from_slice(chunk);
+        if received < announcement.size {
+            return Ok(None);
+        }
+        let (announcement, bytes) = self.pending.take().expect("checked above");
+        Ok(Some(Image {
+            bytes,
+            mime: announcement.mime,
+        }))
+    }
+}
@@ -1 +1,30 @@
-
+pub mod auth;
+pub mod avsync;
+pub mod brain;
+pub mod codecs;
+pub mod config;
+pub mod congestion;
+pub mod depacketizer;
+pub mod features;
+pub mod ffmpeg;
+#[cfg(feature = "fixed-srtp-keys")]
+pub mod fixedsrtp;
+pub mod governor;
+pub mod gzip;
+pub mod h264;
+pub mod ice;
+pub mod images;
+pub mod oneway;
+pub mod ratelimit;
+pub mod recording;
+pub mod scheduler;
+pub mod sdp;
+pub mod shutdown;
+pub mod signaling;
+pub mod storage;
+pub mod stun;
+pub mod thumbnail;
+pub mod tls;
+pub mod transcode;
+pub mod transcript;
+pub mod webhook;
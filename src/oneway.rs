@@ -0,0 +1,131 @@
+// Spots media flowing in only one direction on a connection, the usual sign
+// of a NAT or firewall passing traffic one way. Connections report running
+// byte counts for both directions; once one count has stood still for the
+// threshold while the other kept growing, the silent direction is reported,
+// once, until it flows again. A direction only counts once it has carried
+// media, since a receive-only connection looks the same as a blocked one.
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    bytes: u64,
+    // When the count last grew; unset until it first does
+    grew_at: Option<Instant>,
+}
+
+impl Counter {
+    fn update(&mut self, bytes: u64, now: Instant) {
+        // A count that went backwards belongs to a replacement peer
+        // connection, so it starts over from there
+        if bytes != self.bytes {
+            self.grew_at = (bytes > 0).then_some(now);
+        }
+        self.bytes = bytes;
+    }
+
+    fn active(&self, now: Instant, threshold: Duration) -> bool {
+        self.grew_at
+            .is_some_and(|at| now.saturating_duration_since(at) < threshold)
+    }
+
+    fn stalled(&self, now: Instant, threshold: Duration) -> bool {
+        self.grew_at
+            .is_some_and(|at| now.saturating_duration_since(at) >= threshold)
+    }
+}
+
+#[derive(Debug)]
+pub struct FlowMonitor {
+    threshold: Duration,
+    sent: Counter,
+    received: Counter,
+    warned: Option<Direction>,
+}
+
+impl FlowMonitor {
+    pub fn new(threshold: Duration) -> Self {
+        FlowMonitor {
+            threshold,
+            sent: Counter::default(),
+            received: Counter::default(),
+            warned: None,
+        }
+    }
+
+    // Takes the connection's running totals; returns the direction that just
+    // went silent while the other kept flowing, if one did
+    pub fn observe(
+        &mut self,
+        bytes_sent: u64,
+        bytes_received: u64,
+        now: Instant,
+    ) -> Option<Direction> {
+        self.sent.update(bytes_sent, now);
+        self.received.update(bytes_received, now);
+        let silent = if self.sent.stalled(now, self.threshold)
+            && self.received.active(now, self.threshold)
+        {
+            Some(Direction::Outbound)
+        } else if self.received.stalled(now, self.threshold)
+            && self.sent.active(now, self.threshold)
+        {
+            Some(Direction::Inbound)
+        } else {
+            None
+        };
+        if silent == self.warned {
+            return None;
+        }
+        self.warned = silent;
+        silent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_silent_direction_is_reported_once() {
+        let threshold = Duration::from_secs(10);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut monitor = FlowMonitor::new(threshold);
+
+        // Both directions flow, then inbound stops while outbound keeps going
+        assert_eq!(monitor.observe(1_000, 1_000, at(0)), None);
+        assert_eq!(monitor.observe(2_000, 2_000, at(5)), None);
+        assert_eq!(monitor.observe(3_000, 2_000, at(10)), None);
+        assert_eq!(
+            monitor.observe(4_000, 2_000, at(15)),
+            Some(Direction::Inbound)
+        );
+        assert_eq!(monitor.observe(5_000, 2_000, at(20)), None);
+
+        // Inbound recovers, then outbound goes silent instead
+        assert_eq!(monitor.observe(6_000, 3_000, at(25)), None);
+        assert_eq!(monitor.observe(6_000, 4_000, at(30)), None);
+        assert_eq!(
+            monitor.observe(6_000, 5_000, at(35)),
+            Some(Direction::Outbound)
+        );
+    }
+
+    #[test]
+    fn a_direction_that_never_carried_media_is_not_reported() {
+        let mut monitor = FlowMonitor::new(Duration::from_secs(10));
+        let start = Instant::now();
+        for secs in (0..60).step_by(5) {
+            let now = start + Duration::from_secs(secs);
+            assert_eq!(monitor.observe(1_000 * (secs + 1), 0, now), None);
+        }
+    }
+}
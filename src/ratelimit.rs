@@ -0,0 +1,116 @@
+// Limits how fast one client may send signaling messages. A token bucket
+// lets short bursts through; messages beyond it are dropped, and a client
+// that keeps overrunning it for SUSTAINED_ABUSE is to be disconnected.
+use std::time::{Duration, Instant};
+
+pub const SUSTAINED_ABUSE: Duration = Duration::from_secs(5);
+// Drops this far apart are treated as separate bursts rather than one flood
+const ABUSE_GAP: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub struct MessageLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+    // When the current run of drops began, and the latest drop in it
+    abusing_since: Option<Instant>,
+    last_drop: Option<Instant>,
+    dropped: u64,
+}
+
+impl MessageLimiter {
+    // Allows `per_sec` messages a second on average and up to `burst` at once
+    pub fn new(per_sec: u32, burst: u32, now: Instant) -> Self {
+        MessageLimiter {
+            rate: per_sec as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: now,
+            abusing_since: None,
+            last_drop: None,
+            dropped: 0,
+        }
+    }
+
+    // Messages dropped over the limiter's lifetime
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        if self
+            .last_drop
+            .is_some_and(|at| now.saturating_duration_since(at) > ABUSE_GAP)
+        {
+            self.abusing_since = None;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+        self.dropped += 1;
+        self.last_drop = Some(now);
+        let since = *self.abusing_since.get_or_insert(now);
+        if now.saturating_duration_since(since) >= SUSTAINED_ABUSE {
+            Verdict::Disconnect
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends `count` messages evenly over one second from `start`
+    fn flood(limiter: &mut MessageLimiter, start: Instant, count: u32) -> Vec<Verdict> {
+        (0..count)
+            .map(|i| limiter.check(start + Duration::from_secs(1) * i / count))
+            .collect()
+    }
+
+    #[test]
+    fn messages_over_the_rate_are_dropped_and_counted() {
+        let start = Instant::now();
+        let mut limiter = MessageLimiter::new(50, 100, start);
+
+        // The burst goes through at once, then only the refill rate
+        let verdicts = flood(&mut limiter, start, 1000);
+        let allowed = verdicts.iter().filter(|v| **v == Verdict::Allow).count();
+        assert!((149..=151).contains(&allowed), "{} allowed", allowed);
+        assert!(!verdicts.contains(&Verdict::Disconnect));
+        assert_eq!(limiter.dropped(), 1000 - allowed as u64);
+
+        // A client back under the rate is let through again
+        let later = start + Duration::from_secs(3);
+        assert_eq!(limiter.check(later), Verdict::Allow);
+    }
+
+    #[test]
+    fn sustained_flooding_disconnects() {
+        let start = Instant::now();
+        let mut limiter = MessageLimiter::new(50, 100, start);
+        let seconds = SUSTAINED_ABUSE.as_secs() as u32;
+        let verdicts: Vec<Verdict> = (0..=seconds)
+            .flat_map(|s| flood(&mut limiter, start + Duration::from_secs(s.into()), 1000))
+            .collect();
+        assert_eq!(verdicts.last(), Some(&Verdict::Disconnect));
+        let first = verdicts
+            .iter()
+            .position(|v| *v == Verdict::Disconnect)
+            .unwrap();
+        assert!(first >= 1000 * seconds as usize);
+    }
+}
@@ -0,0 +1,392 @@
+// Writes reassembled frames from an inbound track to one or more outputs: VP8
+// as IVF and/or MP4, H.264 as a raw Annex B stream
+use crate::{ffmpeg, gzip};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+// Every recording file starts with this, which is how retention tells them
+// apart from anything else in the directory
+pub const FILE_PREFIX: &str = "recording_";
+// Files written to this recently are assumed to still be recording
+const IN_PROGRESS: Duration = Duration::from_secs(60);
+// Compression reads the whole file into memory, so larger ones stay as they are
+const MAX_COMPRESS_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ivf,
+    Mp4,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Ivf => "ivf",
+            Format::Mp4 => "mp4",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ivf" => Ok(Format::Ivf),
+            "mp4" => Ok(Format::Mp4),
+            other => Err(format!("unknown recording format '{}'", other)),
+        }
+    }
+}
+
+// Minimal IVF muxer; the header is written lazily from the first keyframe so
+// it can carry the real resolution
+pub struct IvfWriter<W: Write> {
+    out: W,
+    first_timestamp: Option<u32>,
+}
+
+impl<W: Write> IvfWriter<W> {
+    pub fn new(out: W) -> Self {
+        IvfWriter {
+            out,
+            first_timestamp: None,
+        }
+    }
+
+    // Frames before the first keyframe can't be decoded, so they're skipped.
+    // Timestamps are RTP ticks and the IVF timebase is set to match (1/90000).
+    pub fn write_frame(&mut self, frame: &[u8], rtp_timestamp: u32) -> io::Result<()> {
+        let first = match self.first_timestamp {
+            Some(first) => first,
+            None => {
+                let Some((width, height)) = vp8_keyframe_size(frame) else {
+                    return Ok(());
+                };
+                self.write_header(width, height)?;
+                *self.first_timestamp.insert(rtp_timestamp)
+            }
+        };
+        let pts = u64::from(rtp_timestamp.wrapping_sub(first));
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&pts.to_le_bytes())?;
+        self.out.write_all(frame)
+    }
+
+    fn write_header(&mut self, width: u16, height: u16) -> io::Result<()> {
+        let mut header = Vec::with_capacity(32);
+        header.extend_from_slice(b"DKIF");
+        header.extend_from_slice(&0u16.to_le_bytes()); // version
+        header.extend_from_slice(&32u16.to_le_bytes()); // header size
+        header.extend_from_slice(b"VP80");
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header.extend_from_slice(&90000u32.to_le_bytes()); // timebase denominator
+        header.extend_from_slice(&1u32.to_le_bytes()); // timebase numerator
+        header.extend_from_slice(&0u32.to_le_bytes()); // frame count, unknown while live
+        header.extend_from_slice(&0u32.to_le_bytes()); // unused
+        self.out.write_all(&header)
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Resolution from a VP8 keyframe's uncompressed header, None for inter frames
+pub fn vp8_keyframe_size(frame: &[u8]) -> Option<(u16, u16)> {
+    let is_keyframe = frame.first()? & 0x01 == 0;
+    if !is_keyframe || frame.get(3..6)? != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let size = frame.get(6..10)?;
+    let width = u16::from_le_bytes([size[0], size[1]]) & 0x3fff;
+    let height = u16::from_le_bytes([size[2], size[3]]) & 0x3fff;
+    Some((width, height))
+}
+
+enum Sink {
+    Ivf(IvfWriter<BufWriter<File>>),
+    // Access units already carry their start codes, so they're written as is
+    AnnexB(BufWriter<File>),
+    // ffmpeg transcodes the IVF we pipe to its stdin
+    Mp4 {
+        writer: IvfWriter<ChildStdin>,
+        child: Child,
+    },
+}
+
+// Every configured sink is fed the same frames; a sink that fails is logged
+// and dropped without stopping the others
+pub struct Recording {
+    sinks: Vec<(PathBuf, Sink)>,
+}
+
+impl Recording {
+    pub fn create(dir: &Path, name: &str, formats: &[Format]) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut sinks = Vec::new();
+        for &format in formats {
+            let path = dir.join(format!("{}.{}", name, format.extension()));
+            let sink = match format {
+                Format::Ivf => Sink::Ivf(IvfWriter::new(BufWriter::new(
+                    File::options().write(true).create_new(true).open(&path)?,
+                ))),
+                Format::Mp4 => {
+                    let mut child = Command::new("ffmpeg")
+                        .args(ffmpeg::mp4_transcode_args(&path.to_string_lossy()))
+                        .stdin(Stdio::piped())
+                        .spawn()?;
+                    let stdin = child.stdin.take().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::BrokenPipe, "ffmpeg stdin unavailable")
+                    })?;
+                    Sink::Mp4 {
+                        writer: IvfWriter::new(stdin),
+                        child,
+                    }
+                }
+            };
+            sinks.push((path, sink));
+        }
+        Ok(Recording { sinks })
+    }
+
+    // H.264 has a single output, `<name>.h264`, whatever formats VP8 uses
+    pub fn create_h264(dir: &Path, name: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.h264", name));
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok(Recording {
+            sinks: vec![(path, Sink::AnnexB(BufWriter::new(file)))],
+        })
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.sinks.iter().map(|(path, _)| path.as_path())
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8], rtp_timestamp: u32) {
+        self.sinks.retain_mut(|(path, sink)| {
+            let result = match sink {
+                Sink::Ivf(writer) => writer.write_frame(frame, rtp_timestamp),
+                Sink::Mp4 { writer, .. } => writer.write_frame(frame, rtp_timestamp),
+                Sink::AnnexB(out) => out.write_all(frame),
+            };
+            if let Err(e) = &result {
+                eprintln!("Stopped recording to {}: {}", path.display(), e);
+            }
+            result.is_ok()
+        });
+    }
+
+    // Flushes files and waits for ffmpeg to finish writing each MP4
+    pub fn finish(self) {
+        for (path, sink) in self.sinks {
+            let result = match sink {
+                Sink::Ivf(writer) => writer.into_inner().map(drop),
+                Sink::AnnexB(mut out) => out.flush(),
+                Sink::Mp4 { writer, mut child } => writer
+                    .into_inner()
+                    .map(drop)
+                    .and_then(|_| child.wait())
+                    .and_then(|status| {
+                        if status.success() {
+                            Ok(())
+                        } else {
+                            Err(io::Error::other(format!("ffmpeg exited with {}", status)))
+                        }
+                    }),
+            };
+            match result {
+                Ok(()) => println!("Recording saved to {}", path.display()),
+                Err(e) => eprintln!("Failed to finish recording {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+// Limits on what a recording directory keeps. Unset limits don't apply.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    pub max_total_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    // Finished recordings older than this are gzipped in place
+    pub compress_after: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_total_bytes.is_none() && self.max_age.is_none() && self.compress_after.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Sweep {
+    pub deleted: Vec<PathBuf>,
+    pub compressed: Vec<PathBuf>,
+    // What the recordings left in the directory add up to
+    pub total_bytes: u64,
+}
+
+struct Entry {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+}
+
+// Applies the policy to the recordings in `dir`: expired ones are deleted,
+// old ones compressed, then the oldest deleted until the total fits. Files
+// still being written are counted but never touched, so the total can stay
+// over the cap while a long recording is in progress.
+pub fn enforce_retention(
+    dir: &Path,
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> io::Result<Sweep> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || !entry.file_name().to_string_lossy().starts_with(FILE_PREFIX) {
+            continue;
+        }
+        entries.push(Entry {
+            path: entry.path(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+    entries.sort_by_key(|entry| entry.modified);
+    let age = |entry: &Entry| now.duration_since(entry.modified).unwrap_or_default();
+
+    let mut sweep = Sweep::default();
+    let mut kept = Vec::new();
+    for mut entry in entries {
+        if age(&entry) < IN_PROGRESS {
+            kept.push(entry);
+            continue;
+        }
+        if policy.max_age.is_some_and(|max_age| age(&entry) > max_age) {
+            remove(entry.path, &mut sweep);
+            continue;
+        }
+        let compressible = entry.path.extension().is_some_and(|ext| ext != "gz")
+            && entry.len <= MAX_COMPRESS_BYTES;
+        if compressible
+            && policy
+                .compress_after
+                .is_some_and(|after| age(&entry) > after)
+        {
+            match compress(&entry) {
+                Ok((path, len)) => {
+                    sweep.compressed.push(path.clone());
+                    entry.path = path;
+                    entry.len = len;
+                }
+                Err(e) => eprintln!("Failed to compress {}: {}", entry.path.display(), e),
+            }
+        }
+        kept.push(entry);
+    }
+
+    // Oldest first, since `kept` is still in modification order
+    sweep.total_bytes = kept.iter().map(|entry| entry.len).sum();
+    if let Some(max_total) = policy.max_total_bytes {
+        for entry in kept {
+            if sweep.total_bytes <= max_total {
+                break;
+            }
+            if age(&entry) >= IN_PROGRESS && remove(entry.path, &mut sweep) {
+                sweep.total_bytes -= entry.len;
+            }
+        }
+    }
+    Ok(sweep)
+}
+
+fn remove(path: PathBuf, sweep: &mut Sweep) -> bool {
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            sweep.deleted.push(path);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to delete {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+// Writes `<file>.gz` and removes the original. The compressed copy keeps the
+// original's modification time so it ages as the recording it replaces.
+fn compress(entry: &Entry) -> io::Result<(PathBuf, u64)> {
+    let mut name = entry.path.clone().into_os_string();
+    name.push(".gz");
+    let path = PathBuf::from(name);
+    let compressed = gzip::compress(&std::fs::read(&entry.path)?);
+    let mut file = File::options().write(true).create_new(true).open(&path)?;
+    let written = file
+        .write_all(&compressed)
+        .and_then(|_| file.set_modified(entry.modified));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    std::fs::remove_file(&entry.path)?;
+    Ok((path, compressed.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A finished recording of `len` bytes last written `age` ago
+    fn recording(dir: &Path, name: &str, len: usize, age: Duration) -> PathBuf {
+        let path = dir.join(format!("{}{}.ivf", FILE_PREFIX, name));
+        let file = File::create(&path).unwrap();
+        file.set_len(len as u64).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn recordings_over_the_size_cap_are_deleted_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("retention-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let oldest = recording(&dir, "1", 100, minutes(40));
+        let older = recording(&dir, "2", 100, minutes(30));
+        let newer = recording(&dir, "3", 100, minutes(20));
+        let newest = recording(&dir, "4", 100, minutes(10));
+        let recording_now = recording(&dir, "5", 100, Duration::ZERO);
+        std::fs::write(dir.join("notes.txt"), vec![0; 1000]).unwrap();
+
+        let policy = RetentionPolicy {
+            max_total_bytes: Some(350),
+            ..Default::default()
+        };
+        let sweep = enforce_retention(&dir, &policy, SystemTime::now()).unwrap();
+        assert_eq!(sweep.deleted, vec![oldest.clone(), older.clone()]);
+        assert_eq!(sweep.total_bytes, 300);
+        for (path, kept) in [
+            (oldest, false),
+            (older, false),
+            (newer, true),
+            (newest, true),
+            (recording_now, true),
+        ] {
+            assert_eq!(path.exists(), kept, "{}", path.display());
+        }
+
+        // Already under the cap, nothing more goes
+        let sweep = enforce_retention(&dir, &policy, SystemTime::now()).unwrap();
+        assert!(sweep.deleted.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+// Limits how many writers may flush at once; when permits run out, waiting
+// writers are admitted highest priority first and FIFO within a priority
+pub struct PriorityGate {
+    state: Mutex<GateState>,
+}
+
+struct GateState {
+    available: usize,
+    next_seq: u64,
+    waiters: BinaryHeap<Waiter>,
+}
+
+struct Waiter {
+    priority: u8,
+    seq: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+pub struct Permit {
+    gate: Arc<PriorityGate>,
+}
+
+impl PriorityGate {
+    pub fn new(permits: usize) -> Arc<Self> {
+        Arc::new(PriorityGate {
+            state: Mutex::new(GateState {
+                available: permits.max(1),
+                next_seq: 0,
+                waiters: BinaryHeap::new(),
+            }),
+        })
+    }
+
+    pub async fn acquire(self: &Arc<Self>, priority: u8) -> Permit {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                None
+            } else {
+                let (wake, wait) = oneshot::channel();
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.waiters.push(Waiter {
+                    priority,
+                    seq,
+                    wake,
+                });
+                Some(wait)
+            }
+        };
+        if let Some(wait) = wait {
+            // Waiters only leave the heap when a released permit is handed to them
+            let _ = wait.await;
+        }
+        Permit {
+            gate: Arc::clone(self),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.gate.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.pop() {
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn contended_permits_go_to_the_highest_priority_first() {
+        let gate = PriorityGate::new(1);
+        let held = gate.acquire(0).await;
+        let (flushed, mut order) = tokio::sync::mpsc::unbounded_channel();
+
+        // Two low-priority writers queue up before a high-priority one
+        for (queued, (name, priority)) in [("low 1", 1), ("low 2", 1), ("high", 5)]
+            .into_iter()
+            .enumerate()
+        {
+            let (writer_gate, flushed) = (Arc::clone(&gate), flushed.clone());
+            tokio::spawn(async move {
+                let _permit = writer_gate.acquire(priority).await;
+                flushed.send(name).unwrap();
+            });
+            while gate.state.lock().unwrap().waiters.len() <= queued {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(held);
+
+        let mut admitted = Vec::new();
+        for _ in 0..3 {
+            admitted.push(order.recv().await.unwrap());
+        }
+        assert_eq!(admitted, ["high", "low 1", "low 2"]);
+    }
+}
@@ -0,0 +1,409 @@
+// Helpers for inspecting and rewriting SDP bodies as plain text
+
+pub fn supports_trickle(sdp: &str) -> bool {
+    sdp.lines()
+        .filter_map(|line| line.trim_end().strip_prefix("a=ice-options:"))
+        .any(|options| options.split_whitespace().any(|o| o == "trickle"))
+}
+
+pub fn candidate_count(sdp: &str) -> usize {
+    sdp.lines()
+        .filter(|line| line.starts_with("a=candidate:"))
+        .count()
+}
+
+pub fn media_count(sdp: &str) -> usize {
+    sdp.lines().filter(|line| line.starts_with("m=")).count()
+}
+
+// m-lines of `kind` that still carry media: not rejected with port 0 and
+// not marked inactive
+pub fn active_media_count(sdp: &str, kind: &str) -> usize {
+    let (_, sections) = split_sections(sdp);
+    sections
+        .iter()
+        .filter(|section| {
+            media_line(section).is_some_and(|media| media.kind == kind && media.port != "0")
+                && !section.lines().any(|line| line.trim_end() == "a=inactive")
+        })
+        .count()
+}
+
+// Hex digests from every `a=fingerprint` line, uppercased so they compare
+// regardless of how the peer formatted them
+pub fn fingerprints(sdp: &str) -> Vec<String> {
+    sdp.lines()
+        .filter_map(|line| line.trim_end().strip_prefix("a=fingerprint:"))
+        .filter_map(|value| value.split_whitespace().nth(1))
+        .map(str::to_ascii_uppercase)
+        .collect()
+}
+
+// An offer passes only if it carries at least one fingerprint and all of them
+// are allowlisted; entries may include the hash name ("sha-256 AB:CD:...")
+pub fn fingerprint_allowed(sdp: &str, allowlist: &[String]) -> bool {
+    let allowed: Vec<String> = allowlist
+        .iter()
+        .filter_map(|entry| entry.split_whitespace().last())
+        .map(str::to_ascii_uppercase)
+        .collect();
+    let offered = fingerprints(sdp);
+    !offered.is_empty() && offered.iter().all(|fp| allowed.contains(fp))
+}
+
+// First `a=ice-ufrag` and `a=ice-pwd` values, session or media level; with
+// bundling every section carries the same pair
+pub fn ice_credentials(sdp: &str) -> Option<(String, String)> {
+    let attribute = |name: &str| {
+        sdp.lines()
+            .find_map(|line| line.trim_end().strip_prefix(name))
+            .map(str::to_owned)
+    };
+    Some((attribute("a=ice-ufrag:")?, attribute("a=ice-pwd:")?))
+}
+
+// fmtp parameters of every payload type whose rtpmap names `encoding`, e.g.
+// "H264"; payload types without an fmtp line yield an empty string
+pub fn fmtp_for(sdp: &str, encoding: &str) -> Vec<String> {
+    let payload_types: Vec<&str> = sdp
+        .lines()
+        .filter_map(|line| line.trim_end().strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| {
+            let (pt, codec) = rtpmap.split_once(' ')?;
+            let name = codec.split('/').next()?;
+            name.eq_ignore_ascii_case(encoding).then_some(pt)
+        })
+        .collect();
+    payload_types
+        .iter()
+        .map(|pt| {
+            let prefix = format!("a=fmtp:{} ", pt);
+            sdp.lines()
+                .find_map(|line| line.trim_end().strip_prefix(prefix.as_str()))
+                .unwrap_or("")
+                .to_owned()
+        })
+        .collect()
+}
+
+// Encoding name of the payload type an answer selected for the first accepted
+// m-line of `kind` ("video", "audio"): the first format listed, looked up in
+// that section's rtpmap lines
+pub fn negotiated_codec(sdp: &str, kind: &str) -> Option<String> {
+    let (_, sections) = split_sections(sdp);
+    sections.iter().find_map(|section| {
+        let media = media_line(section)?;
+        if media.kind != kind || media.port == "0" {
+            return None;
+        }
+        let prefix = format!("a=rtpmap:{} ", media.formats.first()?);
+        section.lines().find_map(|line| {
+            let codec = line.trim_end().strip_prefix(prefix.as_str())?;
+            Some(codec.split('/').next()?.to_owned())
+        })
+    })
+}
+
+// Drops `a=rtcp-fb` lines whose feedback type starts with any of the disabled
+// entries, so "nack" removes both "nack" and "nack pli"
+pub fn strip_rtcp_feedback(sdp: &str, disabled: &[String]) -> String {
+    if disabled.is_empty() {
+        return sdp.to_owned();
+    }
+    sdp.split_inclusive('\n')
+        .filter(|line| {
+            let Some(feedback) = line.trim_end().strip_prefix("a=rtcp-fb:") else {
+                return true;
+            };
+            let tokens: Vec<&str> = feedback.split_whitespace().skip(1).collect();
+            !disabled.iter().any(|entry| {
+                let entry: Vec<&str> = entry.split_whitespace().collect();
+                !entry.is_empty() && tokens.starts_with(&entry)
+            })
+        })
+        .collect()
+}
+
+// Takes `trickle` out of every `a=ice-options` line, dropping lines left
+// with no options, so the SDP no longer claims trickle support
+pub fn strip_trickle(sdp: &str) -> String {
+    sdp.split_inclusive('\n')
+        .filter_map(|line| {
+            let content = line.trim_end();
+            let Some(options) = content.strip_prefix("a=ice-options:") else {
+                return Some(line.to_owned());
+            };
+            let kept: Vec<&str> = options
+                .split_whitespace()
+                .filter(|option| *option != "trickle")
+                .collect();
+            if kept.is_empty() {
+                return None;
+            }
+            let ending = &line[content.len()..];
+            Some(format!("a=ice-options:{}{}", kept.join(" "), ending))
+        })
+        .collect()
+}
+
+// Drops the `a=simulcast` and `a=rid` lines, leaving each section with a
+// single encoding
+pub fn strip_simulcast(sdp: &str) -> String {
+    sdp.split_inclusive('\n')
+        .filter(|line| !line.starts_with("a=simulcast:") && !line.starts_with("a=rid:"))
+        .collect()
+}
+
+// Media kinds of the m-lines that were rejected with port 0
+pub fn rejected_media(sdp: &str) -> Vec<String> {
+    sdp.lines()
+        .filter_map(|line| line.strip_prefix("m="))
+        .filter_map(|media| {
+            let mut fields = media.split_whitespace();
+            let kind = fields.next()?;
+            (fields.next()? == "0").then(|| kind.to_owned())
+        })
+        .collect()
+}
+
+// Splits an SDP into its session-level part and one chunk per m-line, each
+// keeping its original line endings
+pub fn split_sections(sdp: &str) -> (String, Vec<String>) {
+    let mut session = String::new();
+    let mut sections: Vec<String> = Vec::new();
+    for line in sdp.split_inclusive('\n') {
+        if line.starts_with("m=") {
+            sections.push(String::new());
+        }
+        match sections.last_mut() {
+            Some(section) => section.push_str(line),
+            None => session.push_str(line),
+        }
+    }
+    (session, sections)
+}
+
+pub struct MediaLine {
+    pub kind: String,
+    pub port: String,
+    pub proto: String,
+    pub formats: Vec<String>,
+}
+
+pub fn media_line(section: &str) -> Option<MediaLine> {
+    let mut fields = section
+        .lines()
+        .next()?
+        .strip_prefix("m=")?
+        .split_whitespace();
+    Some(MediaLine {
+        kind: fields.next()?.to_owned(),
+        port: fields.next()?.to_owned(),
+        proto: fields.next()?.to_owned(),
+        formats: fields.map(|f| f.to_owned()).collect(),
+    })
+}
+
+fn section_mid(section: &str) -> Option<&str> {
+    section
+        .lines()
+        .find_map(|line| line.trim_end().strip_prefix("a=mid:"))
+}
+
+// Rejects (port 0) every answer section whose offered counterpart fails
+// `supported`, adding sections the answer left out so the m-line count still
+// matches the offer. Returns the rewritten answer and the rejected kinds.
+pub fn reject_unsupported_media(
+    offer: &str,
+    answer: &str,
+    supported: impl Fn(&MediaLine) -> bool,
+) -> (String, Vec<String>) {
+    let (_, offered) = split_sections(offer);
+    let (mut session, mut answered) = split_sections(answer);
+    let mut rejected = Vec::new();
+
+    for (index, offered_section) in offered.iter().enumerate() {
+        let Some(media) = media_line(offered_section) else {
+            continue;
+        };
+        if media.port == "0" || supported(&media) {
+            continue;
+        }
+        let line = format!(
+            "m={} 0 {} {}\r\n",
+            media.kind,
+            media.proto,
+            media.formats.join(" ")
+        );
+        let answered_count = answered.len();
+        match answered.get_mut(index) {
+            Some(section) => {
+                let rest = section.split_once('\n').map(|(_, rest)| rest).unwrap_or("");
+                *section = format!("{}{}", line, rest);
+            }
+            None if index == answered_count => {
+                let mut section = line;
+                if let Some(mid) = section_mid(offered_section) {
+                    section.push_str(&format!("a=mid:{}\r\n", mid));
+                }
+                answered.push(section);
+            }
+            None => continue,
+        }
+        if let Some(mid) = section_mid(offered_section) {
+            session = remove_from_bundle(&session, mid);
+        }
+        rejected.push(media.kind);
+    }
+
+    session.extend(answered);
+    (session, rejected)
+}
+
+fn remove_from_bundle(session: &str, mid: &str) -> String {
+    session
+        .split_inclusive('\n')
+        .map(
+            |line| match line.trim_end().strip_prefix("a=group:BUNDLE") {
+                Some(mids) => {
+                    let kept: Vec<&str> = mids.split_whitespace().filter(|m| *m != mid).collect();
+                    format!("a=group:BUNDLE {}\r\n", kept.join(" "))
+                }
+                None => line.to_owned(),
+            },
+        )
+        .collect()
+}
+
+// Renumbers payload types so each pinned encoding (e.g. "VP8" -> 96) uses
+// its fixed number, for peers that only handle static assignments. Only the
+// first payload type of an encoding in each m-line moves, and RTX `apt=`
+// references follow it. A pin whose number another codec in the section
+// already holds is skipped and reported instead, as "VP8 -> 96".
+pub fn pin_payload_types(sdp: &str, pins: &[(String, u8)]) -> (String, Vec<String>) {
+    let (mut session, sections) = split_sections(sdp);
+    let mut collisions = Vec::new();
+    for section in sections {
+        let Some(media) = media_line(&section) else {
+            session.push_str(&section);
+            continue;
+        };
+        let encodings: Vec<(String, String)> = section
+            .lines()
+            .filter_map(|line| line.trim_end().strip_prefix("a=rtpmap:"))
+            .filter_map(|rtpmap| {
+                let (pt, codec) = rtpmap.split_once(' ')?;
+                Some((pt.to_owned(), codec.split('/').next()?.to_owned()))
+            })
+            .collect();
+        let mut moves: Vec<(String, String)> = Vec::new();
+        for (encoding, pinned) in pins {
+            let pinned = pinned.to_string();
+            let Some(current) = media.formats.iter().find(|format| {
+                encodings
+                    .iter()
+                    .any(|(pt, name)| pt == *format && name.eq_ignore_ascii_case(encoding))
+            }) else {
+                continue;
+            };
+            if *current == pinned {
+                continue;
+            }
+            let taken =
+                media.formats.contains(&pinned) || moves.iter().any(|(_, to)| *to == pinned);
+            if taken {
+                collisions.push(format!("{} -> {}", encoding, pinned));
+                continue;
+            }
+            moves.push((current.clone(), pinned));
+        }
+        if moves.is_empty() {
+            session.push_str(&section);
+            continue;
+        }
+        let renumber = |pt: &str| {
+            moves
+                .iter()
+                .find(|(from, _)| from == pt)
+                .map_or(pt.to_owned(), |(_, to)| to.clone())
+        };
+        for line in section.split_inclusive('\n') {
+            let body = line.trim_end();
+            let ending = &line[body.len()..];
+            let rewritten = if body.starts_with("m=") {
+                let mut fields: Vec<String> = body.split(' ').map(str::to_owned).collect();
+                for field in fields.iter_mut().skip(3) {
+                    *field = renumber(field);
+                }
+                fields.join(" ")
+            } else if let Some((prefix, rest)) = ["a=rtpmap:", "a=fmtp:", "a=rtcp-fb:"]
+                .iter()
+                .find_map(|prefix| Some((*prefix, body.strip_prefix(prefix)?)))
+            {
+                let (pt, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                let value = match value.split_once("apt=") {
+                    Some((before, apt)) => {
+                        let (referenced, after) = apt.split_at(apt.find(';').unwrap_or(apt.len()));
+                        format!("{}apt={}{}", before, renumber(referenced), after)
+                    }
+                    None => value.to_owned(),
+                };
+                if value.is_empty() {
+                    format!("{}{}", prefix, renumber(pt))
+                } else {
+                    format!("{}{} {}", prefix, renumber(pt), value)
+                }
+            } else {
+                body.to_owned()
+            };
+            session.push_str(&rewritten);
+            session.push_str(ending);
+        }
+    }
+    (session, collisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = "v=0\r\ns=-\r\nt=0 0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 97\r\n\
+                          a=rtpmap:96 VP8/90000\r\na=rtcp-fb:96 goog-remb\r\n\
+                          a=rtcp-fb:96 ccm fir\r\na=rtcp-fb:96 nack\r\n\
+                          a=rtcp-fb:96 nack pli\r\na=rtpmap:97 VP9/90000\r\n\
+                          a=rtcp-fb:97 nack\r\na=sendonly\r\n";
+
+    #[test]
+    fn disabled_feedback_is_stripped_from_the_answer() {
+        let stripped = strip_rtcp_feedback(ANSWER, &["nack".to_owned()]);
+        assert!(!stripped
+            .lines()
+            .any(|line| line.starts_with("a=rtcp-fb:") && line.contains(" nack")));
+        assert!(stripped.contains("a=rtcp-fb:96 goog-remb\r\n"));
+        assert!(stripped.contains("a=rtcp-fb:96 ccm fir\r\n"));
+        assert!(stripped.contains("a=rtpmap:97 VP9/90000\r\n"));
+
+        // A multi-word entry only removes that exact feedback
+        let stripped = strip_rtcp_feedback(ANSWER, &["nack pli".to_owned()]);
+        assert!(!stripped.contains("nack pli"));
+        assert!(stripped.contains("a=rtcp-fb:96 nack\r\n"));
+    }
+
+    #[test]
+    fn unsupported_media_is_rejected_with_port_zero() {
+        let offer = "v=0\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0 1\r\n\
+                     m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\na=rtpmap:96 VP8/90000\r\n\
+                     m=application 9 UDP/TLS/RTP/SAVPF 100\r\na=mid:1\r\n";
+        let answer = "v=0\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\n\
+                      m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\na=rtpmap:96 VP8/90000\r\n";
+        let supported = |media: &MediaLine| media.kind != "application";
+
+        let (answer, rejected) = reject_unsupported_media(offer, answer, supported);
+        assert_eq!(rejected, vec!["application"]);
+        assert_eq!(rejected_media(&answer), vec!["application"]);
+        assert_eq!(media_count(&answer), 2);
+        assert!(answer.contains("m=application 0 UDP/TLS/RTP/SAVPF 100\r\na=mid:1\r\n"));
+        assert!(answer.contains("m=video 9 "));
+        assert!(answer.contains("a=group:BUNDLE 0\r\n"));
+    }
+}
@@ -0,0 +1,58 @@
+use std::future::Future;
+
+// Resolves on Ctrl-C, or SIGTERM on Unix, so the binaries can close their
+// connections instead of dying mid-session. The SIGTERM listener is set up
+// when this is called rather than when the future is first polled, so a
+// signal arriving in between isn't lost. Needs a running Tokio runtime.
+pub fn signal() -> impl Future<Output = ()> {
+    #[cfg(unix)]
+    let sigterm = {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .map_err(|e| eprintln!("Cannot listen for SIGTERM: {}", e))
+            .ok()
+    };
+    async move {
+        let ctrl_c = async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                eprintln!("Cannot listen for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async move {
+            match sigterm {
+                Some(mut sigterm) => {
+                    sigterm.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate => {}
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn sigterm_resolves_the_signal() {
+        let signal = signal();
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        tokio::time::timeout(Duration::from_secs(5), signal)
+            .await
+            .expect("signal() didn't resolve on SIGTERM");
+    }
+}
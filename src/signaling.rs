@@ -0,0 +1,108 @@
+// Wire formats shared by every binary and the browser client. SDP travels in
+// the shape of the browser's RTCSessionDescriptionInit, `{"type", "sdp"}`,
+// which the internally tagged SignalingMessage enums produce for their Offer
+// and Answer variants; candidates use RTCIceCandidateInit's camelCase names.
+use crate::gzip;
+use base64::engine::general_purpose;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionDescriptionInit {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sdp: String,
+}
+
+impl SessionDescriptionInit {
+    pub fn offer(sdp: String) -> Self {
+        SessionDescriptionInit {
+            kind: "offer".to_owned(),
+            sdp,
+        }
+    }
+
+    pub fn answer(sdp: String) -> Self {
+        SessionDescriptionInit {
+            kind: "answer".to_owned(),
+            sdp,
+        }
+    }
+}
+
+impl TryFrom<SessionDescriptionInit> for RTCSessionDescription {
+    type Error = anyhow::Error;
+
+    // Also parses the SDP, so a malformed body is caught here
+    fn try_from(init: SessionDescriptionInit) -> Result<Self, Self::Error> {
+        let description = match init.kind.as_str() {
+            "offer" => RTCSessionDescription::offer(init.sdp)?,
+            "answer" => RTCSessionDescription::answer(init.sdp)?,
+            "pranswer" => RTCSessionDescription::pranswer(init.sdp)?,
+            other => anyhow::bail!("unsupported session description type '{}'", other),
+        };
+        Ok(description)
+    }
+}
+
+impl From<&RTCSessionDescription> for SessionDescriptionInit {
+    fn from(description: &RTCSessionDescription) -> Self {
+        SessionDescriptionInit {
+            kind: description.sdp_type.to_string(),
+            sdp: description.sdp.clone(),
+        }
+    }
+}
+
+// One entry of a batched `candidates` message, shaped like RTCIceCandidateInit
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CandidateInit {
+    pub candidate: String,
+    #[serde(rename = "sdpMid", alias = "sdp_mid")]
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex", alias = "sdp_mline_index")]
+    pub sdp_mline_index: Option<u32>,
+}
+
+// On constrained signaling channels an Offer or Answer may carry its SDP
+// gzip-compressed and base64-encoded, flagged by `"compressed": true`
+pub fn compress_sdp(sdp: &str) -> String {
+    general_purpose::STANDARD.encode(gzip::compress(sdp.as_bytes()))
+}
+
+// `max_len` bounds the decompressed size, like the limit on plain SDP
+pub fn decompress_sdp(encoded: &str, max_len: usize) -> anyhow::Result<String> {
+    let compressed = general_purpose::STANDARD.decode(encoded)?;
+    Ok(String::from_utf8(gzip::decompress(&compressed, max_len)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n\
+                       a=group:BUNDLE 0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+                       c=IN IP4 0.0.0.0\r\na=rtpmap:96 VP8/90000\r\na=sendonly\r\n";
+
+    #[test]
+    fn compressed_sdp_round_trips() {
+        let encoded = compress_sdp(SDP);
+        assert!(general_purpose::STANDARD.decode(&encoded).is_ok());
+        assert_eq!(decompress_sdp(&encoded, SDP.len()).unwrap(), SDP);
+    }
+
+    #[test]
+    fn undecodable_sdp_is_an_error() {
+        // Not base64, then base64 that isn't gzip
+        assert!(decompress_sdp("not base64!", 1024).is_err());
+        let not_gzip = general_purpose::STANDARD.encode(SDP);
+        assert!(decompress_sdp(&not_gzip, 1024).is_err());
+    }
+
+    #[test]
+    fn sdp_over_the_limit_is_refused() {
+        let encoded = compress_sdp(SDP);
+        assert!(decompress_sdp(&encoded, SDP.len() - 1).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+// Where the server puts captured images. The capture handler only names the
+// image and hands over its bytes; a backend stores them and says where they
+// ended up, so object storage can replace the local disk without touching
+// the handler.
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::AsyncWriteExt;
+
+// Resolves to the stored object's location, a path or URL. A name that is
+// already taken fails with `ErrorKind::AlreadyExists` rather than replacing
+// what is there, so callers can pick another name and retry.
+pub type StoreFuture<'a> = Pin<Box<dyn Future<Output = io::Result<String>> + Send + 'a>>;
+
+pub trait ImageStore: Send + Sync {
+    fn store<'a>(&'a self, name: &'a str, bytes: &'a [u8]) -> StoreFuture<'a>;
+}
+
+// Writes each image as a file in one directory, created on first use
+pub struct LocalFsStore {
+    dir: PathBuf,
+}
+
+impl LocalFsStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalFsStore { dir: dir.into() }
+    }
+}
+
+impl ImageStore for LocalFsStore {
+    fn store<'a>(&'a self, name: &'a str, bytes: &'a [u8]) -> StoreFuture<'a> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let path = self.dir.join(name);
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await?;
+            file.write_all(bytes).await?;
+            // Tokio finishes the write in the background otherwise, and the
+            // caller may read the image back as soon as this returns
+            file.flush().await?;
+            Ok(path.display().to_string())
+        })
+    }
+}
@@ -0,0 +1,69 @@
+// Chooses a STUN server from an ordered list by sending each a binding request
+// and keeping the first that reports our server-reflexive address in time
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::net::{lookup_host, UdpSocket};
+use webrtc::stun::agent::TransactionId;
+use webrtc::stun::message::{Getter, Message, BINDING_REQUEST};
+use webrtc::stun::xoraddr::XorMappedAddress;
+
+pub const DEFAULT_SERVER: &str = "stun:stun.l.google.com:19302";
+
+// Accepts `stun:host:port` as given to RTCIceServer, or a bare `host:port`
+fn host_port(url: &str) -> &str {
+    url.strip_prefix("stun:").unwrap_or(url)
+}
+
+// Asks one server for our reflexive address, the same answer ICE would turn
+// into a srflx candidate
+pub async fn reflexive_address(url: &str, timeout: Duration) -> Result<SocketAddr> {
+    let server = lookup_host(host_port(url))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{} did not resolve", url))?;
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind).await?;
+
+    let mut request = Message::new();
+    request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])?;
+    socket.send_to(&request.raw, server).await?;
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = tokio::time::timeout(timeout, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| anyhow!("{} did not answer within {:?}", url, timeout))??;
+        if from != server {
+            continue;
+        }
+        let mut response = Message::new();
+        response.unmarshal_binary(&buf[..len])?;
+        if response.transaction_id != request.transaction_id {
+            continue;
+        }
+        let mut mapped = XorMappedAddress::default();
+        mapped.get_from(&response)?;
+        return Ok(SocketAddr::new(mapped.ip, mapped.port));
+    }
+}
+
+// Returns the first server that answers, in the order given, or None when
+// none of them do
+pub async fn first_reachable(urls: &[String], timeout: Duration) -> Option<String> {
+    for url in urls {
+        match reflexive_address(url, timeout).await {
+            Ok(addr) => {
+                println!("STUN server {} reachable, reflexive address {}", url, addr);
+                return Some(url.clone());
+            }
+            Err(e) => println!("STUN server {} unusable, trying the next: {}", url, e),
+        }
+    }
+    None
+}
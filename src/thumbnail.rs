@@ -0,0 +1,107 @@
+// Periodic JPEG thumbnails of the published video for monitoring. The newest
+// VP8 keyframe is kept, and once per interval ffmpeg decodes it, scales it
+// down and encodes a JPEG that replaces `thumbnail.jpg` in the target
+// directory, so a dashboard only has to poll that one file.
+use crate::ffmpeg;
+use crate::recording::{vp8_keyframe_size, IvfWriter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const FILE_NAME: &str = "thumbnail.jpg";
+
+#[derive(Clone, Debug)]
+pub struct ThumbnailOptions {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    // Output width; the height follows the aspect ratio
+    pub width: u32,
+}
+
+// Keeps the newest keyframe for the thumbnail task; inter frames are ignored
+#[derive(Clone)]
+pub struct KeyframeTap(Arc<watch::Sender<Option<Vec<u8>>>>);
+
+impl KeyframeTap {
+    pub fn channel() -> (Self, watch::Receiver<Option<Vec<u8>>>) {
+        let (tx, rx) = watch::channel(None);
+        (KeyframeTap(Arc::new(tx)), rx)
+    }
+
+    pub fn offer(&self, frame: &[u8]) {
+        if vp8_keyframe_size(frame).is_some() {
+            self.0.send_replace(Some(frame.to_vec()));
+        }
+    }
+}
+
+// Decodes one VP8 keyframe, wrapped in a one-frame IVF, into a JPEG
+pub fn render(keyframe: &[u8], width: u32) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("ffmpeg")
+        .args(ffmpeg::thumbnail_args(width))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "ffmpeg stdin unavailable"))?;
+    let mut writer = IvfWriter::new(stdin);
+    writer.write_frame(keyframe, 0)?;
+    // Closing stdin tells ffmpeg the input is complete
+    drop(writer.into_inner()?);
+    let output = child.wait_with_output()?;
+    // Every JPEG starts with an SOI marker
+    if !output.status.success() || !output.stdout.starts_with(&[0xff, 0xd8]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ffmpeg did not produce a JPEG",
+        ));
+    }
+    Ok(output.stdout)
+}
+
+// Goes through a temporary file so readers never see a partial image
+pub fn save(dir: &Path, jpeg: &[u8]) -> io::Result<PathBuf> {
+    let path = dir.join(FILE_NAME);
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, jpeg)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+// Renders a thumbnail each interval in which a new keyframe arrived, until
+// the tap is dropped
+pub async fn run(options: ThumbnailOptions, mut keyframes: watch::Receiver<Option<Vec<u8>>>) {
+    if let Err(e) = std::fs::create_dir_all(&options.dir) {
+        eprintln!(
+            "Thumbnails disabled, cannot create {:?}: {}",
+            options.dir, e
+        );
+        return;
+    }
+    let mut ticker = tokio::time::interval(options.interval);
+    loop {
+        ticker.tick().await;
+        match keyframes.has_changed() {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(_) => return,
+        }
+        let Some(keyframe) = keyframes.borrow_and_update().clone() else {
+            continue;
+        };
+        let ThumbnailOptions { dir, width, .. } = options.clone();
+        let result =
+            tokio::task::spawn_blocking(move || save(&dir, &render(&keyframe, width)?)).await;
+        match result {
+            Ok(Ok(path)) => println!("Thumbnail updated at {}", path.display()),
+            Ok(Err(e)) => eprintln!("Error rendering thumbnail: {}", e),
+            Err(e) => eprintln!("Error rendering thumbnail: {}", e),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// TLS termination for serving wss://. warp's own `tls` feature pulls in a
+// rustls whose `subtle` requirement conflicts with the version webrtc pins,
+// so connections are accepted and handshaken here with tokio-rustls and the
+// decrypted streams handed to warp as its incoming connections.
+use futures_util::Stream;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// Connections whose handshake hasn't finished in this long are dropped, so a
+// client that connects and goes quiet doesn't hold a task forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reads a PEM certificate chain and its private key, PKCS#8 or RSA, with an
+// error that names the file and what was wrong with it
+pub fn load_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let open = |what: &str, path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Cannot read TLS {} {}: {}", what, path.display(), e))
+    };
+    let certs = pemfile::certs(&mut open("certificate", cert)?).unwrap_or_default();
+    if certs.is_empty() {
+        return Err(format!("No PEM certificate found in {}", cert.display()));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open("key", key)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open("key", key)?).unwrap_or_default();
+    }
+    let Some(private_key) = keys.into_iter().next() else {
+        return Err(format!(
+            "No PEM private key (PKCS#8 or RSA) found in {}",
+            key.display()
+        ));
+    };
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    // rustls only checks that the key parses; one that doesn't belong to the
+    // certificate shows up as failed handshakes
+    config
+        .set_single_cert(certs, private_key)
+        .map_err(|e| format!("Cannot use TLS key {}: {}", key.display(), e))?;
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(Arc::new(config))
+}
+
+// Accepts TCP connections on `listener` and yields them once their TLS
+// handshake completes. Each handshake runs in its own task so a slow client
+// can't hold up the others; failed ones are logged and dropped.
+pub fn incoming(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(config);
+    let (tx, mut rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            // Stops accepting once the server has stopped taking connections
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tx.closed() => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; give some a chance to close
+                    eprintln!("Failed to accept a TLS connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", peer, e),
+                    Err(_) => eprintln!("TLS handshake with {} timed out", peer),
+                }
+            });
+        }
+    });
+    futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
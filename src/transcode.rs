@@ -0,0 +1,116 @@
+// Re-encodes the published VP8 frames to H.264 for viewers that can only
+// decode H.264. ffmpeg does the work: frames are piped to it as IVF and the
+// Annex B stream it writes back is split into access units for the track
+// as it is read, whatever size the pipe's reads come in.
+use crate::ffmpeg;
+use crate::h264::AnnexBFramer;
+use crate::recording::IvfWriter;
+use std::io::{self, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::media::Sample;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+pub struct Transcoder {
+    frames: mpsc::Sender<(Vec<u8>, Duration)>,
+    child: Child,
+}
+
+impl Transcoder {
+    // Starts ffmpeg and the tasks feeding it and draining it into `track`
+    pub fn spawn(threads: usize, track: Arc<TrackLocalStaticSample>) -> io::Result<Self> {
+        let mut child = Command::new("ffmpeg")
+            .args(ffmpeg::h264_transcode_args(threads))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let unavailable = |pipe| io::Error::new(io::ErrorKind::BrokenPipe, pipe);
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| unavailable("ffmpeg stdin unavailable"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| unavailable("ffmpeg stdout unavailable"))?;
+
+        // ffmpeg emits one picture per input frame, so output samples take the
+        // duration of the latest input frame
+        let frame_nanos = Arc::new(AtomicU64::new(0));
+
+        let (frames, mut frames_rx) = mpsc::channel::<(Vec<u8>, Duration)>(30);
+        let input_nanos = Arc::clone(&frame_nanos);
+        tokio::task::spawn_blocking(move || {
+            let mut writer = IvfWriter::new(stdin);
+            let mut timestamp = 0u32;
+            while let Some((frame, duration)) = frames_rx.blocking_recv() {
+                input_nanos.store(duration.as_nanos() as u64, Ordering::Relaxed);
+                if let Err(e) = writer.write_frame(&frame, timestamp) {
+                    eprintln!("Stopped feeding the H.264 transcoder: {}", e);
+                    return;
+                }
+                let ticks = duration.as_secs_f64() * 90_000.0;
+                timestamp = timestamp.wrapping_add(ticks.round() as u32);
+            }
+        });
+
+        let (samples, mut samples_rx) = mpsc::channel::<Sample>(30);
+        tokio::task::spawn_blocking(move || {
+            let mut stdout = stdout;
+            let mut framer = AnnexBFramer::default();
+            let mut chunk = vec![0u8; 64 * 1024];
+            loop {
+                let (access_units, done) = match stdout.read(&mut chunk) {
+                    Ok(0) => (framer.finish().into_iter().collect(), true),
+                    Ok(len) => (framer.push(&chunk[..len]), false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        eprintln!("Stopped reading the H.264 transcoder: {}", e);
+                        return;
+                    }
+                };
+                for access_unit in access_units {
+                    let sample = Sample {
+                        data: access_unit.into(),
+                        duration: Duration::from_nanos(frame_nanos.load(Ordering::Relaxed)),
+                        ..Default::default()
+                    };
+                    if samples.blocking_send(sample).is_err() {
+                        return;
+                    }
+                }
+                if done {
+                    return;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(sample) = samples_rx.recv().await {
+                if let Err(e) = track.write_sample(&sample).await {
+                    eprintln!("Error writing transcoded H.264: {}", e);
+                    return;
+                }
+            }
+        });
+
+        Ok(Transcoder { frames, child })
+    }
+
+    // Queues one VP8 frame; fails once ffmpeg has stopped taking input
+    pub async fn write_frame(&self, frame: &[u8], duration: Duration) -> io::Result<()> {
+        self.frames
+            .send((frame.to_vec(), duration))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "H.264 transcoder stopped"))
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+// One line of a JSONL transcript; `at_ms` is relative to the connection start
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscriptEntry {
+    pub direction: Direction,
+    pub at_ms: u64,
+    pub message: serde_json::Value,
+}
+
+pub struct TranscriptWriter {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+    started: Instant,
+}
+
+impl TranscriptWriter {
+    pub async fn create(dir: &Path, connection_id: &str) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.jsonl", connection_id));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(TranscriptWriter {
+            path,
+            file: Mutex::new(file),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn record<T: Serialize>(&self, direction: Direction, message: &T) {
+        let entry = match serde_json::to_value(message) {
+            Ok(message) => TranscriptEntry {
+                direction,
+                at_ms: self.started.elapsed().as_millis() as u64,
+                message,
+            },
+            Err(e) => {
+                eprintln!("Failed to serialize transcript entry: {}", e);
+                return;
+            }
+        };
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to serialize transcript entry: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        // Flushed per entry so a reader sees every line once `record` returns
+        let mut file = self.file.lock().await;
+        let written = match file.write_all(line.as_bytes()).await {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to write transcript {:?}: {}", self.path, e);
+        }
+    }
+}
+
+pub fn read_transcript(path: &Path) -> anyhow::Result<Vec<TranscriptEntry>> {
+    let contents = std::fs::read_to_string(path)?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
@@ -0,0 +1,107 @@
+// Fire-and-forget HTTP notifications about connection lifecycle events
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Connect,
+    Disconnect,
+    SessionStart,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::Connect,
+        WebhookEvent::Disconnect,
+        WebhookEvent::SessionStart,
+    ];
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "connect" => Ok(WebhookEvent::Connect),
+            "disconnect" => Ok(WebhookEvent::Disconnect),
+            "session_start" => Ok(WebhookEvent::SessionStart),
+            other => Err(format!("unknown webhook event '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub event: WebhookEvent,
+    pub connection_id: String,
+    pub user_id: Option<String>,
+    pub room: Option<String>,
+    pub at_ms: u64,
+}
+
+// Only plain http:// endpoints are supported; put a local relay in front of
+// anything that needs TLS
+pub struct Webhook {
+    url: Uri,
+    events: HashSet<WebhookEvent>,
+    client: Client<HttpConnector>,
+}
+
+impl Webhook {
+    pub fn new(url: &str, events: impl IntoIterator<Item = WebhookEvent>) -> Result<Self, String> {
+        let url: Uri = url
+            .parse()
+            .map_err(|e| format!("invalid webhook url '{}': {}", url, e))?;
+        if url.scheme_str() != Some("http") {
+            return Err(format!("webhook url '{}' must use http://", url));
+        }
+        Ok(Webhook {
+            url,
+            events: events.into_iter().collect(),
+            client: Client::new(),
+        })
+    }
+
+    // Returns immediately; delivery happens on a spawned task and failures
+    // are only logged, so a slow endpoint never holds up a connection
+    pub fn fire(&self, payload: WebhookPayload) {
+        if !self.events.contains(&payload.event) {
+            return;
+        }
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to serialize webhook payload: {}", e);
+                return;
+            }
+        };
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header("content-type", "application/json")
+            .body(Body::from(body));
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Failed to build webhook request: {}", e);
+                return;
+            }
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            match client.request(request).await {
+                Ok(response) if !response.status().is_success() => eprintln!(
+                    "Webhook for {:?} returned {}",
+                    payload.event,
+                    response.status()
+                ),
+                Ok(_) => {}
+                Err(e) => eprintln!("Webhook for {:?} failed: {}", payload.event, e),
+            }
+        });
+    }
+}
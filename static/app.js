@@ -1,4 +1,7 @@
-const signalingSocket = new WebSocket("ws://127.0.0.1:3030/signaling");
+// A `?token=` on the page is passed on to servers that require one
+const authToken = new URLSearchParams(location.search).get("token");
+const signalingSocket = new WebSocket("ws://127.0.0.1:3030/signaling"
+    + (authToken ? `?token=${encodeURIComponent(authToken)}` : ""));
 const localVideo = document.getElementById("localVideo");
 const remoteVideo = document.getElementById("remoteVideo");
 
@@ -7,9 +10,23 @@ const pc = new RTCPeerConnection({
     iceServers: [{ urls: "stun:stun.l.google.com:19302" }]
 });
 
+// Captured images go to the streamer over their own data channel, keeping
+// them off the signaling socket: a JSON announcement of the size, then the
+// bytes in binary chunks
+const IMAGE_CHUNK_BYTES = 16 * 1024;
+const imageChannel = pc.createDataChannel("images");
+imageChannel.bufferedAmountLowThreshold = 1024 * 1024;
+
+// The server only forwards offers, answers and candidates addressed to one
+// peer. Whoever last sent us one is the peer we talk to; until then it is the
+// first peer the server told us about.
+let remoteId = null;
+
 // WebSocket Event Handlers
 signalingSocket.onopen = () => {
     console.log("WebSocket connected!");
+    // Tell the server what this page handles; it adapts what it forwards to match
+    sendMessage({ type: "hello", features: ["trickle", "data-channel", "compression"] });
     document.getElementById("startCall").disabled = false;
     document.getElementById("captureImage").disabled = false;
 };
@@ -29,6 +46,25 @@ async function startCall() {
     localStream.getTracks().forEach(track => pc.addTrack(track, localStream));
 }
 
+// Falls back to a base64 `image` message over signaling until the channel is open
+async function sendImage(canvas, imageData) {
+    if (imageChannel.readyState !== "open") {
+        sendMessage({ type: "image", data: imageData });
+        return;
+    }
+    const blob = await new Promise(resolve => canvas.toBlob(resolve, "image/png"));
+    const bytes = new Uint8Array(await blob.arrayBuffer());
+    imageChannel.send(JSON.stringify({ size: bytes.length, mime: "image/png" }));
+    for (let offset = 0; offset < bytes.length; offset += IMAGE_CHUNK_BYTES) {
+        if (imageChannel.bufferedAmount > imageChannel.bufferedAmountLowThreshold) {
+            await new Promise(resolve =>
+                imageChannel.addEventListener("bufferedamountlow", resolve, { once: true }));
+        }
+        imageChannel.send(bytes.subarray(offset, offset + IMAGE_CHUNK_BYTES));
+    }
+    console.log(`Sent ${bytes.length} byte image over the data channel`);
+}
+
 // Unified function for sending WebSocket messages
 function sendMessage(data) {
     if (signalingSocket.readyState === WebSocket.OPEN) {
@@ -39,13 +75,27 @@ function sendMessage(data) {
     }
 }
 
+// Offers and answers may carry gzip-compressed, base64-encoded SDP
+async function readSdp(data) {
+    if (!data.compressed) {
+        return data.sdp;
+    }
+    const bytes = Uint8Array.from(atob(data.sdp), c => c.charCodeAt(0));
+    const stream = new Blob([bytes]).stream().pipeThrough(new DecompressionStream("gzip"));
+    return await new Response(stream).text();
+}
+
 // Start call when button is clicked
 document.getElementById("startCall").onclick = async () => {
     if (signalingSocket.readyState === WebSocket.OPEN) {
+        if (!remoteId) {
+            console.error("Nobody else is connected yet, cannot start call");
+            return;
+        }
         await startCall();
         const offer = await pc.createOffer();
         await pc.setLocalDescription(offer);
-        sendMessage({ type: "offer", sdp: offer.sdp });
+        sendMessage({ type: "offer", sdp: offer.sdp, target: remoteId });
     } else {
         console.error("WebSocket is not open, cannot start call");
     }
@@ -58,16 +108,30 @@ pc.onicecandidate = ({ candidate }) => {
             type: "candidate",
             candidate: candidate.candidate,
             sdpMid: candidate.sdpMid,
-            sdpMLineIndex: candidate.sdpMLineIndex
+            sdpMLineIndex: candidate.sdpMLineIndex,
+            target: remoteId
         });
     }
 };
 
+// Report ICE state so the server knows whether this session is really connected
+pc.oniceconnectionstatechange = () => {
+    sendMessage({ type: "icestate", state: pc.iceConnectionState });
+};
+
 // Handle incoming messages from the signaling server
 signalingSocket.onmessage = async (message) => {
     const data = JSON.parse(message.data);
+    if (data.from) {
+        remoteId = data.from;
+    }
 
-    if (data.type === "candidate") {
+    if (data.type === "welcome") {
+        console.log(`Connected as ${data.your_id}`);
+        remoteId ??= data.peers[0] ?? null;
+    } else if (data.type === "peerjoined") {
+        remoteId ??= data.id;
+    } else if (data.type === "candidate") {
         if (data.candidate && (data.sdpMid !== null || data.sdpMLineIndex !== null)) {
             try {
                 const candidate = new RTCIceCandidate({
@@ -83,23 +147,52 @@ signalingSocket.onmessage = async (message) => {
         } else {
             console.warn("Skipping ICE candidate due to missing sdpMid or sdpMLineIndex", data);
         }
+    } else if (data.type === "candidates") {
+        for (const entry of data.list || []) {
+            try {
+                await pc.addIceCandidate(new RTCIceCandidate(entry));
+            } catch (error) {
+                console.error("Error adding batched ICE candidate", error);
+            }
+        }
     } else if (data.type === "offer") {
         try {
-            await pc.setRemoteDescription(new RTCSessionDescription(data));
+            await pc.setRemoteDescription({ type: "offer", sdp: await readSdp(data) });
             const answer = await pc.createAnswer();
             await pc.setLocalDescription(answer);
-            sendMessage({ type: "answer", sdp: answer.sdp });
+            sendMessage({ type: "answer", sdp: answer.sdp, target: remoteId });
             console.log("Sent answer to offer");
         } catch (error) {
             console.error("Error handling received offer", error);
+            sendMessage({ type: "error", code: "bad_sdp", detail: String(error) });
         }
     } else if (data.type === "answer") {
         try {
-            await pc.setRemoteDescription(new RTCSessionDescription(data));
+            await pc.setRemoteDescription({ type: "answer", sdp: await readSdp(data) });
             console.log("Set remote description from answer");
         } catch (error) {
             console.error("Error setting remote description from answer", error);
+            sendMessage({ type: "error", code: "bad_sdp", detail: String(error) });
         }
+    } else if (data.type === "queueupdate") {
+        console.log(data.position === 0 && data.estimated_wait_secs === 0
+            ? "It's our turn"
+            : `Queue position ${data.position + 1}, about ${data.estimated_wait_secs}s to wait`);
+    } else if (data.type === "prepareturn") {
+        // Start gathering candidates now so the connection is quick once our turn begins
+        console.log(`Our turn starts in ${data.starts_in_secs}s, warming up`);
+        pc.setConfiguration({ ...pc.getConfiguration(), iceCandidatePoolSize: 1 });
+    } else if (data.type === "hello") {
+        console.log(`Server honours features: ${data.features.join(", ")}`);
+    } else if (data.type === "negotiated") {
+        console.log(`Negotiated video ${data.video_codec ?? "none"}, audio ${data.audio_codec ?? "none"}`);
+    } else if (data.type === "mediawarning") {
+        console.warn(`Our ${data.direction} media stopped while the other direction flows`);
+    } else if (data.type === "error") {
+        console.error(`Signaling error ${data.code}: ${data.detail}`);
+    } else if (data.type === "bye") {
+        console.log("Signaling server is shutting down, closing the call");
+        pc.close();
     } else if (data.type === "image") {
         console.log("Received image data:", data.data);
     }
@@ -147,7 +240,7 @@ captureImageBtn.addEventListener("click", () => {
 
         console.log("Image captured:", imageData);
 
-        sendMessage({ type: "image", data: imageData });
+        sendImage(canvas, imageData);
     } else {
         console.error("Failed to get canvas context");
     }
@@ -172,8 +265,8 @@ function captureFrame() {
 
         console.log("Image captured:", imageData);
 
-        // Send the captured image data to the server
-        sendMessage({ type: "image", data: imageData });
+        // Send the captured image to the streamer, or the server as a fallback
+        sendImage(canvas, imageData);
     } else {
         console.error("Failed to get canvas context for drawing.");
     }